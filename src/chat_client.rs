@@ -1,7 +1,6 @@
 // src/chat_client.rs
 use crate::common::{
-    ChatMessage, Colors, LogLevel, log, format_timestamp, 
-    format_participants, format_nym_address, format_nym_debug_info, separator
+    ChatMessage, Colors, LogLevel, log, format_participants, frame_codec, separator
 };
 use nym_sdk::tcp_proxy::NymProxyClient;
 use nym_sdk::mixnet::{Recipient, NymNetworkDetails};
@@ -9,33 +8,48 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::signal;
-use tokio_util::codec::{BytesCodec, FramedRead, FramedWrite};
+use tokio_util::codec::{FramedRead, FramedWrite};
 use tokio_stream::StreamExt;
 use futures_util::sink::SinkExt;
 use std::io::{self, Write};
-use std::net::SocketAddr;
-use serde_json;
 
-const PROXY_CLIENT_PORT: u16 = 8070;
+pub const DEFAULT_PROXY_CLIENT_PORT: u16 = 8070;
+pub const DEFAULT_PROXY_CLIENT_POOL_SIZE: usize = 2;
+const PROXY_CLIENT_HOST: &str = "127.0.0.1"; // Local proxy only accepts loopback connections
 const PROXY_CLIENT_TIMEOUT: u64 = 300; // 5 min connection timeout
-const PROXY_CLIENT_POOL_SIZE: usize = 2;
 const TERMINAL_WIDTH: usize = 80; // Default terminal width
 
+/// Settings for the local TCP proxy the chat client talks through
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    pub port: u16,
+    pub pool_size: usize,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PROXY_CLIENT_PORT,
+            pool_size: DEFAULT_PROXY_CLIENT_POOL_SIZE,
+        }
+    }
+}
+
 pub struct ChatClient {
     username: String,
     room_address: String,
+    proxy: ProxyConfig,
     verbosity: LogLevel,
-    connection_time: SystemTime,
     message_count: usize,
 }
 
 impl ChatClient {
-    pub fn new(username: String, room_address: String, verbosity: LogLevel) -> Self {
+    pub fn new(username: String, room_address: String, proxy: ProxyConfig, verbosity: LogLevel) -> Self {
         Self {
             username,
             room_address,
+            proxy,
             verbosity,
-            connection_time: SystemTime::now(),
             message_count: 0,
         }
     }
@@ -64,11 +78,11 @@ impl ChatClient {
         // Create the proxy client
         let proxy_client = NymProxyClient::new(
             address,
-            "127.0.0.1",
-            &PROXY_CLIENT_PORT.to_string(),
+            PROXY_CLIENT_HOST,
+            &self.proxy.port.to_string(),
             PROXY_CLIENT_TIMEOUT,
             network_details,
-            PROXY_CLIENT_POOL_SIZE,
+            self.proxy.pool_size,
         ).await?;
         
         // Clone for running the proxy
@@ -92,7 +106,7 @@ impl ChatClient {
         self.print_status("Connecting to local proxy...");
         
         // Connect to the local proxy
-        let stream = match TcpStream::connect(format!("127.0.0.1:{}", PROXY_CLIENT_PORT)).await {
+        let stream = match TcpStream::connect(format!("{}:{}", PROXY_CLIENT_HOST, self.proxy.port)).await {
             Ok(stream) => stream,
             Err(e) => {
                 self.print_error(&format!("Failed to connect to proxy: {}", e));
@@ -107,8 +121,11 @@ impl ChatClient {
         let (read_half, write_half) = stream.into_split();
         
         // Setup framed reading/writing
-        let mut framed_read = FramedRead::new(read_half, BytesCodec::new());
-        let mut framed_write = FramedWrite::new(write_half, BytesCodec::new());
+        let mut framed_read = FramedRead::new(read_half, frame_codec());
+        let mut framed_write = FramedWrite::new(write_half, frame_codec());
+        
+        // Outgoing messages from the input and exit tasks go through one writer
+        let (outgoing_tx, mut outgoing_rx) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
         
         // Status update
         self.print_status("Joining chat room...");
@@ -116,6 +133,11 @@ impl ChatClient {
         // Send join message
         let join_msg = ChatMessage::Join {
             username: self.username.clone(),
+            key_package: None,
+            identity_key: None,
+            proof: None,
+            last_seen: None,
+            channel: None,
         };
         
         if let Ok(join_bytes) = serde_json::to_vec(&join_msg) {
@@ -133,12 +155,21 @@ impl ChatClient {
         // Print separator
         println!("{}", separator(Some("Messages"), TERMINAL_WIDTH));
         
+        // Write queued messages to the proxy
+        let writer_verbosity = self.verbosity;
+        tokio::spawn(async move {
+            while let Some(msg_bytes) = outgoing_rx.recv().await {
+                if let Err(e) = framed_write.send(bytes::Bytes::from(msg_bytes)).await {
+                    log(LogLevel::Debug, writer_verbosity, &format!("Error writing to stream: {}", e));
+                    break;
+                }
+            }
+        });
+        
         // Handle user input in a separate task
         let username = self.username.clone();
         let input_verbosity = self.verbosity;
-        
-        let framed_write_ref = framed_write.clone();
-        let mut framed_write_clone = framed_write;
+        let input_tx = outgoing_tx.clone();
         
         tokio::spawn(async move {
             let stdin = BufReader::new(tokio::io::stdin());
@@ -164,14 +195,17 @@ impl ChatClient {
                     from: username.clone(),
                     content: line.trim().to_string(),
                     timestamp,
+                    id: None,
+                    sealed: None,
+                    seq: None,
                 };
                 
                 log(LogLevel::Debug, input_verbosity, &format!("Sending text message: {}", line.trim()));
                 
                 match serde_json::to_vec(&text_msg) {
                     Ok(msg_bytes) => {
-                        if let Err(e) = framed_write_clone.send(bytes::Bytes::from(msg_bytes)).await {
-                            eprintln!("{}Error:{} Failed to send message: {}", Colors::RED, Colors::RESET, e);
+                        if input_tx.send(msg_bytes).is_err() {
+                            eprintln!("{}Error:{} Failed to send message: connection closed", Colors::RED, Colors::RESET);
                             break;
                         }
                     },
@@ -195,7 +229,7 @@ impl ChatClient {
         let exit_verbosity = self.verbosity;
         let proxy_client_exit = proxy_client.clone();
         
-        let mut framed_write_exit = framed_write_ref;
+        let exit_tx = outgoing_tx;
         tokio::spawn(async move {
             signal::ctrl_c().await.ok();
             println!("\r{}Leaving chat room...{}", Colors::YELLOW, Colors::RESET);
@@ -206,7 +240,7 @@ impl ChatClient {
             };
             
             if let Ok(leave_bytes) = serde_json::to_vec(&leave_msg) {
-                let _ = framed_write_exit.send(bytes::Bytes::from(leave_bytes)).await;
+                let _ = exit_tx.send(leave_bytes);
             }
             
             // Wait briefly for the message to be sent
@@ -230,7 +264,7 @@ impl ChatClient {
                     
                     if let Ok(message) = serde_json::from_slice::<ChatMessage>(&bytes) {
                         match &message {
-                            ChatMessage::Join { username } if username != &username_recv => {
+                            ChatMessage::Join { username, .. } if username != &username_recv => {
                                 let formatted = message.format(false);
                                 println!("\r{}", formatted); // \r to clear the prompt
                                 self.redraw_prompt();
@@ -254,7 +288,7 @@ impl ChatClient {
                                 log(LogLevel::Info, recv_verbosity, &format!("Message from {}: {}", from, content));
                                 self.message_count += 1;
                             },
-                            ChatMessage::StateSync { history, participants } => {
+                            ChatMessage::StateSync { history, participants, .. } => {
                                 log(LogLevel::Debug, recv_verbosity, &format!(
                                     "Received state sync with {} messages and {} participants",
                                     history.len(), participants.len()
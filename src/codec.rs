@@ -0,0 +1,121 @@
+// src/codec.rs
+//
+// Wire encoding for `ChatMessage` payloads sent over the mixnet.
+//
+// Every payload starts with a one-byte format tag followed by the encoded
+// message. Payloads from older clients carry no tag at all and are plain
+// JSON objects, which we recognise by their leading `{`.
+use crate::common::ChatMessage;
+
+// Format tags, chosen so they can never be confused with a leading `{`
+const TAG_JSON: u8 = 0x01;
+const TAG_CBOR: u8 = 0x02;
+const LEGACY_JSON_START: u8 = b'{';
+
+/// How a payload is encoded on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WireFormat {
+    /// Untagged JSON, as sent by clients that predate format tags
+    LegacyJson,
+    /// Tagged JSON
+    Json,
+    /// Tagged CBOR: compact and self-describing, so optional fields stay decodable
+    Cbor,
+}
+
+impl WireFormat {
+    /// The format new clients use unless told otherwise
+    pub const PREFERRED: WireFormat = WireFormat::Cbor;
+
+    pub fn encode(self, message: &ChatMessage) -> anyhow::Result<Vec<u8>> {
+        match self {
+            WireFormat::LegacyJson => Ok(serde_json::to_vec(message)?),
+            WireFormat::Json => {
+                let mut payload = vec![TAG_JSON];
+                serde_json::to_writer(&mut payload, message)?;
+                Ok(payload)
+            },
+            WireFormat::Cbor => {
+                let mut payload = vec![TAG_CBOR];
+                ciborium::ser::into_writer(message, &mut payload)
+                    .map_err(|e| anyhow::anyhow!("CBOR encoding failed: {}", e))?;
+                Ok(payload)
+            },
+        }
+    }
+}
+
+/// Decode a payload in any supported format, reporting which one it used
+pub fn decode(payload: &[u8]) -> anyhow::Result<(ChatMessage, WireFormat)> {
+    match payload.first() {
+        Some(&TAG_JSON) => Ok((serde_json::from_slice(&payload[1..])?, WireFormat::Json)),
+        Some(&TAG_CBOR) => {
+            let message = ciborium::de::from_reader(&payload[1..])
+                .map_err(|e| anyhow::anyhow!("CBOR decoding failed: {}", e))?;
+            Ok((message, WireFormat::Cbor))
+        },
+        Some(&LEGACY_JSON_START) => Ok((serde_json::from_slice(payload)?, WireFormat::LegacyJson)),
+        Some(tag) => Err(anyhow::anyhow!("Unknown wire format tag 0x{:02x}", tag)),
+        None => Err(anyhow::anyhow!("Empty payload")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text() -> ChatMessage {
+        ChatMessage::Text {
+            from: "alice".to_string(),
+            content: "hello".to_string(),
+            timestamp: 42,
+            id: None,
+            sealed: None,
+            seq: None,
+        }
+    }
+
+    fn assert_is_text(message: &ChatMessage) {
+        assert!(matches!(message, ChatMessage::Text { from, content, timestamp: 42, .. }
+            if from == "alice" && content == "hello"), "unexpected message: {:?}", message);
+    }
+
+    #[test]
+    fn every_format_round_trips() {
+        for format in [WireFormat::LegacyJson, WireFormat::Json, WireFormat::Cbor] {
+            let payload = format.encode(&text()).unwrap();
+            let (message, decoded_format) = decode(&payload).unwrap();
+            assert_eq!(decoded_format, format);
+            assert_is_text(&message);
+        }
+    }
+
+    #[test]
+    fn untagged_json_from_old_clients_decodes() {
+        let payload = br#"{"Text":{"from":"alice","content":"hello","timestamp":42}}"#;
+        let (message, format) = decode(payload).unwrap();
+        assert_eq!(format, WireFormat::LegacyJson);
+        assert_is_text(&message);
+    }
+
+    #[test]
+    fn unknown_tag_is_rejected() {
+        let mut payload = WireFormat::Cbor.encode(&text()).unwrap();
+        payload[0] = 0x7f;
+        assert!(decode(&payload).unwrap_err().to_string().contains("0x7f"));
+    }
+
+    #[test]
+    fn empty_and_truncated_payloads_are_rejected() {
+        assert!(decode(&[]).is_err());
+
+        for format in [WireFormat::LegacyJson, WireFormat::Json, WireFormat::Cbor] {
+            let payload = format.encode(&text()).unwrap();
+            assert!(decode(&payload[..payload.len() - 1]).is_err(), "{:?} decoded a truncated payload", format);
+        }
+
+        // A tag with nothing after it
+        assert!(decode(&[TAG_JSON]).is_err());
+        assert!(decode(&[TAG_CBOR]).is_err());
+    }
+}
@@ -1,8 +1,15 @@
 // src/common.rs
-use serde::{Deserialize, Serialize};
+use crate::codec::{self, WireFormat};
+use crate::crypto::Sealed;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use chrono::{DateTime, Local};
+use std::cell::Cell;
 use std::fmt;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_util::codec::LengthDelimitedCodec;
+
+/// Largest single frame accepted on the TCP-proxy chat stream
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024; // 1 MiB
 
 /// Color codes for terminal output
 pub struct Colors;
@@ -45,11 +52,99 @@ impl Colors {
     pub const BG_WHITE: &'static str = "\x1b[47m";
 }
 
+/// Current chat protocol version. Version 1 is the original handshake-less protocol.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version we can still talk to after a handshake
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Optional protocol features a peer can advertise during the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Capability {
+    /// Client holds a room secret and seals message content
+    Encryption,
+    BinaryCodec,
+    DeliveryAcks,
+    /// Room asks for more reply SURBs before a client runs out
+    SurbTopUp,
+    /// Room relays `Signed` envelopes and shares members' identity keys
+    Signatures,
+    /// Large state syncs are sent in numbered chunks
+    ChunkedSync,
+    /// Client can receive `DirectMessage`s
+    DirectMessages,
+    /// A capability from a newer peer that we don't know about
+    #[serde(other)]
+    Unknown,
+}
+
+/// Client-chosen identifier for a chat message
+pub type MessageId = u64;
+
+/// Generate a fresh, practically unique message id
+pub fn new_message_id() -> MessageId {
+    uuid::Uuid::new_v4().as_u64_pair().0
+}
+
+/// Delivery state of a line we sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryState {
+    Pending,
+    Delivered,
+    Failed,
+}
+
 /// Chat messages exchanged between clients and server
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ChatMessage {
+    /// First message from a client, advertising what it speaks
+    Hello {
+        version: u32,
+        min_version: u32,
+        capabilities: Vec<Capability>,
+    },
+    /// Server's answer to a compatible `Hello`
+    Welcome {
+        version: u32,
+        capabilities: Vec<Capability>,
+        /// Set by passphrase-protected rooms; the `Join` must answer it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        challenge: Option<Vec<u8>>,
+        /// Channel a `Join` that names none is put in, which its signature is bound to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        default_channel: Option<String>,
+    },
+    /// Server's answer to an incompatible `Hello`
+    HelloRejected {
+        server_version: u32,
+        reason: String,
+    },
     Join {
         username: String,
+        /// Public key other members encrypt group key updates to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_package: Option<Vec<u8>>,
+        /// Long-term Ed25519 key this member signs its messages with
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity_key: Option<Vec<u8>>,
+        /// Answer to the room's challenge, proving we know the room passphrase
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        proof: Option<Vec<u8>>,
+        /// The last history item we saw here before, so the room only syncs
+        /// what came after it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_seen: Option<HistoryCursor>,
+        /// Channel to join; the room's first channel if unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel: Option<String>,
+    },
+    /// Server's answer to a `Join` with a taken or invalid username
+    JoinRejected {
+        reason: String,
+    },
+    /// Server's answer to a `Join` without a valid passphrase proof
+    AccessDenied {
+        reason: String,
     },
     Leave {
         username: String,
@@ -58,18 +153,245 @@ pub enum ChatMessage {
         from: String,
         content: String,
         timestamp: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<MessageId>,
+        /// End-to-end encrypted content; `content` is empty when this is set
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sealed: Option<Sealed>,
+        /// Sequence number the room gave the message in its history, set as it relays it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// Private message for one participant, relayed only to them and never stored
+    DirectMessage {
+        from: String,
+        to: String,
+        content: String,
+        /// End-to-end encrypted content; `content` is empty when this is set
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sealed: Option<Sealed>,
+    },
+    /// Server's answer to a `DirectMessage` it could not deliver
+    DirectMessageFailed {
+        to: String,
+        reason: String,
+    },
+    /// Server confirmation that a `Text` was accepted into the room
+    Ack {
+        id: MessageId,
+        /// Sequence number the message was given in the room's history
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
     },
     StateSync {
         history: Vec<HistoryItem>,
         participants: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        key_packages: Vec<KeyPackage>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        identity_keys: Vec<IdentityKey>,
+        /// Set when the history was too large for one message and came in
+        /// `StateSyncChunk`s; `history` is then empty and this completes the sync
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chunked: Option<SyncChunks>,
+        /// Set when `history` only holds what came after this item, the one
+        /// the client said it saw last
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        since: Option<u64>,
+        /// Channel the client was put in
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel: Option<String>,
+        /// Set when `history` is only the latest page of what the client
+        /// hasn't seen; it asks for the rest with `HistoryRequest`
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        more: bool,
+        /// Which numbering the history's sequence numbers belong to, for the
+        /// client's cursor
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        history_epoch: Option<u64>,
+    },
+    /// Part `index` of `chunks` of a chunked state sync's history, oldest first
+    StateSyncChunk {
+        sync_id: u64,
+        index: u32,
+        chunks: u32,
+        history: Vec<HistoryItem>,
+    },
+    /// Client request for the parts of a chunked state sync that never arrived
+    StateSyncResend {
+        sync_id: u64,
+        missing: Vec<u32>,
+    },
+    /// A member's new group key epoch, encrypted separately to every other member
+    GroupCommit {
+        epoch: u64,
+        committer: String,
+        commit_key: Vec<u8>,
+        shares: Vec<KeyShare>,
+    },
+    /// A member's request for a fresh commit after missing the one for `epoch`
+    GroupKeyRequest {
+        member: String,
+        epoch: u64,
+    },
+    /// Client request for up to `limit` history items from before item `before`
+    /// (the most recent ones if unset)
+    HistoryRequest {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        before: Option<u64>,
+        limit: u32,
+    },
+    /// Server's answer to a `HistoryRequest`, oldest item first
+    HistoryPage {
+        items: Vec<HistoryItem>,
+        /// Whether the room holds items older than these
+        more: bool,
+    },
+    /// Client request for the room's channels
+    ListChannels,
+    /// Server's answer to `ListChannels`
+    ChannelList {
+        channels: Vec<ChannelInfo>,
+    },
+    /// Server warning that it is running low on reply SURBs for this client
+    SurbRequest {
+        remaining: u32,
+    },
+    /// Client message sent with `surbs` extra reply SURBs attached
+    SurbTopUp {
+        surbs: u32,
+    },
+    /// A message signed with its sender's identity key, relayed untouched
+    Signed {
+        message: SignedPayload,
+        signature: Vec<u8>,
+        /// A join's passphrase proof, outside the signature so the room can
+        /// drop it before relaying the join
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        proof: Option<Vec<u8>>,
+        /// Sequence number the room gave a signed `Text`, outside the
+        /// signature since the room sets it as it relays the message
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
     },
 }
 
+/// A message exactly as its sender encoded it for signing.
+///
+/// Only the encoded bytes go on the wire, so when the room re-encodes the
+/// envelope for a participant that speaks another format, the bytes the
+/// signature covers stay the same.
+#[derive(Debug, Clone)]
+pub struct SignedPayload {
+    bytes: Vec<u8>,
+    message: Box<ChatMessage>,
+}
+
+impl SignedPayload {
+    pub fn new(message: &ChatMessage) -> anyhow::Result<Self> {
+        Ok(Self {
+            bytes: WireFormat::Cbor.encode(message)?,
+            message: Box::new(message.clone()),
+        })
+    }
+
+    /// The bytes the signature covers
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn message(&self) -> &ChatMessage {
+        &self.message
+    }
+}
+
+impl Serialize for SignedPayload {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.bytes.serialize(serializer)
+    }
+}
+
+thread_local! {
+    // Set while a payload is being decoded, so an envelope inside it is
+    // refused before its own payload is decoded, and so on without end
+    static DECODING_PAYLOAD: Cell<bool> = const { Cell::new(false) };
+}
+
+impl<'de> Deserialize<'de> for SignedPayload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        if DECODING_PAYLOAD.replace(true) {
+            return Err(serde::de::Error::custom("signature envelopes can't be nested"));
+        }
+        let decoded = codec::decode(&bytes);
+        DECODING_PAYLOAD.set(false);
+
+        let (message, _) = decoded.map_err(serde::de::Error::custom)?;
+        Ok(Self {
+            bytes,
+            message: Box::new(message),
+        })
+    }
+}
+
 impl ChatMessage {
+    /// The message inside a signature envelope, or the message itself
+    pub fn payload(&self) -> &ChatMessage {
+        match self {
+            ChatMessage::Signed { message, .. } => message.message(),
+            message => message,
+        }
+    }
+
+    /// The passphrase proof a join carries for the room, in an envelope or not
+    pub fn join_proof(&self) -> Option<&[u8]> {
+        match self {
+            ChatMessage::Signed { proof, .. } | ChatMessage::Join { proof, .. } => proof.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// This message without its join proof, which is only for the room
+    pub fn without_proof(&self) -> ChatMessage {
+        let mut message = self.clone();
+        if let ChatMessage::Signed { proof, .. } | ChatMessage::Join { proof, .. } = &mut message {
+            *proof = None;
+        }
+        message
+    }
+
+    /// Sequence number the room gave a relayed `Text`, in an envelope or not
+    pub fn seq(&self) -> Option<u64> {
+        match self {
+            ChatMessage::Signed { seq, .. } | ChatMessage::Text { seq, .. } => *seq,
+            _ => None,
+        }
+    }
+
+    /// This message numbered `seq` in the room's history
+    pub fn with_seq(&self, seq: u64) -> ChatMessage {
+        let mut message = self.clone();
+        if let ChatMessage::Signed { seq: numbered, .. } | ChatMessage::Text { seq: numbered, .. } = &mut message {
+            *numbered = Some(seq);
+        }
+        message
+    }
+
+    /// The member a message claims to come from, for those sent on a member's behalf
+    pub fn sender(&self) -> Option<&str> {
+        match self {
+            ChatMessage::Join { username, .. } | ChatMessage::Leave { username } => Some(username.as_str()),
+            ChatMessage::Text { from, .. } | ChatMessage::DirectMessage { from, .. } => Some(from.as_str()),
+            ChatMessage::GroupCommit { committer, .. } => Some(committer.as_str()),
+            ChatMessage::GroupKeyRequest { member, .. } => Some(member.as_str()),
+            _ => None,
+        }
+    }
+
     /// Returns a formatted string representation with colors and timestamps
     pub fn format(&self, is_self: bool) -> String {
         match self {
-            ChatMessage::Join { username } => {
+            ChatMessage::Join { username, .. } => {
                 format!(
                     "{}{}{} {} joined the room{}",
                     Colors::DIM,
@@ -105,7 +427,7 @@ impl ChatMessage {
                     )
                 )
             },
-            ChatMessage::Text { from, content, timestamp } => {
+            ChatMessage::Text { from, content, timestamp, .. } => {
                 let time_str = format_timestamp_from_unix(*timestamp);
                 let name_color = if is_self {
                     Colors::BRIGHT_BLUE
@@ -124,6 +446,39 @@ impl ChatMessage {
                     content
                 )
             },
+            ChatMessage::DirectMessage { from, to, content, .. } => {
+                let name_color = if is_self {
+                    Colors::BRIGHT_BLUE
+                } else {
+                    get_username_color(from)
+                };
+                
+                format!(
+                    "{}{}{} {}{}{} {}→ {}{}: {}",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    name_color,
+                    from,
+                    Colors::RESET,
+                    Colors::MAGENTA,
+                    to,
+                    Colors::RESET,
+                    content
+                )
+            },
+            ChatMessage::DirectMessageFailed { to, reason } => {
+                format!(
+                    "{}{}{} {}Message to {} not delivered:{} {}",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    Colors::RED,
+                    to,
+                    Colors::RESET,
+                    reason
+                )
+            },
             ChatMessage::StateSync { .. } => {
                 format!(
                     "{}{}{}  State synchronization received",
@@ -132,19 +487,254 @@ impl ChatMessage {
                     Colors::RESET
                 )
             },
+            ChatMessage::StateSyncChunk { index, chunks, .. } => {
+                format!(
+                    "{}{}{}  State synchronization part {} of {} received",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    index + 1,
+                    chunks
+                )
+            },
+            ChatMessage::StateSyncResend { missing, .. } => {
+                format!(
+                    "{}{}{}  Requested {} missing state synchronization parts",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    missing.len()
+                )
+            },
+            ChatMessage::Hello { version, .. } => {
+                format!(
+                    "{}{}{}  Hello (protocol v{})",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    version
+                )
+            },
+            ChatMessage::Welcome { version, .. } => {
+                format!(
+                    "{}{}{}  Welcome (protocol v{})",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    version
+                )
+            },
+            ChatMessage::Ack { id, .. } => {
+                format!(
+                    "{}{}{}  Delivery acknowledged for message {:016x}",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    id
+                )
+            },
+            ChatMessage::JoinRejected { reason } => {
+                format!(
+                    "{}{}{} {}Join rejected:{} {}",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    Colors::RED,
+                    Colors::RESET,
+                    reason
+                )
+            },
+            ChatMessage::AccessDenied { reason } => {
+                format!(
+                    "{}{}{} {}Access denied:{} {}",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    Colors::RED,
+                    Colors::RESET,
+                    reason
+                )
+            },
+            ChatMessage::GroupCommit { epoch, committer, .. } => {
+                format!(
+                    "{}{}{}  {} rotated the room key (epoch {})",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    committer,
+                    epoch
+                )
+            },
+            ChatMessage::GroupKeyRequest { member, epoch } => {
+                format!(
+                    "{}{}{}  {} missed room key epoch {}",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    member,
+                    epoch
+                )
+            },
+            ChatMessage::HistoryRequest { limit, .. } => {
+                format!(
+                    "{}{}{}  Requested {} earlier messages",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    limit
+                )
+            },
+            ChatMessage::HistoryPage { items, .. } => {
+                format!(
+                    "{}{}{}  Received {} earlier messages",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    items.len()
+                )
+            },
+            ChatMessage::ListChannels => {
+                format!(
+                    "{}{}{}  Requested the room's channels",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET
+                )
+            },
+            ChatMessage::ChannelList { channels } => {
+                format!(
+                    "{}{}{}  Room has {} channels",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    channels.len()
+                )
+            },
+            ChatMessage::SurbRequest { remaining } => {
+                format!(
+                    "{}{}{}  Room requested more reply SURBs ({} left)",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    remaining
+                )
+            },
+            ChatMessage::SurbTopUp { surbs } => {
+                format!(
+                    "{}{}{}  Sent {} reply SURBs to the room",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    surbs
+                )
+            },
+            ChatMessage::Signed { message, .. } => message.message().format(is_self),
+            ChatMessage::HelloRejected { reason, .. } => {
+                format!(
+                    "{}{}{} {}Connection rejected:{} {}",
+                    Colors::DIM,
+                    format_timestamp(SystemTime::now()),
+                    Colors::RESET,
+                    Colors::RED,
+                    Colors::RESET,
+                    reason
+                )
+            },
         }
     }
 }
 
+/// Length-prefixed framing for the TCP-proxy chat stream.
+///
+/// Each frame carries exactly one serialized `ChatMessage`, so messages that
+/// arrive together or split across reads are reassembled intact.
+pub fn frame_codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(MAX_FRAME_SIZE)
+        .new_codec()
+}
+
+/// A participant's public key for receiving group key updates
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyPackage {
+    pub username: String,
+    pub public_key: Vec<u8>,
+}
+
+/// A participant's long-term identity key, as last announced in its `Join`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityKey {
+    pub username: String,
+    pub public_key: Vec<u8>,
+}
+
+/// A channel as listed by the room
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelInfo {
+    pub name: String,
+    pub participants: u32,
+}
+
+/// How a chunked state sync's history was split up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncChunks {
+    pub sync_id: u64,
+    pub chunks: u32,
+}
+
+/// An epoch secret encrypted to one member
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyShare {
+    pub member: String,
+    pub sealed: Sealed,
+}
+
+/// Where a client left off in a channel's history
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryCursor {
+    /// Random number the room's history was started under. Numbering starts
+    /// over with a new one, so a cursor from another epoch means nothing.
+    pub epoch: u64,
+    /// Sequence number of the last item seen
+    pub seq: u64,
+}
+
 /// History item for storing chat history
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HistoryItem {
     pub from: String,
     pub content: String,
     pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<Sealed>,
+    /// Room-assigned position in the history, used to page through it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// Id of the `Text` this came from, which rejoining clients resume after
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<MessageId>,
+    /// Its sender's signature over the `Text` it came from, so members can
+    /// check the room didn't alter it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Vec<u8>>,
+    /// Identity key the sender had joined with, which made `signature`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_key: Option<Vec<u8>>,
 }
 
 impl HistoryItem {
+    /// The `Text` this came from, encoded as its sender signed it
+    pub fn signed_payload(&self) -> anyhow::Result<SignedPayload> {
+        SignedPayload::new(&ChatMessage::Text {
+            from: self.from.clone(),
+            content: self.content.clone(),
+            timestamp: self.timestamp,
+            id: self.id,
+            sealed: self.sealed.clone(),
+            seq: None,
+        })
+    }
+
     /// Format a history item with timestamp and colors
     pub fn format(&self, is_self: bool) -> String {
         let time_str = format_timestamp_from_unix(self.timestamp);
@@ -167,6 +757,39 @@ impl HistoryItem {
     }
 }
 
+/// Format the delivery state of one of our own lines
+pub fn format_delivery_status(state: DeliveryState, content: &str) -> String {
+    match state {
+        DeliveryState::Pending => format!(
+            "{}{} ⋯ sending: {}{}",
+            Colors::DIM,
+            format_timestamp(SystemTime::now()),
+            content,
+            Colors::RESET
+        ),
+        DeliveryState::Delivered => format!(
+            "{}{}{} {}✓{} {}delivered: {}{}",
+            Colors::DIM,
+            format_timestamp(SystemTime::now()),
+            Colors::RESET,
+            Colors::GREEN,
+            Colors::RESET,
+            Colors::DIM,
+            content,
+            Colors::RESET
+        ),
+        DeliveryState::Failed => format!(
+            "{}{}{} {}✗ not delivered:{} {}",
+            Colors::DIM,
+            format_timestamp(SystemTime::now()),
+            Colors::RESET,
+            Colors::RED,
+            Colors::RESET,
+            content
+        ),
+    }
+}
+
 /// Log levels for debugging
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum LogLevel {
@@ -338,3 +961,86 @@ pub fn format_nym_debug_info(sender_tag: Option<&str>, surbs: Option<u32>) -> St
         format!(" [{}]", parts.join(", "))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio_stream::StreamExt;
+    use tokio_util::codec::FramedRead;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[tokio::test]
+    async fn frames_written_together_decode_one_by_one() {
+        let payloads: Vec<&[u8]> = vec![br#"{"Join":{"username":"alice"}}"#, b"x", br#"{"Leave":{"username":"alice"}}"#];
+        let (mut writer, reader) = tokio::io::duplex(1024);
+
+        // All three frames land in the reader in one write
+        let bytes: Vec<u8> = payloads.iter().flat_map(|p| frame(p)).collect();
+        writer.write_all(&bytes).await.unwrap();
+        drop(writer);
+
+        let frames: Vec<Vec<u8>> = FramedRead::new(reader, frame_codec())
+            .map(|frame| frame.unwrap().to_vec())
+            .collect()
+            .await;
+        assert_eq!(frames, payloads);
+    }
+
+    #[tokio::test]
+    async fn frame_written_byte_by_byte_decodes_whole() {
+        let payload = br#"{"Text":{"from":"alice","content":"hello","timestamp":1}}"#;
+        let (mut writer, reader) = tokio::io::duplex(1);
+
+        let writes = tokio::spawn(async move {
+            for byte in frame(payload) {
+                writer.write_all(&[byte]).await.unwrap();
+                writer.flush().await.unwrap();
+            }
+        });
+
+        let frames: Vec<Vec<u8>> = FramedRead::new(reader, frame_codec())
+            .map(|frame| frame.unwrap().to_vec())
+            .collect()
+            .await;
+        writes.await.unwrap();
+        assert_eq!(frames, vec![payload.to_vec()]);
+    }
+
+    fn signed(message: &ChatMessage) -> ChatMessage {
+        ChatMessage::Signed {
+            message: SignedPayload::new(message).unwrap(),
+            signature: vec![0; 64],
+            proof: None,
+            seq: None,
+        }
+    }
+
+    #[test]
+    fn nested_signature_envelopes_are_refused() {
+        let text = ChatMessage::Text {
+            from: "alice".to_string(),
+            content: "hello".to_string(),
+            timestamp: 1,
+            id: None,
+            sealed: None,
+            seq: None,
+        };
+
+        for format in [WireFormat::LegacyJson, WireFormat::Json, WireFormat::Cbor] {
+            let once = format.encode(&signed(&text)).unwrap();
+            assert!(matches!(codec::decode(&once).unwrap().0.payload(), ChatMessage::Text { .. }));
+
+            let twice = format.encode(&signed(&signed(&text))).unwrap();
+            assert!(codec::decode(&twice).is_err());
+
+            // Refusing one doesn't get in the way of the next
+            assert!(codec::decode(&once).is_ok());
+        }
+    }
+}
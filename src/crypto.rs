@@ -0,0 +1,379 @@
+// src/crypto.rs
+//
+// End-to-end encryption of message content. Clients derive a room key from
+// a secret carried in the room invite (`nym://<address>#<secret>`), which the
+// room server never sees, so it only ever stores and relays ciphertext.
+//
+// On top of that invite key, members agree on a fresh epoch secret whenever
+// membership changes (see `group.rs`). Epoch secrets are distributed as
+// X25519 key shares, and each epoch key mixes in the invite secret.
+//
+// Rooms can also require a passphrase. Joining clients prove they know it by
+// answering a fresh challenge from the room, so the passphrase itself never
+// crosses the mixnet. The key behind those answers comes from Argon2, so
+// anyone who captures a challenge and its answer pays dearly for each guess.
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use nym_sdk::mixnet::Recipient;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use std::str::FromStr;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const SECRET_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+// Domain separation for each derived key
+const ROOM_KEY_INFO: &[u8] = b"nymcat room key v1";
+const EPOCH_KEY_INFO: &[u8] = b"nymcat epoch key v1";
+const KEY_SHARE_INFO: &[u8] = b"nymcat key share v1";
+const ACCESS_KEY_INFO: &[u8] = b"nymcat access key v1";
+const JOIN_PROOF_INFO: &[u8] = b"nymcat join proof v1";
+
+// Length of the room's join challenge
+const CHALLENGE_LEN: usize = 32;
+
+// Argon2id cost for passphrase keys: 19 MiB and two passes, fixed here so
+// a crate upgrade can't change which key a passphrase gives
+const ACCESS_KEY_MEMORY_KIB: u32 = 19 * 1024;
+const ACCESS_KEY_PASSES: u32 = 2;
+
+/// Length of an X25519 public key
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// Message content sealed with a room key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sealed {
+    /// Key epoch the content was sealed under; 0 is the invite key itself
+    #[serde(default)]
+    pub epoch: u64,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+/// Random secret shared out of band in a room invite
+#[derive(Clone, PartialEq, Eq)]
+pub struct RoomSecret([u8; SECRET_LEN]);
+
+impl RoomSecret {
+    pub fn generate() -> Self {
+        let mut secret = [0u8; SECRET_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self(secret)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let secret = bytes.try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid secret: expected {} bytes", SECRET_LEN))?;
+        Ok(Self(secret))
+    }
+}
+
+impl FromStr for RoomSecret {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let bytes = bs58::decode(s).into_vec()
+            .map_err(|e| anyhow::anyhow!("Invalid room secret: {}", e))?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl fmt::Display for RoomSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", bs58::encode(self.0).into_string())
+    }
+}
+
+// Never print the secret by accident
+impl fmt::Debug for RoomSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RoomSecret(..)")
+    }
+}
+
+fn derive_key(ikm: &[u8], salt: &[u8], info: &[&[u8]]) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::new(Some(salt), ikm);
+    let mut key = [0u8; 32];
+    hkdf.expand_multi_info(info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// Symmetric key for one room and key epoch
+pub struct RoomKey {
+    cipher: XChaCha20Poly1305,
+    epoch: u64,
+}
+
+impl RoomKey {
+    /// Derive the invite key for `room` so one secret can't be replayed across rooms
+    pub fn derive(secret: &RoomSecret, room: &Recipient) -> Self {
+        let key = derive_key(&secret.0, &room.to_bytes(), &[ROOM_KEY_INFO]);
+        Self {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+            epoch: 0,
+        }
+    }
+
+    /// Derive the key for a group epoch. Mixing in the invite secret means a
+    /// member injected by the room server still can't read the room.
+    pub fn derive_epoch(secret: &RoomSecret, room: &Recipient, epoch: u64, epoch_secret: &RoomSecret) -> Self {
+        let ikm = [secret.0, epoch_secret.0].concat();
+        let key = derive_key(&ikm, &room.to_bytes(), &[EPOCH_KEY_INFO, &epoch.to_be_bytes()]);
+        Self {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+            epoch,
+        }
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Encrypt `content`, binding it to the claimed sender so it can't be re-attributed
+    pub fn seal(&self, content: &str, from: &str) -> anyhow::Result<Sealed> {
+        seal_bytes(&self.cipher, self.epoch, content.as_bytes(), from.as_bytes())
+    }
+
+    /// Decrypt content sealed by a member of the room
+    pub fn open(&self, sealed: &Sealed, from: &str) -> anyhow::Result<String> {
+        if sealed.epoch != self.epoch {
+            return Err(anyhow::anyhow!("Sealed under key epoch {}, not {}", sealed.epoch, self.epoch));
+        }
+
+        let plaintext = open_bytes(&self.cipher, sealed, from.as_bytes())?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+fn seal_bytes(cipher: &XChaCha20Poly1305, epoch: u64, plaintext: &[u8], aad: &[u8]) -> anyhow::Result<Sealed> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let payload = Payload { msg: plaintext, aad };
+    let ciphertext = cipher.encrypt(XNonce::from_slice(&nonce), payload)
+        .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+
+    Ok(Sealed {
+        epoch,
+        nonce: nonce.to_vec(),
+        ciphertext,
+    })
+}
+
+fn open_bytes(cipher: &XChaCha20Poly1305, sealed: &Sealed, aad: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if sealed.nonce.len() != NONCE_LEN {
+        return Err(anyhow::anyhow!("Malformed nonce"));
+    }
+
+    let payload = Payload { msg: sealed.ciphertext.as_slice(), aad };
+    cipher.decrypt(XNonce::from_slice(&sealed.nonce), payload)
+        .map_err(|_| anyhow::anyhow!("Decryption failed"))
+}
+
+/// Per-session X25519 keypair that other members encrypt epoch secrets to
+pub struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.public.to_bytes()
+    }
+
+    // Cipher for a key share between this keypair and `peer` in one epoch
+    fn share_cipher(&self, peer: &[u8], epoch: u64) -> anyhow::Result<XChaCha20Poly1305> {
+        let peer: [u8; PUBLIC_KEY_LEN] = peer.try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid public key length"))?;
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer));
+        let key = derive_key(shared.as_bytes(), &[], &[KEY_SHARE_INFO, &epoch.to_be_bytes()]);
+        Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+
+    /// Encrypt an epoch secret to the member `recipient` holding public key `peer`
+    pub fn seal_share(&self, peer: &[u8], recipient: &str, epoch: u64, epoch_secret: &RoomSecret) -> anyhow::Result<Sealed> {
+        let cipher = self.share_cipher(peer, epoch)?;
+        seal_bytes(&cipher, epoch, &epoch_secret.0, recipient.as_bytes())
+    }
+
+    /// Decrypt an epoch secret addressed to us as `username` by the holder of `peer`
+    pub fn open_share(&self, peer: &[u8], username: &str, sealed: &Sealed) -> anyhow::Result<RoomSecret> {
+        let cipher = self.share_cipher(peer, sealed.epoch)?;
+        RoomSecret::from_bytes(&open_bytes(&cipher, sealed, username.as_bytes())?)
+    }
+}
+
+/// Key derived from a room passphrase, used to answer join challenges
+#[derive(Clone)]
+pub struct AccessKey([u8; 32]);
+
+impl AccessKey {
+    /// Derive the key for `room`, so a proof for one room is useless in another
+    pub fn from_passphrase(passphrase: &str, room: &Recipient) -> anyhow::Result<Self> {
+        let params = Params::new(ACCESS_KEY_MEMORY_KIB, ACCESS_KEY_PASSES, 1, Some(32))
+            .map_err(|e| anyhow::anyhow!("Invalid passphrase key parameters: {}", e))?;
+        let salt = [ACCESS_KEY_INFO, &room.to_bytes()].concat();
+
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| anyhow::anyhow!("Failed to derive passphrase key: {}", e))?;
+        Ok(Self(key))
+    }
+
+    /// Answer the room's `challenge` for a join as `username`
+    pub fn prove(&self, challenge: &[u8], username: &str) -> Vec<u8> {
+        self.proof_mac(challenge, username).finalize().into_bytes().to_vec()
+    }
+
+    /// Check a join proof in constant time
+    pub fn verify(&self, challenge: &[u8], username: &str, proof: &[u8]) -> bool {
+        self.proof_mac(challenge, username).verify_slice(proof).is_ok()
+    }
+
+    fn proof_mac(&self, challenge: &[u8], username: &str) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.0)
+            .expect("HMAC accepts keys of any length");
+        mac.update(JOIN_PROOF_INFO);
+        mac.update(challenge);
+        mac.update(username.as_bytes());
+        mac
+    }
+}
+
+// Never print the key by accident
+impl fmt::Debug for AccessKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AccessKey(..)")
+    }
+}
+
+/// A fresh random challenge for one join attempt
+pub fn generate_challenge() -> Vec<u8> {
+    let mut challenge = vec![0u8; CHALLENGE_LEN];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    challenge
+}
+
+/// Split an invite into the room address and the secret after `#`, if any
+pub fn parse_invite(invite: &str) -> anyhow::Result<(Recipient, Option<RoomSecret>)> {
+    let invite = invite.strip_prefix("nym://").unwrap_or(invite);
+    let (address, secret) = match invite.split_once('#') {
+        Some((address, secret)) => (address, Some(secret.parse()?)),
+        None => (invite, None),
+    };
+
+    Ok((Recipient::from_str(address)?, secret))
+}
+
+/// Format an invite for `room` that carries `secret`
+pub fn format_invite(room: &Recipient, secret: &RoomSecret) -> String {
+    format!("nym://{}#{}", room, secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::LoopbackNetwork;
+    use crate::transport::Transport;
+
+    fn rooms() -> (Recipient, Recipient) {
+        let network = LoopbackNetwork::new();
+        (network.connect().nym_address(), network.connect().nym_address())
+    }
+
+    #[test]
+    fn sealed_content_opens_only_for_its_room_and_sender() {
+        let secret = RoomSecret::generate();
+        let (room, other_room) = rooms();
+        let key = RoomKey::derive(&secret, &room);
+
+        let sealed = key.seal("hello", "alice").unwrap();
+        assert_ne!(sealed.ciphertext, b"hello");
+        assert_eq!(key.open(&sealed, "alice").unwrap(), "hello");
+
+        // Re-attributed, replayed in another room or opened with another secret
+        assert!(key.open(&sealed, "bob").is_err());
+        assert!(RoomKey::derive(&secret, &other_room).open(&sealed, "alice").is_err());
+        assert!(RoomKey::derive(&RoomSecret::generate(), &room).open(&sealed, "alice").is_err());
+    }
+
+    #[test]
+    fn tampered_or_misnumbered_content_is_rejected() {
+        let secret = RoomSecret::generate();
+        let (room, _) = rooms();
+        let key = RoomKey::derive(&secret, &room);
+        let sealed = key.seal("hello", "alice").unwrap();
+
+        let mut tampered = sealed.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(key.open(&tampered, "alice").is_err());
+
+        let mut truncated = sealed.clone();
+        truncated.nonce.pop();
+        assert!(key.open(&truncated, "alice").is_err());
+
+        // Each epoch has its own key, and content says which one it needs
+        let epoch_key = RoomKey::derive_epoch(&secret, &room, 1, &RoomSecret::generate());
+        assert!(epoch_key.open(&sealed, "alice").is_err());
+        let mut renumbered = epoch_key.seal("hello", "alice").unwrap();
+        renumbered.epoch = 0;
+        assert!(key.open(&renumbered, "alice").is_err());
+    }
+
+    #[test]
+    fn key_share_opens_only_for_its_recipient() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+        let carol = KeyPair::generate();
+        let epoch_secret = RoomSecret::generate();
+
+        let share = alice.seal_share(&bob.public_key(), "bob", 3, &epoch_secret).unwrap();
+        assert_eq!(bob.open_share(&alice.public_key(), "bob", &share).unwrap(), epoch_secret);
+        assert!(carol.open_share(&alice.public_key(), "carol", &share).is_err());
+        assert!(bob.open_share(&alice.public_key(), "carol", &share).is_err());
+    }
+
+    #[test]
+    fn join_proof_needs_the_passphrase_challenge_and_name() {
+        let (room, other_room) = rooms();
+        let key = AccessKey::from_passphrase("correct horse", &room).unwrap();
+        let challenge = generate_challenge();
+        let proof = key.prove(&challenge, "alice");
+
+        assert!(key.verify(&challenge, "alice", &proof));
+        assert!(!key.verify(&challenge, "bob", &proof));
+        assert!(!key.verify(&generate_challenge(), "alice", &proof));
+        assert!(!AccessKey::from_passphrase("wrong horse", &room).unwrap().verify(&challenge, "alice", &proof));
+        assert!(!AccessKey::from_passphrase("correct horse", &other_room).unwrap().verify(&challenge, "alice", &proof));
+    }
+
+    #[test]
+    fn invite_round_trips() {
+        let (room, _) = rooms();
+        let secret = RoomSecret::generate();
+
+        let (address, parsed) = parse_invite(&format_invite(&room, &secret)).unwrap();
+        assert_eq!(address.to_string(), room.to_string());
+        assert_eq!(parsed, Some(secret));
+
+        let (address, parsed) = parse_invite(&room.to_string()).unwrap();
+        assert_eq!(address.to_string(), room.to_string());
+        assert!(parsed.is_none());
+
+        assert!(parse_invite(&format!("nym://{}#not-a-secret", room)).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+// src/direct.rs
+//
+// One-to-one chat straight between two mixnet clients, with no room server.
+// The initiator knows the listener's nym address and writes to it with reply
+// SURBs attached; the listener never learns the initiator's address and
+// answers through those SURBs, asking for more when it runs low. Both ends
+// speak `ChatMessage` and render it the way the room client does.
+use crate::codec::{self, WireFormat};
+use crate::common::{ChatMessage, Colors, DeliveryState, LogLevel, format_delivery_status, log};
+use crate::simple::SessionIo;
+use crate::transport::{self, Transport, TransportSender};
+use nym_sdk::mixnet::{AnonymousSenderTag, IncludedSurbs, Recipient};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Name shown to the other end unless we pick one
+pub const DEFAULT_USERNAME: &str = "anonymous";
+
+// Reply SURBs the initiator attaches to every message
+const SURBS_PER_MESSAGE: u32 = 10;
+
+// The listener asks for more SURBs once its estimate drops below this
+const SURB_LOW_WATERMARK: u32 = 5;
+
+// SURBs the initiator sends when asked for more
+const SURB_TOPUP: u32 = 50;
+
+/// Which end of a direct chat we are
+pub enum Peer {
+    /// Start the chat by writing to this address
+    Address(Recipient),
+    /// Wait for someone to write to us, and answer whoever does first
+    Listen,
+}
+
+pub async fn run_direct_chat(
+    peer: Peer,
+    username: String,
+    data_dir: Option<PathBuf>,
+    verbosity: LogLevel,
+    env_file: Option<String>,
+) -> anyhow::Result<()> {
+    // Set environment if provided
+    if let Some(path) = &env_file {
+        std::env::set_var("NYM_ENV_FILE", path);
+    }
+
+    let client = transport::connect_mixnet(data_dir.as_deref()).await?;
+
+    // Feed stdin into the session until Ctrl+C
+    let io = SessionIo::stdin(|| {
+        println!("{}Leaving direct chat...{}", Colors::YELLOW, Colors::RESET);
+    });
+
+    direct_session(client, peer, username, io, verbosity).await?;
+
+    // Wait briefly for the leave message to be sent
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    std::process::exit(0);
+}
+
+/// Run one end of a direct chat over any transport, returning once we have left
+pub async fn direct_session<T: Transport>(
+    mut client: T,
+    peer: Peer,
+    username: String,
+    io: SessionIo,
+    verbosity: LogLevel,
+) -> anyhow::Result<()> {
+    let SessionIo { lines: mut input_lines, received } = io;
+    let our_address = client.nym_address();
+    let mut session = DirectSession {
+        sender: client.split_sender(),
+        route: match peer {
+            Peer::Address(address) => Route::Address(address),
+            Peer::Listen => Route::Reply(None),
+        },
+        username,
+        surb_estimate: 0,
+        surbs_requested: false,
+        verbosity,
+    };
+
+    // Pass incoming messages, with who sent them, to the session loop
+    let (incoming_tx, mut incoming_rx) = mpsc::unbounded_channel::<(ChatMessage, Option<AnonymousSenderTag>)>();
+    let msgs_verbosity = verbosity;
+
+    let handle_messages = client.on_messages(move |msg| {
+        match codec::decode(&msg.message) {
+            Ok((message, _)) => {
+                if let Some(received) = &received {
+                    received.send(message.clone()).ok();
+                }
+                incoming_tx.send((message, msg.sender_tag)).ok();
+            },
+            Err(e) => {
+                log(LogLevel::Debug, msgs_verbosity, &format!("Failed to parse incoming message: {}", e));
+            }
+        }
+    });
+
+    let run_session = async {
+        match session.route {
+            Route::Address(address) => {
+                // Announce ourselves so the listener can answer before we say anything
+                session.send(&ChatMessage::Join {
+                    username: session.username.clone(),
+                    key_package: None,
+                    identity_key: None,
+                    proof: None,
+                    last_seen: None,
+                    channel: None,
+                }).await?;
+                println!("{}Direct chat with nym://{} as {}{}", Colors::GREEN, address, session.username, Colors::RESET);
+            },
+            Route::Reply(_) => {
+                println!("{}Waiting for a direct chat at nym://{}{}", Colors::GREEN, our_address, Colors::RESET);
+                println!("Share this address with the person you want to talk to.");
+            },
+        }
+
+        loop {
+            tokio::select! {
+                line = input_lines.recv() => match line {
+                    Some(line) => session.handle_input(&line).await,
+                    None => break,
+                },
+                message = incoming_rx.recv() => match message {
+                    Some((message, sender_tag)) => session.handle_message(message, sender_tag).await,
+                    None => return Ok(()),
+                },
+            }
+        }
+
+        if session.has_peer() {
+            log(LogLevel::Debug, verbosity, "Sending leave message");
+            if let Err(e) = session.send(&ChatMessage::Leave { username: session.username.clone() }).await {
+                log(LogLevel::Debug, verbosity, &format!("Failed to send leave message: {}", e));
+            }
+        }
+
+        Ok(())
+    };
+
+    // Receive until we have left the chat
+    tokio::select! {
+        _ = handle_messages => Ok(()),
+        result = run_session => result,
+    }
+}
+
+/// How we reach the other end
+enum Route {
+    /// Their nym address, which we attach SURBs to
+    Address(Recipient),
+    /// The SURBs of whoever wrote to us first, once someone has
+    Reply(Option<AnonymousSenderTag>),
+}
+
+struct DirectSession<S: TransportSender> {
+    sender: S,
+    route: Route,
+    username: String,
+    surb_estimate: u32, // Reply SURBs we think we still hold for the initiator
+    surbs_requested: bool,
+    verbosity: LogLevel,
+}
+
+impl<S: TransportSender> DirectSession<S> {
+    fn has_peer(&self) -> bool {
+        !matches!(self.route, Route::Reply(None))
+    }
+
+    async fn send(&mut self, message: &ChatMessage) -> anyhow::Result<()> {
+        self.send_with_surbs(message, SURBS_PER_MESSAGE).await
+    }
+
+    async fn send_with_surbs(&mut self, message: &ChatMessage, surbs: u32) -> anyhow::Result<()> {
+        let msg_bytes = WireFormat::Cbor.encode(message)?;
+        match self.route {
+            Route::Address(address) => {
+                self.sender.send_message(address, &msg_bytes, IncludedSurbs::Amount(surbs)).await
+            },
+            Route::Reply(Some(sender_tag)) => {
+                self.sender.send_reply(sender_tag, &msg_bytes).await?;
+                self.surb_estimate = self.surb_estimate.saturating_sub(1);
+                Ok(())
+            },
+            Route::Reply(None) => Err(anyhow::anyhow!("No one has started a chat with us yet")),
+        }
+    }
+
+    /// Send a line typed by the user
+    async fn handle_input(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() { return; }
+
+        if !self.has_peer() {
+            println!("{}No one has started a chat with you yet{}", Colors::YELLOW, Colors::RESET);
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let text_msg = ChatMessage::Text {
+            from: self.username.clone(),
+            content: line.to_string(),
+            timestamp,
+            id: None,
+            sealed: None,
+            seq: None,
+        };
+
+        if let Err(e) = self.send(&text_msg).await {
+            log(LogLevel::Debug, self.verbosity, &format!("Failed to send message: {}", e));
+            println!("{}", format_delivery_status(DeliveryState::Failed, line));
+        }
+        self.request_surbs().await;
+    }
+
+    /// Ask the initiator for more SURBs before we run out of ways to answer
+    async fn request_surbs(&mut self) {
+        if !matches!(self.route, Route::Reply(Some(_))) || self.surbs_requested || self.surb_estimate >= SURB_LOW_WATERMARK {
+            return;
+        }
+
+        log(LogLevel::Debug, self.verbosity, &format!("Requesting SURB top-up, {} left", self.surb_estimate));
+        let request = ChatMessage::SurbRequest { remaining: self.surb_estimate };
+        match self.send(&request).await {
+            Ok(()) => self.surbs_requested = true,
+            Err(e) => log(LogLevel::Debug, self.verbosity, &format!("Failed to request SURBs: {}", e)),
+        }
+    }
+
+    /// Render a message from the other end
+    async fn handle_message(&mut self, message: ChatMessage, sender_tag: Option<AnonymousSenderTag>) {
+        // A listener talks to whoever wrote first, until they leave
+        if let Route::Reply(peer) = &mut self.route {
+            let sender_tag = match sender_tag {
+                Some(sender_tag) => sender_tag,
+                None => {
+                    log(LogLevel::Debug, self.verbosity, "Message has no sender tag, skipping");
+                    return;
+                }
+            };
+
+            match peer {
+                Some(peer) if *peer != sender_tag => {
+                    log(LogLevel::Debug, self.verbosity, "Ignoring message from someone outside this chat");
+                    return;
+                },
+                Some(_) => {},
+                None => *peer = Some(sender_tag),
+            }
+
+            // Every message from the initiator carries SURBs we can answer with
+            self.surb_estimate += match &message {
+                ChatMessage::SurbTopUp { surbs } => {
+                    self.surbs_requested = false;
+                    *surbs
+                },
+                _ => SURBS_PER_MESSAGE,
+            };
+        }
+
+        match &message {
+            ChatMessage::Join { username, .. } => {
+                println!("{}{} started a direct chat with you{}", Colors::GREEN, username, Colors::RESET);
+            },
+            ChatMessage::Text { .. } => {
+                println!("{}", message.format(false));
+            },
+            ChatMessage::Leave { username } => {
+                println!("{}{} left the chat{}", Colors::YELLOW, username, Colors::RESET);
+
+                // Free the listener for whoever writes next
+                if let Route::Reply(peer) = &mut self.route {
+                    *peer = None;
+                    self.surb_estimate = 0;
+                    self.surbs_requested = false;
+                }
+            },
+            ChatMessage::SurbRequest { remaining } => {
+                log(LogLevel::Debug, self.verbosity, &format!("Peer is running low on reply SURBs ({} left)", remaining));
+                if let Err(e) = self.send_with_surbs(&ChatMessage::SurbTopUp { surbs: SURB_TOPUP }, SURB_TOPUP).await {
+                    log(LogLevel::Debug, self.verbosity, &format!("Failed to send SURB top-up: {}", e));
+                }
+            },
+            ChatMessage::SurbTopUp { surbs } => {
+                log(LogLevel::Debug, self.verbosity, &format!("Received {} reply SURBs from peer", surbs));
+            },
+            _ => {
+                log(LogLevel::Debug, self.verbosity, "Ignoring message not used in direct chats");
+            }
+        }
+    }
+}
@@ -0,0 +1,426 @@
+// src/group.rs
+//
+// Client-side group key agreement on top of the invite key.
+//
+// Every member publishes a per-session X25519 key package in its `Join`.
+// Whenever membership changes, the remaining member with the lowest username
+// commits a new epoch: a fresh secret encrypted separately to every other
+// member. If that member has gone quiet and no commit arrives in time, the
+// next member in name order commits instead, and so on down the list.
+// Members who leave get no share and can't read later traffic, and
+// superseded epoch keys are dropped, including the invite key once the first
+// commit has replaced it. A member that misses a commit asks for a new one.
+//
+// The room server only relays commits and requests. A commit only counts if
+// a current member signed it, and only a Leave the member signed takes it out
+// of the group: members the room prunes keep their share until then.
+use crate::common::{ChatMessage, KeyPackage, KeyShare};
+use crate::crypto::{KeyPair, RoomKey, RoomSecret, Sealed};
+use nym_sdk::mixnet::Recipient;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// How long each member in line waits for the one before it to commit
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub struct GroupState {
+    room_secret: RoomSecret,
+    room: Recipient,
+    keypair: KeyPair,
+    members: BTreeMap<String, Vec<u8>>, // Username -> key package, including ours
+    current: RoomKey, // Starts as the invite key, epoch 0
+    committer: Option<String>, // Who committed the current epoch
+    previous: Option<RoomKey>, // Kept for messages still in flight across a rekey
+    requested: Option<u64>, // Newest epoch we asked for a commit to
+    rekey: Option<PendingRekey>, // Membership change someone else should have committed for
+}
+
+/// A membership change still waiting for its commit
+struct PendingRekey {
+    member: String, // Who joined, left or asked; never in line to commit for it
+    since: Instant,
+}
+
+impl GroupState {
+    pub fn new(room_secret: RoomSecret, room: Recipient) -> Self {
+        Self {
+            current: RoomKey::derive(&room_secret, &room),
+            room_secret,
+            room,
+            keypair: KeyPair::generate(),
+            members: BTreeMap::new(),
+            committer: None,
+            previous: None,
+            requested: None,
+            rekey: None,
+        }
+    }
+
+    /// Our public key, sent in `Join`
+    pub fn key_package(&self) -> Vec<u8> {
+        self.keypair.public_key().to_vec()
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.current.epoch()
+    }
+
+    /// Seal content under the current epoch
+    pub fn seal(&self, content: &str, from: &str) -> anyhow::Result<Sealed> {
+        self.current.seal(content, from)
+    }
+
+    /// Open content sealed under any epoch we still hold a key for
+    pub fn open(&self, sealed: &Sealed, from: &str) -> anyhow::Result<String> {
+        let key = self.key(sealed.epoch)
+            .ok_or_else(|| anyhow::anyhow!("No key for epoch {}", sealed.epoch))?;
+
+        key.open(sealed, from)
+    }
+
+    /// Whether we still hold the key for `epoch`. Epoch keys only go to the
+    /// members present at the time and are never handed out again, so once
+    /// an epoch is past, content sealed under it stays sealed if we don't.
+    pub fn holds(&self, epoch: u64) -> bool {
+        self.key(epoch).is_some()
+    }
+
+    fn key(&self, epoch: u64) -> Option<&RoomKey> {
+        [Some(&self.current), self.previous.as_ref()]
+            .into_iter()
+            .flatten()
+            .find(|key| key.epoch() == epoch)
+    }
+
+    /// Take the keyed members from a state sync. If we are alone in the room
+    /// we start the first epoch ourselves.
+    pub fn sync_members(&mut self, packages: &[KeyPackage], me: &str) {
+        self.members = packages.iter()
+            .map(|package| (package.username.clone(), package.public_key.clone()))
+            .collect();
+        self.members.insert(me.to_string(), self.key_package());
+
+        if self.members.len() == 1 && self.epoch() == 0 {
+            self.commit(me);
+        }
+    }
+
+    /// Track a new member, returning our commit if it's our turn to rekey
+    pub fn member_joined(&mut self, username: &str, key_package: Option<&[u8]>, me: &str) -> Option<ChatMessage> {
+        // Members without a key package can't follow rekeys, and only read
+        // until the first commit
+        let key_package = key_package?;
+        self.members.insert(username.to_string(), key_package.to_vec());
+
+        if self.our_turn(username, me) {
+            self.commit(me)
+        } else {
+            self.await_commit(username);
+            None
+        }
+    }
+
+    /// Ask for a fresh commit after seeing `epoch` without its key, unless
+    /// we already asked for this epoch or a later one
+    pub fn request_commit(&mut self, epoch: u64, me: &str) -> Option<ChatMessage> {
+        if epoch <= self.epoch() || self.requested.is_some_and(|requested| requested >= epoch) {
+            return None;
+        }
+
+        self.requested = Some(epoch);
+        Some(ChatMessage::GroupKeyRequest {
+            member: me.to_string(),
+            epoch,
+        })
+    }
+
+    /// Answer a member that missed our current epoch, returning our commit
+    /// if it's our turn. Requests for older epochs are already answered by
+    /// the commit that replaced them.
+    pub fn commit_requested(&mut self, username: &str, epoch: u64, me: &str) -> Option<ChatMessage> {
+        if epoch != self.epoch() || !self.members.contains_key(username) {
+            return None;
+        }
+
+        if self.our_turn(username, me) {
+            self.commit(me)
+        } else {
+            self.await_commit(username);
+            None
+        }
+    }
+
+    /// Forget a departed member, returning our commit if it's our turn to rekey
+    pub fn member_left(&mut self, username: &str, me: &str) -> Option<ChatMessage> {
+        self.members.remove(username)?;
+
+        if self.our_turn(username, me) {
+            self.commit(me)
+        } else {
+            self.await_commit(username);
+            None
+        }
+    }
+
+    /// Commit ourselves if a membership change has waited too long for the
+    /// members before us in line, `COMMIT_TIMEOUT` each
+    pub fn overdue_commit(&mut self, now: Instant, me: &str) -> Option<ChatMessage> {
+        let rekey = self.rekey.as_ref()?;
+        let place = self.members.keys()
+            .filter(|member| **member != rekey.member)
+            .position(|member| member == me)?;
+
+        if now.saturating_duration_since(rekey.since) < COMMIT_TIMEOUT * place as u32 {
+            return None;
+        }
+        self.commit(me)
+    }
+
+    // Wait for a commit covering a change to `member`, unless already waiting
+    // on an earlier change, which the same commit will cover
+    fn await_commit(&mut self, member: &str) {
+        if self.rekey.is_none() {
+            self.rekey = Some(PendingRekey {
+                member: member.to_string(),
+                since: Instant::now(),
+            });
+        }
+    }
+
+    /// Move to a new epoch with a fresh secret for every other member
+    fn commit(&mut self, me: &str) -> Option<ChatMessage> {
+        let epoch = self.epoch() + 1;
+        let epoch_secret = RoomSecret::generate();
+        let commit_keypair = KeyPair::generate();
+
+        // A member with a malformed key package simply gets no share
+        let shares: Vec<KeyShare> = self.members.iter()
+            .filter(|(member, _)| *member != me)
+            .filter_map(|(member, key_package)| {
+                let sealed = commit_keypair.seal_share(key_package, member, epoch, &epoch_secret).ok()?;
+                Some(KeyShare { member: member.clone(), sealed })
+            })
+            .collect();
+
+        self.advance(RoomKey::derive_epoch(&self.room_secret, &self.room, epoch, &epoch_secret), me);
+
+        if shares.is_empty() {
+            return None;
+        }
+
+        Some(ChatMessage::GroupCommit {
+            epoch,
+            committer: me.to_string(),
+            commit_key: commit_keypair.public_key().to_vec(),
+            shares,
+        })
+    }
+
+    /// Apply another member's commit. Concurrent commits for the same epoch
+    /// are settled in favour of the lowest committer name, as everyone agrees
+    /// on that. Returns whether the commit moved us to its epoch.
+    pub fn apply_commit(
+        &mut self,
+        epoch: u64,
+        committer: &str,
+        commit_key: &[u8],
+        shares: &[KeyShare],
+        me: &str,
+    ) -> anyhow::Result<bool> {
+        if !self.members.contains_key(committer) || committer == me {
+            return Err(anyhow::anyhow!("{} is not a member who can commit for us", committer));
+        }
+
+        let newer = epoch > self.epoch();
+        let wins_tie = epoch == self.epoch()
+            && self.committer.as_deref().is_some_and(|current| committer < current);
+        if !newer && !wins_tie {
+            return Ok(false);
+        }
+
+        let share = shares.iter()
+            .find(|share| share.member == me)
+            .ok_or_else(|| anyhow::anyhow!("Commit for epoch {} has no share for us", epoch))?;
+        if share.sealed.epoch != epoch {
+            return Err(anyhow::anyhow!("Key share is for epoch {}, not {}", share.sealed.epoch, epoch));
+        }
+
+        let epoch_secret = self.keypair.open_share(commit_key, me, &share.sealed)?;
+        self.advance(RoomKey::derive_epoch(&self.room_secret, &self.room, epoch, &epoch_secret), committer);
+        Ok(true)
+    }
+
+    // The member other than `username` with the lowest name commits on its behalf
+    fn our_turn(&self, username: &str, me: &str) -> bool {
+        self.members.keys().find(|member| *member != username)
+            .is_some_and(|committer| committer == me)
+    }
+
+    // Switch to `key`, keeping only the key it replaces. Any new epoch
+    // covers the membership changes we were waiting on.
+    fn advance(&mut self, key: RoomKey, committer: &str) {
+        self.previous = Some(std::mem::replace(&mut self.current, key));
+        self.committer = Some(committer.to_string());
+        self.rekey = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::LoopbackNetwork;
+    use crate::transport::Transport;
+
+    #[test]
+    fn group_rekey_shuts_out_departed_members() {
+        let secret = RoomSecret::generate();
+        let room = LoopbackNetwork::new().connect().nym_address();
+        let package = |group: &GroupState, name: &str| KeyPackage {
+            username: name.to_string(),
+            public_key: group.key_package(),
+        };
+
+        let mut alice = GroupState::new(secret.clone(), room);
+        let mut bob = GroupState::new(secret.clone(), room);
+        let mut carol = GroupState::new(secret.clone(), room);
+
+        // Alice is alone and starts the first epoch herself
+        alice.sync_members(&[package(&alice, "alice")], "alice");
+        assert_eq!(alice.epoch(), 1);
+
+        // Each newcomer is keyed in by the lowest-named existing member
+        let commit = alice.member_joined("bob", Some(&bob.key_package()), "alice")
+            .expect("alice should rekey for bob");
+        let ChatMessage::GroupCommit { epoch, committer, commit_key, shares } = commit else { unreachable!() };
+        bob.sync_members(&[package(&alice, "alice"), package(&bob, "bob")], "bob");
+        assert!(bob.apply_commit(epoch, &committer, &commit_key, &shares, "bob").unwrap());
+
+        assert!(bob.member_joined("carol", Some(&carol.key_package()), "bob").is_none());
+        let commit = alice.member_joined("carol", Some(&carol.key_package()), "alice")
+            .expect("alice should rekey for carol");
+        let ChatMessage::GroupCommit { epoch, committer, commit_key, shares } = commit else { unreachable!() };
+        assert!(bob.apply_commit(epoch, &committer, &commit_key, &shares, "bob").unwrap());
+        carol.sync_members(&[package(&alice, "alice"), package(&bob, "bob"), package(&carol, "carol")], "carol");
+        assert!(carol.apply_commit(epoch, &committer, &commit_key, &shares, "carol").unwrap());
+        assert_eq!(carol.epoch(), alice.epoch());
+        assert_eq!(bob.epoch(), alice.epoch());
+
+        let sealed = alice.seal("all three of us", "alice").unwrap();
+        assert_eq!(bob.open(&sealed, "alice").unwrap(), "all three of us");
+        assert_eq!(carol.open(&sealed, "alice").unwrap(), "all three of us");
+
+        // Bob leaves: his share is gone from the next epoch
+        let commit = alice.member_left("bob", "alice").expect("alice should rekey without bob");
+        let ChatMessage::GroupCommit { epoch, committer, commit_key, shares } = commit else { unreachable!() };
+        assert!(shares.iter().all(|share| share.member != "bob"));
+        assert!(bob.apply_commit(epoch, &committer, &commit_key, &shares, "bob").is_err());
+        assert!(carol.apply_commit(epoch, &committer, &commit_key, &shares, "carol").unwrap());
+
+        let sealed = alice.seal("just us now", "alice").unwrap();
+        assert_eq!(carol.open(&sealed, "alice").unwrap(), "just us now");
+        assert!(bob.open(&sealed, "alice").is_err());
+    }
+
+    #[test]
+    fn invite_key_stops_opening_after_rekeys() {
+        let secret = RoomSecret::generate();
+        let room = LoopbackNetwork::new().connect().nym_address();
+        let mut alice = GroupState::new(secret.clone(), room);
+        let newcomer = GroupState::new(secret.clone(), room);
+        let invite_sealed = newcomer.seal("sealed with the invite", "newcomer").unwrap();
+
+        // The first commit keeps the invite key only for messages in flight
+        alice.sync_members(&[], "alice");
+        assert_eq!(alice.open(&invite_sealed, "newcomer").unwrap(), "sealed with the invite");
+
+        let bob = GroupState::new(secret, room);
+        alice.member_joined("bob", Some(&bob.key_package()), "alice").expect("alice should rekey for bob");
+        assert!(alice.open(&invite_sealed, "newcomer").is_err());
+    }
+
+    #[test]
+    fn missed_commit_is_replaced_on_request() {
+        let secret = RoomSecret::generate();
+        let room = LoopbackNetwork::new().connect().nym_address();
+        let mut alice = GroupState::new(secret.clone(), room);
+        let mut bob = GroupState::new(secret, room);
+
+        alice.sync_members(&[], "alice");
+        alice.member_joined("bob", Some(&bob.key_package()), "alice").expect("alice should rekey for bob");
+        bob.sync_members(&[KeyPackage { username: "alice".to_string(), public_key: alice.key_package() }], "bob");
+
+        // Bob never sees that commit, only traffic sealed under it
+        let sealed = alice.seal("can you read this", "alice").unwrap();
+        assert!(bob.open(&sealed, "alice").is_err());
+        let request = bob.request_commit(sealed.epoch, "bob").expect("bob should ask for the epoch");
+        assert!(bob.request_commit(sealed.epoch, "bob").is_none(), "bob asked twice");
+
+        let ChatMessage::GroupKeyRequest { member, epoch } = request else { unreachable!() };
+        let commit = alice.commit_requested(&member, epoch, "alice").expect("alice should answer");
+        assert!(alice.commit_requested(&member, epoch, "alice").is_none(), "alice answered twice");
+
+        let ChatMessage::GroupCommit { epoch, committer, commit_key, shares } = commit else { unreachable!() };
+        assert!(bob.apply_commit(epoch, &committer, &commit_key, &shares, "bob").unwrap());
+        let sealed = alice.seal("and now?", "alice").unwrap();
+        assert_eq!(bob.open(&sealed, "alice").unwrap(), "and now?");
+    }
+
+    #[test]
+    fn next_member_commits_when_the_first_stays_quiet() {
+        let secret = RoomSecret::generate();
+        let room = LoopbackNetwork::new().connect().nym_address();
+        let mut alice = GroupState::new(secret.clone(), room);
+        let mut bob = GroupState::new(secret.clone(), room);
+        let mut carol = GroupState::new(secret.clone(), room);
+        let packages = [
+            KeyPackage { username: "alice".to_string(), public_key: alice.key_package() },
+            KeyPackage { username: "bob".to_string(), public_key: bob.key_package() },
+            KeyPackage { username: "carol".to_string(), public_key: carol.key_package() },
+        ];
+        bob.sync_members(&packages, "bob");
+        carol.sync_members(&packages, "carol");
+
+        // Alice is first in line for dave's join but never commits
+        let dave = GroupState::new(secret, room);
+        assert!(bob.member_joined("dave", Some(&dave.key_package()), "bob").is_none());
+        assert!(carol.member_joined("dave", Some(&dave.key_package()), "carol").is_none());
+
+        let now = Instant::now();
+        assert!(bob.overdue_commit(now, "bob").is_none());
+        let commit = bob.overdue_commit(now + COMMIT_TIMEOUT, "bob").expect("bob should commit in alice's place");
+        assert!(carol.overdue_commit(now + COMMIT_TIMEOUT, "carol").is_none(), "carol commits only after bob");
+
+        let ChatMessage::GroupCommit { epoch, committer, commit_key, shares } = commit else { unreachable!() };
+        assert!(carol.apply_commit(epoch, &committer, &commit_key, &shares, "carol").unwrap());
+        assert!(shares.iter().any(|share| share.member == "dave"));
+
+        // Nothing is left waiting once a commit arrives
+        assert!(carol.overdue_commit(now + COMMIT_TIMEOUT * 10, "carol").is_none());
+        assert!(bob.overdue_commit(now + COMMIT_TIMEOUT * 10, "bob").is_none());
+    }
+
+    #[test]
+    fn only_members_can_commit() {
+        let secret = RoomSecret::generate();
+        let room = LoopbackNetwork::new().connect().nym_address();
+        let mut alice = GroupState::new(secret.clone(), room);
+        let mut bob = GroupState::new(secret.clone(), room);
+        bob.sync_members(&[KeyPackage { username: "alice".to_string(), public_key: alice.key_package() }], "bob");
+
+        // Whoever holds the invite can build a commit, but bob never let mallory in
+        let mut mallory = GroupState::new(secret, room);
+        mallory.sync_members(&[], "mallory");
+        let commit = mallory.member_joined("bob", Some(&bob.key_package()), "mallory").expect("mallory should commit");
+        let ChatMessage::GroupCommit { epoch, committer, commit_key, shares } = commit else { unreachable!() };
+        assert!(bob.apply_commit(epoch, &committer, &commit_key, &shares, "bob").is_err());
+
+        // Nor can a commit pass itself off as bob's own
+        assert!(bob.apply_commit(epoch, "bob", &commit_key, &shares, "bob").is_err());
+        assert_eq!(bob.epoch(), 0);
+
+        alice.sync_members(&[], "alice");
+        let commit = alice.member_joined("bob", Some(&bob.key_package()), "alice").expect("alice should commit");
+        let ChatMessage::GroupCommit { epoch, committer, commit_key, shares } = commit else { unreachable!() };
+        assert!(bob.apply_commit(epoch, &committer, &commit_key, &shares, "bob").unwrap());
+    }
+}
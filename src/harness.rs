@@ -0,0 +1,1320 @@
+// src/harness.rs
+//
+// End-to-end harness: a room server and any number of scripted clients
+// talking over the loopback transport, with a manual clock for pruning.
+use crate::clock::ManualClock;
+use crate::codec::{self, WireFormat};
+use crate::common::{Capability, ChatMessage, HistoryItem, IdentityKey, LogLevel, SignedPayload, PROTOCOL_VERSION};
+use crate::crypto::{AccessKey, RoomKey, RoomSecret};
+use crate::direct::{direct_session, Peer};
+use crate::group::GroupState;
+use crate::history::HistoryConfig;
+use crate::identity::{self, Identity};
+use crate::last_seen::LastSeen;
+use crate::loopback::{LoopbackClient, LoopbackNetwork, LoopbackSender};
+use crate::simple::{chat_session, serve_room, ClientConfig, RoomConfig, SessionIo};
+use crate::transport::{Transport, TransportSender};
+use nym_sdk::mixnet::{IncludedSurbs, Recipient};
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// How long to wait for an expected message before failing the test
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Prune often so tests only have to move the manual clock
+const TEST_PRUNE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A running room server on a private loopback network
+pub struct TestRoom {
+    network: LoopbackNetwork,
+    clock: Arc<ManualClock>,
+    address: Recipient,
+    server: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TestRoom {
+    pub fn start() -> Self {
+        Self::start_with(RoomConfig::default())
+    }
+
+    /// Start a room that only admits clients who know `passphrase`
+    pub fn start_protected(passphrase: &str) -> Self {
+        Self::start_with(RoomConfig {
+            passphrase: Some(passphrase.to_string()),
+            ..RoomConfig::default()
+        })
+    }
+
+    /// Start a room with non-default options, on the harness clock
+    pub fn start_with(config: RoomConfig) -> Self {
+        let network = LoopbackNetwork::new();
+        let clock = Arc::new(ManualClock::new());
+        let transport = network.connect();
+        let address = transport.nym_address();
+
+        let config = RoomConfig {
+            clock: clock.clone(),
+            prune_interval: TEST_PRUNE_INTERVAL,
+            ..config
+        };
+        let server = tokio::spawn(serve_room(transport, config, LogLevel::None));
+
+        Self { network, clock, address, server }
+    }
+
+    /// Start a scripted client that joins the room as `username`
+    pub fn join(&self, username: &str) -> ScriptedClient {
+        self.join_with(username, ClientConfig::default())
+    }
+
+    /// Start a scripted client with non-default client options
+    pub fn join_with(&self, username: &str, config: ClientConfig) -> ScriptedClient {
+        let (line_tx, line_rx) = mpsc::unbounded_channel();
+        let (received_tx, received_rx) = mpsc::unbounded_channel();
+
+        let io = SessionIo {
+            lines: line_rx,
+            received: Some(received_tx),
+        };
+        let session = tokio::spawn(chat_session(
+            self.network.connect(),
+            username.to_string(),
+            self.address,
+            config,
+            io,
+            LogLevel::None,
+        ));
+
+        ScriptedClient {
+            inbox: Inbox::new(username, received_rx),
+            lines: Some(line_tx),
+            session,
+        }
+    }
+
+    /// Connect a bare endpoint that sends hand-crafted protocol messages
+    pub fn raw_client(&self, name: &str) -> RawClient {
+        let mut transport = self.network.connect();
+        let sender = transport.split_sender();
+        let (received_tx, received_rx) = mpsc::unbounded_channel();
+
+        let receiver = tokio::spawn(async move {
+            transport.on_messages(move |msg| {
+                if let Ok((message, _)) = codec::decode(&msg.message) {
+                    received_tx.send(message).ok();
+                }
+            }).await
+        });
+
+        RawClient {
+            inbox: Inbox::new(name, received_rx),
+            sender,
+            room: self.address,
+            receiver,
+        }
+    }
+
+    /// Start one scripted client per name
+    pub fn join_all(&self, usernames: &[&str]) -> Vec<ScriptedClient> {
+        usernames.iter().map(|name| self.join(name)).collect()
+    }
+
+    /// Move the room server's clock forward
+    pub fn advance_clock(&self, duration: Duration) {
+        self.clock.advance(duration);
+    }
+}
+
+impl Drop for TestRoom {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// Start one end of a direct chat, driven like a scripted room client
+fn start_direct_chat(transport: LoopbackClient, peer: Peer, username: &str) -> ScriptedClient {
+    let (line_tx, line_rx) = mpsc::unbounded_channel();
+    let (received_tx, received_rx) = mpsc::unbounded_channel();
+
+    let io = SessionIo {
+        lines: line_rx,
+        received: Some(received_tx),
+    };
+    let session = tokio::spawn(direct_session(transport, peer, username.to_string(), io, LogLevel::None));
+
+    ScriptedClient {
+        inbox: Inbox::new(username, received_rx),
+        lines: Some(line_tx),
+        session,
+    }
+}
+
+/// A plain `Join` as sent by clients without group keys
+fn join_message(username: &str) -> ChatMessage {
+    ChatMessage::Join {
+        username: username.to_string(),
+        key_package: None,
+        identity_key: None,
+        proof: None,
+        last_seen: None,
+        channel: None,
+    }
+}
+
+/// A plain `Join` that names the channel to join
+fn join_channel_message(username: &str, channel: &str) -> ChatMessage {
+    ChatMessage::Join {
+        username: username.to_string(),
+        key_package: None,
+        identity_key: None,
+        proof: None,
+        last_seen: None,
+        channel: Some(channel.to_string()),
+    }
+}
+
+/// A chat client driven by the test instead of stdin
+pub struct ScriptedClient {
+    inbox: Inbox,
+    lines: Option<mpsc::UnboundedSender<String>>,
+    session: JoinHandle<anyhow::Result<()>>,
+}
+
+impl ScriptedClient {
+    /// Type a line as if the user entered it
+    pub fn say(&self, line: &str) {
+        self.lines.as_ref()
+            .expect("client has already left")
+            .send(line.to_string())
+            .expect("client session has stopped");
+    }
+
+    /// Close the input, which makes the client leave the room
+    pub async fn leave(&mut self) {
+        self.lines.take();
+        tokio::time::timeout(EXPECT_TIMEOUT, &mut self.session).await
+            .expect("client did not leave in time")
+            .expect("client task panicked")
+            .expect("client session failed");
+    }
+}
+
+impl Deref for ScriptedClient {
+    type Target = Inbox;
+
+    fn deref(&self) -> &Inbox {
+        &self.inbox
+    }
+}
+
+impl DerefMut for ScriptedClient {
+    fn deref_mut(&mut self) -> &mut Inbox {
+        &mut self.inbox
+    }
+}
+
+impl Drop for ScriptedClient {
+    fn drop(&mut self) {
+        self.session.abort();
+    }
+}
+
+/// An endpoint that speaks the wire protocol directly, for protocol-level tests
+pub struct RawClient {
+    inbox: Inbox,
+    sender: LoopbackSender,
+    room: Recipient,
+    receiver: JoinHandle<()>,
+}
+
+impl RawClient {
+    pub async fn send(&self, message: &ChatMessage, format: WireFormat) {
+        let payload = format.encode(message).expect("message should encode");
+        self.sender.send_message(self.room, &payload, IncludedSurbs::Amount(10)).await
+            .expect("room should be reachable");
+    }
+}
+
+impl Deref for RawClient {
+    type Target = Inbox;
+
+    fn deref(&self) -> &Inbox {
+        &self.inbox
+    }
+}
+
+impl DerefMut for RawClient {
+    fn deref_mut(&mut self) -> &mut Inbox {
+        &mut self.inbox
+    }
+}
+
+impl Drop for RawClient {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/// Messages received by one test endpoint, with assertions over them
+pub struct Inbox {
+    pub username: String,
+    received: mpsc::UnboundedReceiver<ChatMessage>,
+}
+
+impl Inbox {
+    fn new(username: &str, received: mpsc::UnboundedReceiver<ChatMessage>) -> Self {
+        Self {
+            username: username.to_string(),
+            received,
+        }
+    }
+
+    /// Wait for the next message that matches `predicate`, skipping others.
+    /// Signed messages are matched and returned without their envelope.
+    pub async fn expect<F>(&mut self, what: &str, predicate: F) -> ChatMessage
+    where
+        F: Fn(&ChatMessage) -> bool,
+    {
+        self.expect_envelope(what, predicate).await.payload().clone()
+    }
+
+    /// Like `expect`, but returns the message as received, envelope and all
+    pub async fn expect_envelope<F>(&mut self, what: &str, predicate: F) -> ChatMessage
+    where
+        F: Fn(&ChatMessage) -> bool,
+    {
+        let wait = async {
+            while let Some(message) = self.received.recv().await {
+                if predicate(message.payload()) {
+                    return Some(message);
+                }
+            }
+            None
+        };
+
+        let result = tokio::time::timeout(EXPECT_TIMEOUT, wait).await;
+        match result {
+            Ok(Some(message)) => message,
+            Ok(None) => panic!("{}: session closed while waiting for {}", self.username, what),
+            Err(_) => panic!("{}: timed out waiting for {}", self.username, what),
+        }
+    }
+
+    /// Wait for a state sync, putting its history back together if it came in chunks
+    pub async fn expect_state_sync(&mut self) -> (Vec<HistoryItem>, Vec<String>) {
+        let mut chunks = BTreeMap::new();
+        loop {
+            let message = self.expect("state sync", |m| {
+                matches!(m, ChatMessage::StateSync { .. } | ChatMessage::StateSyncChunk { .. })
+            }).await;
+
+            match message {
+                ChatMessage::StateSyncChunk { index, history, .. } => {
+                    chunks.insert(index, history);
+                },
+                ChatMessage::StateSync { history, participants, chunked: None, .. } => return (history, participants),
+                ChatMessage::StateSync { participants, chunked: Some(chunked), .. } => {
+                    assert_eq!(chunks.len() as u32, chunked.chunks, "{}: state sync is missing chunks", self.username);
+                    return (chunks.into_values().flatten().collect(), participants);
+                },
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    pub async fn expect_join(&mut self, username: &str) {
+        self.expect(&format!("join of {}", username), |m| {
+            matches!(m, ChatMessage::Join { username: name, .. } if name == username)
+        }).await;
+    }
+
+    pub async fn expect_leave(&mut self, username: &str) {
+        self.expect(&format!("leave of {}", username), |m| {
+            matches!(m, ChatMessage::Leave { username: name } if name == username)
+        }).await;
+    }
+
+    pub async fn expect_text(&mut self, from: &str, content: &str) {
+        self.expect(&format!("\"{}\" from {}", content, from), |m| {
+            matches!(m, ChatMessage::Text { from: f, content: c, .. } if f == from && c == content)
+        }).await;
+    }
+
+    /// Assert nothing matching `predicate` arrives within `window`
+    pub async fn expect_none<F>(&mut self, what: &str, window: Duration, predicate: F)
+    where
+        F: Fn(&ChatMessage) -> bool,
+    {
+        let deadline = tokio::time::Instant::now() + window;
+        while let Ok(Some(message)) = tokio::time::timeout_at(deadline, self.received.recv()).await {
+            assert!(!predicate(message.payload()), "{}: unexpected {}: {:?}", self.username, what, message);
+        }
+    }
+}
+
+#[tokio::test]
+async fn new_participant_receives_history_and_participants() {
+    let room = TestRoom::start();
+    let mut clients = room.join_all(&["alice", "carol"]);
+    let mut carol = clients.pop().unwrap();
+    let mut alice = clients.pop().unwrap();
+
+    alice.expect_state_sync().await;
+    carol.expect_state_sync().await;
+
+    alice.say("hello");
+    carol.expect_text("alice", "hello").await;
+
+    let mut bob = room.join("bob");
+    let (history, mut participants) = bob.expect_state_sync().await;
+    participants.sort();
+
+    assert_eq!(participants, vec!["alice", "bob", "carol"]);
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].from, "alice");
+    assert_eq!(history[0].content, "hello");
+}
+
+#[tokio::test]
+async fn join_and_leave_are_broadcast() {
+    let room = TestRoom::start();
+    let mut alice = room.join("alice");
+    alice.expect_state_sync().await;
+
+    let mut bob = room.join("bob");
+    bob.expect_state_sync().await;
+    alice.expect_join("bob").await;
+
+    bob.say("bye all");
+    alice.expect_text("bob", "bye all").await;
+
+    bob.leave().await;
+    alice.expect_leave("bob").await;
+}
+
+#[tokio::test]
+async fn inactive_participants_are_pruned() {
+    let room = TestRoom::start();
+    let mut alice = room.join("alice");
+    alice.expect_state_sync().await;
+    let mut bob = room.join("bob");
+    bob.expect_state_sync().await;
+    alice.expect_join("bob").await;
+
+    // Bob stays active, Alice goes quiet
+    room.advance_clock(Duration::from_secs(200));
+    bob.say("still here");
+    alice.expect_text("bob", "still here").await;
+
+    bob.expect_none("leave before timeout", Duration::from_millis(100), |m| {
+        matches!(m, ChatMessage::Leave { .. })
+    }).await;
+
+    room.advance_clock(Duration::from_secs(150));
+    bob.expect_leave("alice").await;
+}
+
+#[tokio::test]
+async fn incompatible_hello_is_rejected_with_reason() {
+    let room = TestRoom::start();
+    let mut old = room.raw_client("old");
+
+    old.send(&ChatMessage::Hello {
+        version: 1,
+        min_version: 1,
+        capabilities: Vec::new(),
+    }, WireFormat::Json).await;
+
+    match old.expect("hello rejection", |m| matches!(m, ChatMessage::HelloRejected { .. })).await {
+        ChatMessage::HelloRejected { server_version, reason } => {
+            assert_eq!(server_version, PROTOCOL_VERSION);
+            assert!(reason.contains("upgrade"), "unhelpful reason: {}", reason);
+        },
+        _ => unreachable!(),
+    }
+}
+
+#[tokio::test]
+async fn legacy_json_client_without_handshake_is_served() {
+    let room = TestRoom::start();
+    let mut legacy = room.raw_client("legacy");
+
+    legacy.send(&join_message("legacy"), WireFormat::LegacyJson).await;
+    let (_, participants) = legacy.expect_state_sync().await;
+    assert_eq!(participants, vec!["legacy"]);
+}
+
+#[tokio::test]
+async fn accepted_text_is_acknowledged_to_sender() {
+    let room = TestRoom::start();
+    let mut alice = room.join("alice");
+    alice.expect_state_sync().await;
+
+    alice.say("did this arrive?");
+    alice.expect("ack", |m| matches!(m, ChatMessage::Ack { .. })).await;
+}
+
+#[tokio::test]
+async fn quiet_participant_is_asked_to_top_up_surbs() {
+    let room = TestRoom::start();
+    let mut quiet = room.raw_client("quiet");
+    quiet.send(&ChatMessage::Hello {
+        version: PROTOCOL_VERSION,
+        min_version: PROTOCOL_VERSION,
+        capabilities: vec![Capability::SurbTopUp],
+    }, WireFormat::Cbor).await;
+    quiet.expect("welcome", |m| matches!(m, ChatMessage::Welcome { .. })).await;
+    quiet.send(&join_message("quiet"), WireFormat::Cbor).await;
+    quiet.expect_state_sync().await;
+
+    let mut chatty = room.join("chatty");
+    chatty.expect_state_sync().await;
+    for n in 0..30 {
+        chatty.say(&format!("line {}", n));
+    }
+
+    match quiet.expect("SURB request", |m| matches!(m, ChatMessage::SurbRequest { .. })).await {
+        ChatMessage::SurbRequest { remaining } => assert!(remaining < 10, "requested early with {} left", remaining),
+        _ => unreachable!(),
+    }
+
+    // One request until the client tops up
+    quiet.expect_none("repeated SURB request", Duration::from_millis(200), |m| {
+        matches!(m, ChatMessage::SurbRequest { .. })
+    }).await;
+
+    // Topping up keeps a member that never speaks from being pruned
+    room.advance_clock(Duration::from_secs(200));
+    quiet.send(&ChatMessage::SurbTopUp { surbs: 20 }, WireFormat::Cbor).await;
+    tokio::time::sleep(TEST_PRUNE_INTERVAL * 5).await;
+    room.advance_clock(Duration::from_secs(150));
+    tokio::time::sleep(TEST_PRUNE_INTERVAL * 5).await;
+
+    quiet.send(&ChatMessage::HistoryRequest { before: None, limit: 1 }, WireFormat::Cbor).await;
+    quiet.expect("history page", |m| matches!(m, ChatMessage::HistoryPage { .. })).await;
+}
+
+#[tokio::test]
+async fn participants_cannot_speak_or_leave_for_others() {
+    let room = TestRoom::start();
+    let mut alice = room.join("alice");
+    alice.expect_state_sync().await;
+
+    let mut mallory = room.raw_client("mallory");
+    mallory.send(&join_message("mallory"), WireFormat::Cbor).await;
+    mallory.expect_state_sync().await;
+    alice.expect_join("mallory").await;
+
+    mallory.send(&ChatMessage::Leave { username: "alice".to_string() }, WireFormat::Cbor).await;
+    mallory.send(&ChatMessage::Text {
+        from: "alice".to_string(),
+        content: "I quit".to_string(),
+        timestamp: 0,
+        id: None,
+        sealed: None,
+        seq: None,
+    }, WireFormat::Cbor).await;
+
+    // The forged line is attributed to its real sender
+    alice.expect_text("mallory", "I quit").await;
+
+    let mut bob = room.join("bob");
+    let (history, mut participants) = bob.expect_state_sync().await;
+    participants.sort();
+    assert_eq!(participants, vec!["alice", "bob", "mallory"]);
+    assert_eq!(history[0].from, "mallory");
+}
+
+#[tokio::test]
+async fn taken_username_is_rejected_and_client_can_pick_another() {
+    let room = TestRoom::start();
+    let mut alice = room.join("alice");
+    alice.expect_state_sync().await;
+
+    let mut second = room.join("alice");
+    match second.expect("join rejection", |m| matches!(m, ChatMessage::JoinRejected { .. })).await {
+        ChatMessage::JoinRejected { reason } => assert!(reason.contains("taken"), "unhelpful reason: {}", reason),
+        _ => unreachable!(),
+    }
+
+    second.say("alice2");
+    let (_, mut participants) = second.expect_state_sync().await;
+    participants.sort();
+    assert_eq!(participants, vec!["alice", "alice2"]);
+
+    // The original owner of the name still hears the room
+    alice.expect_join("alice2").await;
+    second.say("hi");
+    alice.expect_text("alice2", "hi").await;
+}
+
+#[tokio::test]
+async fn room_only_relays_ciphertext_between_invited_clients() {
+    let room = TestRoom::start();
+    let secret = RoomSecret::generate();
+    let invited = || ClientConfig { room_secret: Some(secret.clone()), ..Default::default() };
+
+    let mut alice = room.join_with("alice", invited());
+    alice.expect_state_sync().await;
+    let mut bob = room.join_with("bob", invited());
+    bob.expect_state_sync().await;
+    bob.expect("key update", |m| matches!(m, ChatMessage::GroupCommit { .. })).await;
+
+    alice.say("meet at noon");
+    let sealed = match bob.expect("text from alice", |m| matches!(m, ChatMessage::Text { .. })).await {
+        ChatMessage::Text { content, sealed, .. } => {
+            assert!(content.is_empty(), "plaintext on the wire: {}", content);
+            sealed.expect("content should be sealed")
+        },
+        _ => unreachable!(),
+    };
+
+    // Sealed under a group epoch, which the invite secret alone can't open
+    assert!(sealed.epoch > 0);
+    assert!(RoomKey::derive(&secret, &room.address).open(&sealed, "alice").is_err());
+
+    // Stored history is just as opaque
+    let mut carol = room.join("carol");
+    let (history, _) = carol.expect_state_sync().await;
+    assert!(history[0].content.is_empty());
+    assert_eq!(history[0].sealed.as_ref(), Some(&sealed));
+}
+
+#[tokio::test]
+async fn departure_triggers_rekey_through_the_room() {
+    let room = TestRoom::start();
+    let secret = RoomSecret::generate();
+    let invited = || ClientConfig { room_secret: Some(secret.clone()), ..Default::default() };
+
+    let mut alice = room.join_with("alice", invited());
+    alice.expect_state_sync().await;
+    let mut bob = room.join_with("bob", invited());
+    bob.expect_state_sync().await;
+    let mut carol = room.join_with("carol", invited());
+    carol.expect_state_sync().await;
+    carol.expect("key update", |m| matches!(m, ChatMessage::GroupCommit { .. })).await;
+
+    bob.leave().await;
+    let rekey = carol.expect("key update without bob", |m| matches!(
+        m,
+        ChatMessage::GroupCommit { shares, .. } if shares.iter().all(|share| share.member != "bob")
+    )).await;
+    let ChatMessage::GroupCommit { epoch, committer, .. } = rekey else { unreachable!() };
+    assert_eq!(committer, "alice");
+
+    alice.say("bob is gone");
+    match carol.expect("text from alice", |m| matches!(m, ChatMessage::Text { .. })).await {
+        ChatMessage::Text { sealed, .. } => assert_eq!(sealed.map(|s| s.epoch), Some(epoch)),
+        _ => unreachable!(),
+    }
+}
+
+#[tokio::test]
+async fn pruned_member_keeps_its_share_until_it_leaves_itself() {
+    let room = TestRoom::start();
+    let secret = RoomSecret::generate();
+    let invited = || ClientConfig { room_secret: Some(secret.clone()), ..Default::default() };
+
+    let mut alice = room.join_with("alice", invited());
+    alice.expect_state_sync().await;
+    let mut bob = room.join_with("bob", invited());
+    bob.expect_state_sync().await;
+    let mut carol = room.join_with("carol", invited());
+    carol.expect_state_sync().await;
+    bob.expect("key update for carol", |m| matches!(
+        m,
+        ChatMessage::GroupCommit { shares, .. } if shares.iter().any(|share| share.member == "carol")
+    )).await;
+
+    // Carol goes quiet and the room drops her, but only says so unsigned
+    room.advance_clock(Duration::from_secs(200));
+    alice.say("still here");
+    bob.say("me too");
+    bob.expect_text("alice", "still here").await;
+    alice.expect_text("bob", "me too").await;
+    room.advance_clock(Duration::from_secs(150));
+    alice.expect_leave("carol").await;
+
+    bob.expect_none("rekey without carol", Duration::from_millis(300), |m| matches!(
+        m,
+        ChatMessage::GroupCommit { shares, .. } if shares.iter().all(|share| share.member != "carol")
+    )).await;
+}
+
+#[tokio::test]
+async fn member_that_missed_a_commit_gets_a_new_one() {
+    let room = TestRoom::start();
+    let secret = RoomSecret::generate();
+
+    let mut alice = room.join_with("alice", ClientConfig { room_secret: Some(secret.clone()), ..Default::default() });
+    alice.expect_state_sync().await;
+
+    let mut group = GroupState::new(secret, room.address);
+    let mut bob = room.raw_client("bob");
+    bob.send(&ChatMessage::Join {
+        username: "bob".to_string(),
+        key_package: Some(group.key_package()),
+        identity_key: None,
+        proof: None,
+        last_seen: None,
+        channel: None,
+    }, WireFormat::Json).await;
+    match bob.expect("state sync", |m| matches!(m, ChatMessage::StateSync { .. })).await {
+        ChatMessage::StateSync { key_packages, .. } => group.sync_members(&key_packages, "bob"),
+        _ => unreachable!(),
+    }
+
+    // Bob drops the commit that keys him in, then asks for the epoch he missed
+    let missed = bob.expect("key update", |m| matches!(m, ChatMessage::GroupCommit { .. })).await;
+    let ChatMessage::GroupCommit { epoch, .. } = missed else { unreachable!() };
+    bob.send(&ChatMessage::GroupKeyRequest { member: "bob".to_string(), epoch }, WireFormat::Json).await;
+
+    let commit = bob.expect("new key update", |m| matches!(
+        m,
+        ChatMessage::GroupCommit { epoch: newer, .. } if *newer > epoch
+    )).await;
+    let ChatMessage::GroupCommit { epoch, committer, commit_key, shares } = commit else { unreachable!() };
+    assert!(group.apply_commit(epoch, &committer, &commit_key, &shares, "bob").unwrap());
+
+    alice.say("welcome back");
+    match bob.expect("text from alice", |m| matches!(m, ChatMessage::Text { .. })).await {
+        ChatMessage::Text { sealed: Some(sealed), .. } => assert_eq!(group.open(&sealed, "alice").unwrap(), "welcome back"),
+        _ => panic!("text was not sealed"),
+    }
+}
+
+#[tokio::test]
+async fn unsigned_commit_does_not_move_the_group() {
+    let room = TestRoom::start();
+    let secret = RoomSecret::generate();
+    let invited = || ClientConfig { room_secret: Some(secret.clone()), ..Default::default() };
+
+    let mut alice = room.join_with("alice", invited());
+    alice.expect_state_sync().await;
+    let mut bob = room.join_with("bob", invited());
+    bob.expect("key update", |m| matches!(m, ChatMessage::GroupCommit { .. })).await;
+
+    // A member without an identity key (or the room itself) keys everyone to a secret of its own
+    let mut group = GroupState::new(secret, room.address);
+    let mut aaron = room.raw_client("aaron");
+    aaron.send(&ChatMessage::Join {
+        username: "aaron".to_string(),
+        key_package: Some(group.key_package()),
+        identity_key: None,
+        proof: None,
+        last_seen: None,
+        channel: None,
+    }, WireFormat::Json).await;
+    match aaron.expect("state sync", |m| matches!(m, ChatMessage::StateSync { .. })).await {
+        ChatMessage::StateSync { key_packages, .. } => group.sync_members(&key_packages, "aaron"),
+        _ => unreachable!(),
+    }
+    let package = group.key_package();
+    let mut commit = None;
+    for n in 0..10 {
+        commit = group.member_joined(&format!("ghost{}", n), Some(&package), "aaron");
+    }
+    let commit = commit.expect("aaron should commit");
+    let injected = match &commit {
+        ChatMessage::GroupCommit { epoch, .. } => *epoch,
+        _ => unreachable!(),
+    };
+    aaron.send(&commit, WireFormat::Json).await;
+    for member in [&mut alice, &mut bob] {
+        member.expect("unsigned key update", |m| matches!(m, ChatMessage::GroupCommit { epoch, .. } if *epoch == injected)).await;
+    }
+
+    alice.say("still ours");
+    match bob.expect("text from alice", |m| matches!(m, ChatMessage::Text { .. })).await {
+        ChatMessage::Text { sealed: Some(sealed), .. } => {
+            assert!(sealed.epoch < injected, "alice sealed under the injected epoch");
+            assert!(group.open(&sealed, "alice").is_err());
+        },
+        _ => panic!("text was not sealed"),
+    }
+}
+
+#[tokio::test]
+async fn history_sealed_before_a_member_joined_stays_sealed_for_it() {
+    let room = TestRoom::start();
+    let secret = RoomSecret::generate();
+
+    let mut alice = room.join_with("alice", ClientConfig { room_secret: Some(secret.clone()), ..Default::default() });
+    alice.expect_state_sync().await;
+    alice.say("before bob");
+    alice.expect("ack", |m| matches!(m, ChatMessage::Ack { .. })).await;
+
+    // Even with the invite, a new session holds no key for what the room kept
+    let mut group = GroupState::new(secret, room.address);
+    let mut bob = room.raw_client("bob");
+    bob.send(&ChatMessage::Join {
+        username: "bob".to_string(),
+        key_package: Some(group.key_package()),
+        identity_key: None,
+        proof: None,
+        last_seen: None,
+        channel: None,
+    }, WireFormat::Json).await;
+    let history = match bob.expect("state sync", |m| matches!(m, ChatMessage::StateSync { .. })).await {
+        ChatMessage::StateSync { history, key_packages, .. } => {
+            group.sync_members(&key_packages, "bob");
+            history
+        },
+        _ => unreachable!(),
+    };
+    let stored = history[0].sealed.clone().expect("history should be sealed");
+    assert!(!group.holds(stored.epoch));
+    assert!(group.open(&stored, "alice").is_err());
+
+    // The commit that keys bob in is for a later epoch, so it doesn't help either
+    let commit = bob.expect("key update", |m| matches!(m, ChatMessage::GroupCommit { .. })).await;
+    let ChatMessage::GroupCommit { epoch, committer, commit_key, shares } = commit else { unreachable!() };
+    assert!(epoch > stored.epoch);
+    assert!(group.apply_commit(epoch, &committer, &commit_key, &shares, "bob").unwrap());
+    assert!(!group.holds(stored.epoch));
+    assert!(group.open(&stored, "alice").is_err());
+
+    alice.say("after bob");
+    match bob.expect("text from alice", |m| matches!(m, ChatMessage::Text { .. })).await {
+        ChatMessage::Text { sealed: Some(sealed), .. } => assert_eq!(group.open(&sealed, "alice").unwrap(), "after bob"),
+        _ => panic!("text was not sealed"),
+    }
+}
+
+#[tokio::test]
+async fn signatures_survive_relay_in_any_wire_format() {
+    let room = TestRoom::start();
+    let identity = Identity::generate();
+    let alice_key = identity.public_key();
+
+    let mut alice = room.join_with("alice", ClientConfig { identity: Some(identity), ..Default::default() });
+    alice.expect_state_sync().await;
+
+    // Later joiners learn the keys of everyone already in the room
+    let mut legacy = room.raw_client("legacy");
+    legacy.send(&join_message("legacy"), WireFormat::LegacyJson).await;
+    match legacy.expect("state sync", |m| matches!(m, ChatMessage::StateSync { .. })).await {
+        ChatMessage::StateSync { identity_keys, .. } => assert_eq!(identity_keys, vec![IdentityKey {
+            username: "alice".to_string(),
+            public_key: alice_key.clone(),
+        }]),
+        _ => unreachable!(),
+    }
+
+    let mut checker = room.raw_client("checker");
+    checker.send(&ChatMessage::Hello {
+        version: PROTOCOL_VERSION,
+        min_version: PROTOCOL_VERSION,
+        capabilities: vec![Capability::Signatures],
+    }, WireFormat::Json).await;
+    checker.expect("welcome", |m| matches!(m, ChatMessage::Welcome { .. })).await;
+    checker.send(&join_message("checker"), WireFormat::Json).await;
+    checker.expect_state_sync().await;
+
+    // Alice signs CBOR, the room re-encodes the envelope as JSON
+    alice.say("signed and delivered");
+    let envelope = checker.expect_envelope("text from alice", |m| matches!(m, ChatMessage::Text { .. })).await;
+    let ChatMessage::Signed { message, signature, .. } = envelope else { panic!("text was not signed") };
+    identity::verify(&message, &signature, &alice_key, &room.address, "general")
+        .expect("signature should survive re-encoding");
+
+    // It only holds for the room and channel it was sent to, and what was signed
+    assert!(identity::verify(&message, &signature, &alice_key, &room.address, "random").is_err());
+    let ChatMessage::Text { from, timestamp, id, sealed, .. } = message.message().clone() else { unreachable!() };
+    let forged = SignedPayload::new(&ChatMessage::Text { from, content: "signed by nobody".to_string(), timestamp, id, sealed, seq: None }).unwrap();
+    assert!(identity::verify(&forged, &signature, &alice_key, &room.address, "general").is_err());
+
+    // A client that doesn't know envelopes gets just the text, still numbered
+    let relayed = legacy.expect_envelope("text from alice", |m| matches!(m, ChatMessage::Text { .. })).await;
+    assert!(matches!(relayed, ChatMessage::Text { content, seq: Some(_), .. } if content == "signed and delivered"));
+
+    // History keeps the signature for members who join later
+    let mut late = room.raw_client("late");
+    late.send(&join_message("late"), WireFormat::Json).await;
+    let (history, _) = late.expect_state_sync().await;
+    // History keeps just the signature, which covers the item as stored
+    let signature = history[0].signature.as_ref().expect("history lost the signature");
+    assert_eq!(history[0].identity_key.as_ref(), Some(&alice_key));
+    identity::verify(&history[0].signed_payload().unwrap(), signature, &alice_key, &room.address, "general")
+        .expect("history signature should verify");
+}
+
+#[tokio::test]
+async fn nested_signature_envelopes_are_dropped_without_harming_the_room() {
+    let room = TestRoom::start();
+    let mut alice = room.join("alice");
+    alice.expect_state_sync().await;
+
+    let mut mallory = room.raw_client("mallory");
+    mallory.send(&join_message("mallory"), WireFormat::Cbor).await;
+    mallory.expect_state_sync().await;
+
+    // Each envelope wraps the one before, so decoding would recurse once per level
+    let mut message = ChatMessage::Text {
+        from: "mallory".to_string(),
+        content: "nested".to_string(),
+        timestamp: 0,
+        id: None,
+        sealed: None,
+        seq: None,
+    };
+    for _ in 0..12 {
+        message = ChatMessage::Signed {
+            message: SignedPayload::new(&message).unwrap(),
+            signature: vec![0; 64],
+            proof: None,
+            seq: None,
+        };
+    }
+    mallory.send(&message, WireFormat::Cbor).await;
+    alice.expect_none("nested text", Duration::from_millis(200), |m| matches!(m, ChatMessage::Text { .. })).await;
+
+    // The room is still serving
+    let mut bob = room.join("bob");
+    bob.expect_state_sync().await;
+    alice.expect_join("bob").await;
+}
+
+#[tokio::test]
+async fn protected_room_only_admits_clients_with_the_passphrase() {
+    let room = TestRoom::start_protected("open sesame");
+    let with_passphrase = |passphrase: &str| ClientConfig { passphrase: Some(passphrase.to_string()), ..Default::default() };
+
+    let mut alice = room.join_with("alice", with_passphrase("open sesame"));
+    alice.expect_state_sync().await;
+
+    let mut guesser = room.join_with("guesser", with_passphrase("let me in"));
+    match guesser.expect("access denial", |m| matches!(m, ChatMessage::AccessDenied { .. })).await {
+        ChatMessage::AccessDenied { reason } => assert!(reason.contains("Wrong"), "unhelpful reason: {}", reason),
+        _ => unreachable!(),
+    }
+
+    // Skipping the handshake doesn't get around the challenge
+    let mut legacy = room.raw_client("legacy");
+    legacy.send(&join_message("legacy"), WireFormat::LegacyJson).await;
+    legacy.expect("access denial", |m| matches!(m, ChatMessage::AccessDenied { .. })).await;
+    legacy.expect_none("state sync", Duration::from_millis(200), |m| {
+        matches!(m, ChatMessage::StateSync { .. })
+    }).await;
+
+    let mut bob = room.join_with("bob", with_passphrase("open sesame"));
+    let (_, mut participants) = bob.expect_state_sync().await;
+    participants.sort();
+    assert_eq!(participants, vec!["alice", "bob"]);
+
+    // The proof is for the room alone
+    let join = alice.expect_envelope("join of bob", |m| matches!(m, ChatMessage::Join { username, .. } if username == "bob")).await;
+    assert!(join.join_proof().is_none());
+    assert!(join.payload().join_proof().is_none());
+}
+
+#[tokio::test]
+async fn handshake_that_never_joins_is_forgotten() {
+    let room = TestRoom::start_protected("open sesame");
+    let mut slow = room.raw_client("slow");
+    slow.send(&ChatMessage::Hello {
+        version: PROTOCOL_VERSION,
+        min_version: PROTOCOL_VERSION,
+        capabilities: Vec::new(),
+    }, WireFormat::Cbor).await;
+    let challenge = match slow.expect("welcome", |m| matches!(m, ChatMessage::Welcome { .. })).await {
+        ChatMessage::Welcome { challenge: Some(challenge), .. } => challenge,
+        other => panic!("expected a challenge, got {:?}", other),
+    };
+
+    // Well before members would be pruned, the challenge is gone
+    room.advance_clock(Duration::from_secs(120));
+    tokio::time::sleep(TEST_PRUNE_INTERVAL * 5).await;
+
+    let proof = AccessKey::from_passphrase("open sesame", &room.address).unwrap().prove(&challenge, "slow");
+    slow.send(&ChatMessage::Join {
+        username: "slow".to_string(),
+        key_package: None,
+        identity_key: None,
+        proof: Some(proof),
+        last_seen: None,
+        channel: None,
+    }, WireFormat::Cbor).await;
+    slow.expect("access denial", |m| matches!(m, ChatMessage::AccessDenied { .. })).await;
+}
+
+#[tokio::test]
+async fn persisted_history_survives_restart_until_it_ages_out() {
+    let path = std::env::temp_dir().join(format!("nymcat-history-{}.log", uuid::Uuid::new_v4()));
+    let channel_path = path.with_file_name(format!("{}-general.log", path.file_stem().unwrap().to_string_lossy()));
+    let config = || RoomConfig {
+        history: HistoryConfig {
+            path: Some(path.clone()),
+            max_items: 2,
+            max_age: Some(Duration::from_secs(60 * 60)),
+        },
+        ..RoomConfig::default()
+    };
+
+    {
+        let room = TestRoom::start_with(config());
+        let mut alice = room.join("alice");
+        alice.expect_state_sync().await;
+        for line in ["one", "two", "three"] {
+            alice.say(line);
+            alice.expect("ack", |m| matches!(m, ChatMessage::Ack { .. })).await;
+        }
+    }
+
+    // Even a lone channel logs to a file named after it, which only we can read
+    assert!(!path.exists());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&channel_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // The restarted room picks up where it left off within the count limit
+    let room = TestRoom::start_with(config());
+    let mut bob = room.join("bob");
+    let (history, _) = bob.expect_state_sync().await;
+    let contents: Vec<&str> = history.iter().map(|item| item.content.as_str()).collect();
+    assert_eq!(contents, vec!["two", "three"]);
+
+    // Once too old, history is gone from memory and from disk
+    room.advance_clock(Duration::from_secs(2 * 60 * 60));
+    tokio::time::sleep(TEST_PRUNE_INTERVAL * 5).await;
+    let mut carol = room.join("carol");
+    let (history, _) = carol.expect_state_sync().await;
+    assert!(history.is_empty());
+
+    assert!(!path.exists());
+    let log = std::fs::read_to_string(&channel_path).unwrap();
+    std::fs::remove_file(&channel_path).ok();
+    assert!(log.is_empty(), "expired history left on disk: {}", log);
+}
+
+#[tokio::test]
+async fn members_page_back_through_history() {
+    let room = TestRoom::start();
+    let mut alice = room.join("alice");
+    alice.expect_state_sync().await;
+    for line in ["one", "two", "three", "four", "five"] {
+        alice.say(line);
+        alice.expect("ack", |m| matches!(m, ChatMessage::Ack { .. })).await;
+    }
+
+    // Strangers can't read the room
+    let mut lurker = room.raw_client("lurker");
+    lurker.send(&ChatMessage::HistoryRequest { before: None, limit: 10 }, WireFormat::Cbor).await;
+    lurker.expect_none("history page", Duration::from_millis(200), |m| {
+        matches!(m, ChatMessage::HistoryPage { .. })
+    }).await;
+
+    let mut bob = room.raw_client("bob");
+    bob.send(&join_message("bob"), WireFormat::Cbor).await;
+    bob.expect_state_sync().await;
+
+    let page = |message: ChatMessage| match message {
+        ChatMessage::HistoryPage { items, more } => {
+            let contents: Vec<String> = items.iter().map(|item| item.content.clone()).collect();
+            (contents, items.first().and_then(|item| item.seq), more)
+        }
+        other => panic!("expected a history page, got {:?}", other),
+    };
+
+    bob.send(&ChatMessage::HistoryRequest { before: None, limit: 2 }, WireFormat::Cbor).await;
+    let (contents, oldest, more) = page(bob.expect("history page", |m| matches!(m, ChatMessage::HistoryPage { .. })).await);
+    assert_eq!(contents, vec!["four", "five"]);
+    assert!(more);
+
+    bob.send(&ChatMessage::HistoryRequest { before: oldest, limit: 10 }, WireFormat::Cbor).await;
+    let (contents, _, more) = page(bob.expect("history page", |m| matches!(m, ChatMessage::HistoryPage { .. })).await);
+    assert_eq!(contents, vec!["one", "two", "three"]);
+    assert!(!more);
+}
+
+#[tokio::test]
+async fn newcomer_is_synced_one_page_and_pages_back_for_the_rest() {
+    let room = TestRoom::start();
+    let mut alice = room.join("alice");
+    alice.expect_state_sync().await;
+    let lines: Vec<String> = (1..=25).map(|n| n.to_string()).collect();
+    for line in &lines {
+        alice.say(line);
+        alice.expect("ack", |m| matches!(m, ChatMessage::Ack { .. })).await;
+    }
+
+    let mut bob = room.raw_client("bob");
+    bob.send(&join_message("bob"), WireFormat::Cbor).await;
+    let oldest = match bob.expect("state sync", |m| matches!(m, ChatMessage::StateSync { .. })).await {
+        ChatMessage::StateSync { history, more, .. } => {
+            let contents: Vec<&String> = history.iter().map(|item| &item.content).collect();
+            assert_eq!(contents, lines[5..].iter().collect::<Vec<_>>());
+            assert!(more);
+            history.first().and_then(|item| item.seq)
+        },
+        _ => unreachable!(),
+    };
+
+    bob.send(&ChatMessage::HistoryRequest { before: oldest, limit: 20 }, WireFormat::Cbor).await;
+    match bob.expect("history page", |m| matches!(m, ChatMessage::HistoryPage { .. })).await {
+        ChatMessage::HistoryPage { items, more } => {
+            let contents: Vec<&String> = items.iter().map(|item| &item.content).collect();
+            assert_eq!(contents, lines[..5].iter().collect::<Vec<_>>());
+            assert!(!more);
+        },
+        _ => unreachable!(),
+    }
+}
+
+#[tokio::test]
+async fn large_history_is_synced_in_chunks_and_resent_on_request() {
+    let room = TestRoom::start();
+    let mut alice = room.join("alice");
+    alice.expect_state_sync().await;
+    let lines: Vec<String> = (0..6).map(|n| format!("{}{}", n, "x".repeat(3000))).collect();
+    for line in &lines {
+        alice.say(line);
+        alice.expect("ack", |m| matches!(m, ChatMessage::Ack { .. })).await;
+    }
+
+    // A full client puts the history back together in order
+    let mut bob = room.join("bob");
+    let (history, _) = bob.expect_state_sync().await;
+    let contents: Vec<&String> = history.iter().map(|item| &item.content).collect();
+    assert_eq!(contents, lines.iter().collect::<Vec<_>>());
+
+    // A client that lost a chunk gets it again on request
+    let mut carol = room.raw_client("carol");
+    carol.send(&ChatMessage::Hello {
+        version: PROTOCOL_VERSION,
+        min_version: PROTOCOL_VERSION,
+        capabilities: vec![Capability::BinaryCodec, Capability::ChunkedSync],
+    }, WireFormat::Cbor).await;
+    carol.expect("welcome", |m| matches!(m, ChatMessage::Welcome { .. })).await;
+    carol.send(&join_message("carol"), WireFormat::Cbor).await;
+
+    let chunked = match carol.expect("chunked state sync", |m| matches!(m, ChatMessage::StateSync { .. })).await {
+        ChatMessage::StateSync { history, chunked: Some(chunked), .. } => {
+            assert!(history.is_empty());
+            assert!(chunked.chunks > 1);
+            chunked
+        },
+        other => panic!("expected a chunked state sync, got {:?}", other),
+    };
+
+    carol.send(&ChatMessage::StateSyncResend { sync_id: chunked.sync_id, missing: vec![1] }, WireFormat::Cbor).await;
+    match carol.expect("resent chunk", |m| matches!(m, ChatMessage::StateSyncChunk { .. })).await {
+        ChatMessage::StateSyncChunk { sync_id, index, .. } => {
+            assert_eq!(sync_id, chunked.sync_id);
+            assert_eq!(index, 1);
+        },
+        _ => unreachable!(),
+    }
+    carol.expect("closing state sync", |m| matches!(m, ChatMessage::StateSync { .. })).await;
+}
+
+#[tokio::test]
+async fn rejoining_client_only_syncs_what_it_missed() {
+    let path = std::env::temp_dir().join(format!("nymcat-last-seen-{}", uuid::Uuid::new_v4()));
+    let returning = || ClientConfig {
+        last_seen: Some(LastSeen::load(&path).unwrap()),
+        ..Default::default()
+    };
+
+    let room = TestRoom::start();
+    let mut alice = room.join("alice");
+    alice.expect_state_sync().await;
+    for line in ["one", "two"] {
+        alice.say(line);
+        alice.expect("ack", |m| matches!(m, ChatMessage::Ack { .. })).await;
+    }
+
+    let mut bob = room.join_with("bob", returning());
+    let (history, _) = bob.expect_state_sync().await;
+    assert_eq!(history.len(), 2);
+
+    // The cursor is written out by the time bob has left
+    bob.leave().await;
+    assert!(path.exists(), "last seen message was not saved");
+
+    // Messages are numbered as the room relays them, and the sender learns the number too
+    let mut carol = room.join("carol");
+    carol.expect_state_sync().await;
+    alice.say("three");
+    alice.expect("ack", |m| matches!(m, ChatMessage::Ack { seq: Some(3), .. })).await;
+    let text = carol.expect_envelope("text from alice", |m| matches!(m, ChatMessage::Text { .. })).await;
+    assert_eq!(text.seq(), Some(3));
+
+    let mut bob = room.join_with("bob", returning());
+    let (history, _) = bob.expect_state_sync().await;
+    std::fs::remove_file(&path).ok();
+    let contents: Vec<&str> = history.iter().map(|item| item.content.as_str()).collect();
+    assert_eq!(contents, vec!["three"]);
+}
+
+#[tokio::test]
+async fn client_rejoining_a_restarted_room_syncs_everything() {
+    let path = std::env::temp_dir().join(format!("nymcat-last-seen-{}", uuid::Uuid::new_v4()));
+    let returning = || ClientConfig {
+        last_seen: Some(LastSeen::load(&path).unwrap()),
+        ..Default::default()
+    };
+
+    let room = TestRoom::start();
+    let mut alice = room.join("alice");
+    alice.expect_state_sync().await;
+    for line in ["one", "two"] {
+        alice.say(line);
+        alice.expect("ack", |m| matches!(m, ChatMessage::Ack { .. })).await;
+    }
+    let mut bob = room.join_with("bob", returning());
+    bob.expect_state_sync().await;
+    bob.leave().await;
+    let address = room.address;
+    drop(alice);
+    drop(room);
+
+    // The room comes back without its history and numbers from one again, at the same address
+    let room = TestRoom::start();
+    assert_eq!(room.address, address);
+    let mut alice = room.join("alice");
+    alice.expect_state_sync().await;
+    for line in ["a", "b", "c"] {
+        alice.say(line);
+        alice.expect("ack", |m| matches!(m, ChatMessage::Ack { .. })).await;
+    }
+
+    // Bob's cursor belongs to the old numbering, so none of the new items count as seen
+    let mut bob = room.join_with("bob", returning());
+    let (history, _) = bob.expect_state_sync().await;
+    std::fs::remove_file(&path).ok();
+    let contents: Vec<&str> = history.iter().map(|item| item.content.as_str()).collect();
+    assert_eq!(contents, vec!["a", "b", "c"]);
+}
+
+#[tokio::test]
+async fn channels_keep_their_own_members_and_history() {
+    let room = TestRoom::start_with(RoomConfig {
+        channels: vec!["general".to_string(), "random".to_string()],
+        ..RoomConfig::default()
+    });
+
+    // Clients that don't name a channel land in the first one
+    let mut alice = room.join("alice");
+    alice.expect_state_sync().await;
+    let mut bob = room.join_with("bob", ClientConfig {
+        channel: Some("random".to_string()),
+        ..Default::default()
+    });
+    let (_, participants) = bob.expect_state_sync().await;
+    assert_eq!(participants, vec!["bob".to_string()]);
+
+    alice.say("over in general");
+    alice.expect("ack", |m| matches!(m, ChatMessage::Ack { .. })).await;
+    bob.say("over in random");
+    bob.expect("ack", |m| matches!(m, ChatMessage::Ack { .. })).await;
+    bob.expect_none("text from general", Duration::from_millis(200), |m| {
+        matches!(m, ChatMessage::Text { .. })
+    }).await;
+
+    let mut carol = room.raw_client("carol");
+    carol.send(&join_message("carol"), WireFormat::Cbor).await;
+    let (history, mut participants) = carol.expect_state_sync().await;
+    participants.sort();
+    let contents: Vec<&str> = history.iter().map(|item| item.content.as_str()).collect();
+    assert_eq!(contents, vec!["over in general"]);
+    assert_eq!(participants, vec!["alice".to_string(), "carol".to_string()]);
+
+    carol.send(&ChatMessage::ListChannels, WireFormat::Cbor).await;
+    match carol.expect("channel list", |m| matches!(m, ChatMessage::ChannelList { .. })).await {
+        ChatMessage::ChannelList { channels } => {
+            let counts: Vec<(&str, u32)> = channels.iter().map(|c| (c.name.as_str(), c.participants)).collect();
+            assert_eq!(counts, vec![("general", 2), ("random", 1)]);
+        },
+        _ => unreachable!(),
+    }
+
+    // Names are unique across the room, and unknown channels are refused
+    let mut dave = room.raw_client("dave");
+    dave.send(&join_channel_message("alice", "random"), WireFormat::Cbor).await;
+    dave.expect("rejection of a taken name", |m| matches!(m, ChatMessage::JoinRejected { .. })).await;
+    dave.send(&join_channel_message("dave", "nope"), WireFormat::Cbor).await;
+    dave.expect("rejection of an unknown channel", |m| matches!(m, ChatMessage::JoinRejected { .. })).await;
+}
+
+#[tokio::test]
+async fn direct_messages_reach_only_their_recipient() {
+    let room = TestRoom::start();
+    let mut clients = room.join_all(&["alice", "bob", "carol"]);
+    for client in clients.iter_mut() {
+        client.expect_state_sync().await;
+    }
+    let (alice, rest) = clients.split_first_mut().unwrap();
+    let (bob, rest) = rest.split_first_mut().unwrap();
+    let carol = &mut rest[0];
+
+    alice.say("/msg bob just between us");
+    bob.expect("direct message", |m| {
+        matches!(m, ChatMessage::DirectMessage { from, content, .. } if from == "alice" && content == "just between us")
+    }).await;
+    carol.expect_none("direct message", Duration::from_millis(200), |m| {
+        matches!(m, ChatMessage::DirectMessage { .. })
+    }).await;
+
+    // Nothing of it is kept for later arrivals
+    let mut dave = room.join("dave");
+    let (history, _) = dave.expect_state_sync().await;
+    assert!(history.is_empty());
+
+    alice.say("/msg zed hello?");
+    alice.expect("delivery failure", |m| {
+        matches!(m, ChatMessage::DirectMessageFailed { to, .. } if to == "zed")
+    }).await;
+
+    // A client that can't tell a private message apart doesn't get one
+    let mut legacy = room.raw_client("legacy");
+    legacy.send(&join_message("legacy"), WireFormat::LegacyJson).await;
+    legacy.expect_state_sync().await;
+    alice.say("/msg legacy just between us");
+    alice.expect("delivery failure", |m| {
+        matches!(m, ChatMessage::DirectMessageFailed { to, .. } if to == "legacy")
+    }).await;
+    legacy.expect_none("direct message", Duration::from_millis(200), |m| {
+        matches!(m, ChatMessage::DirectMessage { .. })
+    }).await;
+}
+
+#[tokio::test]
+async fn direct_chat_needs_no_room() {
+    let network = LoopbackNetwork::new();
+    let listener = network.connect();
+    let address = listener.nym_address();
+
+    let mut bob = start_direct_chat(listener, Peer::Listen, "bob");
+    let mut alice = start_direct_chat(network.connect(), Peer::Address(address), "alice");
+    bob.expect_join("alice").await;
+
+    alice.say("hi bob");
+    bob.expect_text("alice", "hi bob").await;
+    bob.say("hi alice");
+    alice.expect_text("bob", "hi alice").await;
+
+    // Once taken, the listener only answers its first peer
+    let mut mallory = start_direct_chat(network.connect(), Peer::Address(address), "mallory");
+    mallory.say("let me in");
+    bob.say("still just us");
+    alice.expect_text("bob", "still just us").await;
+    mallory.expect_none("reply", Duration::from_millis(200), |m| matches!(m, ChatMessage::Text { .. })).await;
+
+    // The listener asks for more SURBs before it runs out of ways to answer
+    for n in 0..20 {
+        bob.say(&format!("line {}", n));
+    }
+    alice.expect("SURB request", |m| matches!(m, ChatMessage::SurbRequest { .. })).await;
+    bob.expect("SURB top-up", |m| matches!(m, ChatMessage::SurbTopUp { .. })).await;
+
+    alice.leave().await;
+    bob.expect_leave("alice").await;
+}
@@ -0,0 +1,389 @@
+// src/history.rs
+//
+// Room history with retention by count and age. History is ephemeral unless
+// the room is given a file: then every item is appended to a JSON-lines log,
+// readable only by its owner, which is reloaded on restart and rewritten with
+// only the retained items whenever enough of it has been dropped.
+//
+// Every item gets the next sequence number, which clients use to page back
+// through history and to say where they left off. Numbering starts over when
+// a room comes back with no history left, so each run of numbers gets a
+// random epoch of its own, and a cursor is only good in the epoch it came from.
+use crate::common::{HistoryCursor, HistoryItem, MessageId};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How much history a room keeps, and where
+#[derive(Debug, Clone)]
+pub struct HistoryConfig {
+    /// Append-only log to persist history in; kept in memory only if unset
+    pub path: Option<PathBuf>,
+    /// Most items kept
+    pub max_items: usize,
+    /// Items older than this are dropped
+    pub max_age: Option<Duration>,
+}
+
+impl HistoryConfig {
+    /// The same limits for one channel of a room, logged to a file of its own
+    /// named after the channel (`history.log` becomes `history-general.log`)
+    pub fn for_channel(&self, channel: &str) -> HistoryConfig {
+        let path = self.path.as_ref().map(|path| {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let file_name = match path.extension() {
+                Some(extension) => format!("{}-{}.{}", stem, channel, extension.to_string_lossy()),
+                None => format!("{}-{}", stem, channel),
+            };
+            path.with_file_name(file_name)
+        });
+
+        HistoryConfig {
+            path,
+            ..self.clone()
+        }
+    }
+}
+
+// One line of the log. The time is the room's own, as client timestamps can't be trusted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredItem {
+    stored_at: u64,
+    #[serde(default)]
+    epoch: u64, // Numbering the item's sequence number belongs to
+    item: HistoryItem,
+}
+
+pub struct History {
+    config: HistoryConfig,
+    items: VecDeque<StoredItem>,
+    log_lines: usize, // Lines in the log, including items we no longer keep
+    epoch: u64,
+    next_seq: u64,
+}
+
+impl History {
+    /// Load whatever the log holds that is still within the retention limits
+    pub fn open(config: HistoryConfig, now: SystemTime) -> anyhow::Result<Self> {
+        let mut history = Self {
+            config,
+            items: VecDeque::new(),
+            log_lines: 0,
+            epoch: rand::random(),
+            next_seq: 1,
+        };
+
+        if let Some(path) = history.config.path.as_ref().filter(|path| path.exists()) {
+            // A line cut short by a crash is skipped rather than failing the room
+            for line in fs::read_to_string(path)?.lines() {
+                history.log_lines += 1;
+                if let Ok(item) = serde_json::from_str::<StoredItem>(line) {
+                    history.items.push_back(item);
+                }
+            }
+
+            history.next_seq = history.items.iter()
+                .filter_map(|stored| stored.item.seq)
+                .max()
+                .map_or(1, |last| last + 1);
+            history.retain(now);
+            history.compact()?;
+
+            // Numbering carries on from what is left, under the same epoch
+            if let Some(stored) = history.items.back() {
+                history.epoch = stored.epoch;
+            }
+        }
+
+        Ok(history)
+    }
+
+    /// Up to `limit` items from before `before` (or the latest ones), oldest
+    /// first, and whether there are older ones still
+    pub fn page(&self, before: Option<u64>, limit: usize) -> (Vec<HistoryItem>, bool) {
+        // Items from logs that predate numbering sort before everything else
+        let older: Vec<&HistoryItem> = self.items.iter()
+            .map(|stored| &stored.item)
+            .filter(|item| before.is_none_or(|before| item.seq.unwrap_or(0) < before))
+            .collect();
+
+        let start = older.len().saturating_sub(limit);
+        (older[start..].iter().map(|item| (*item).clone()).collect(), start > 0)
+    }
+
+    /// Epoch our sequence numbers belong to
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Items that came after the one at `cursor`, oldest first, or None if
+    /// some of them are gone or we never numbered that item
+    pub fn after(&self, cursor: HistoryCursor) -> Option<Vec<HistoryItem>> {
+        let seq = cursor.seq;
+        let oldest = self.items.iter().find_map(|stored| stored.item.seq).unwrap_or(self.next_seq);
+        if cursor.epoch != self.epoch || seq >= self.next_seq || seq + 1 < oldest {
+            return None;
+        }
+
+        Some(self.items.iter()
+            .filter(|stored| stored.item.seq.is_some_and(|item_seq| item_seq > seq))
+            .map(|stored| stored.item.clone())
+            .collect())
+    }
+
+    /// Where the message `id` from `from` was numbered, if we still have it
+    pub fn seq_of(&self, from: &str, id: MessageId) -> Option<u64> {
+        self.items.iter()
+            .rev()
+            .find(|stored| stored.item.id == Some(id) && stored.item.from == from)
+            .and_then(|stored| stored.item.seq)
+    }
+
+    /// Add an item, returning the sequence number it was given
+    pub fn push(&mut self, mut item: HistoryItem, now: SystemTime) -> anyhow::Result<u64> {
+        let seq = self.next_seq;
+        item.seq = Some(seq);
+        self.next_seq += 1;
+
+        let stored = StoredItem {
+            stored_at: unix_secs(now),
+            epoch: self.epoch,
+            item,
+        };
+
+        if let Some(path) = &self.config.path {
+            let mut file = open_log(path, OpenOptions::new().append(true))?;
+            writeln!(file, "{}", serde_json::to_string(&stored)?)?;
+            self.log_lines += 1;
+        }
+        self.items.push_back(stored);
+
+        self.expire(now)?;
+        Ok(seq)
+    }
+
+    /// Drop items past the retention limits. Items dropped for age are removed
+    /// from the log straight away; those dropped for space once they add up.
+    pub fn expire(&mut self, now: SystemTime) -> anyhow::Result<()> {
+        let aged_out = self.retain(now);
+
+        let dropped = self.log_lines.saturating_sub(self.items.len());
+        if aged_out || dropped > self.config.max_items {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    // Apply the retention limits in memory, returning whether anything aged out
+    fn retain(&mut self, now: SystemTime) -> bool {
+        let before = self.items.len();
+        if let Some(max_age) = self.config.max_age {
+            let cutoff = unix_secs(now).saturating_sub(max_age.as_secs());
+            self.items.retain(|stored| stored.stored_at >= cutoff);
+        }
+        let aged_out = self.items.len() < before;
+
+        while self.items.len() > self.config.max_items {
+            self.items.pop_front();
+        }
+        aged_out
+    }
+
+    // Rewrite the log with only the retained items
+    fn compact(&mut self) -> anyhow::Result<()> {
+        let path = match &self.config.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut contents = String::new();
+        for stored in &self.items {
+            contents.push_str(&serde_json::to_string(stored)?);
+            contents.push('\n');
+        }
+
+        // Replace the log in one step so a crash leaves either version intact
+        let temp_path = path.with_extension("tmp");
+        fs::remove_file(&temp_path).ok();
+        open_log(&temp_path, OpenOptions::new().write(true))?.write_all(contents.as_bytes())?;
+        fs::rename(&temp_path, path)?;
+
+        self.log_lines = self.items.len();
+        Ok(())
+    }
+}
+
+// Open a log, creating it for the owner alone if it is new
+fn open_log(path: &Path, options: &mut OpenOptions) -> std::io::Result<File> {
+    options.create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(content: &str) -> HistoryItem {
+        HistoryItem {
+            from: "alice".to_string(),
+            content: content.to_string(),
+            timestamp: 0,
+            sealed: None,
+            seq: None,
+            id: None,
+            signature: None,
+            identity_key: None,
+        }
+    }
+
+    fn in_memory(max_items: usize) -> History {
+        let config = HistoryConfig { path: None, max_items, max_age: None };
+        History::open(config, SystemTime::now()).unwrap()
+    }
+
+    fn contents(items: &[HistoryItem]) -> Vec<&str> {
+        items.iter().map(|item| item.content.as_str()).collect()
+    }
+
+    fn logged(max_items: usize, max_age: Option<Duration>) -> HistoryConfig {
+        let path = std::env::temp_dir().join(format!("nymcat-history-{}.log", uuid::Uuid::new_v4()));
+        HistoryConfig { path: Some(path), max_items, max_age }
+    }
+
+    fn log_lines(config: &HistoryConfig) -> usize {
+        fs::read_to_string(config.path.as_ref().unwrap()).unwrap().lines().count()
+    }
+
+    #[test]
+    fn only_the_latest_items_are_kept() {
+        let mut history = in_memory(2);
+        for content in ["one", "two", "three"] {
+            history.push(text(content), SystemTime::now()).unwrap();
+        }
+
+        let (items, more) = history.page(None, 10);
+        assert_eq!(contents(&items), vec!["two", "three"]);
+        assert!(!more);
+    }
+
+    #[test]
+    fn items_age_out_of_the_log() {
+        let config = logged(10, Some(Duration::from_secs(60)));
+        let start = SystemTime::now();
+        let mut history = History::open(config.clone(), start).unwrap();
+        history.push(text("old"), start).unwrap();
+        history.push(text("new"), start + Duration::from_secs(50)).unwrap();
+
+        history.expire(start + Duration::from_secs(90)).unwrap();
+        assert_eq!(contents(&history.page(None, 10).0), vec!["new"]);
+        assert_eq!(log_lines(&config), 1);
+
+        // Once everything has aged out the room starts over
+        let epoch = history.epoch();
+        let reopened = History::open(config.clone(), start + Duration::from_secs(200)).unwrap();
+        fs::remove_file(config.path.as_ref().unwrap()).ok();
+        assert!(reopened.page(None, 10).0.is_empty());
+        assert_ne!(reopened.epoch(), epoch);
+    }
+
+    #[test]
+    fn log_is_compacted_once_enough_is_dropped() {
+        let config = logged(2, None);
+        let mut history = History::open(config.clone(), SystemTime::now()).unwrap();
+        for n in 0..4 {
+            history.push(text(&n.to_string()), SystemTime::now()).unwrap();
+        }
+        // Two dropped items aren't worth a rewrite yet
+        assert_eq!(log_lines(&config), 4);
+
+        history.push(text("4"), SystemTime::now()).unwrap();
+        assert_eq!(log_lines(&config), 2);
+
+        // What is left comes back after a restart, numbered as before
+        let epoch = history.epoch();
+        let reopened = History::open(config.clone(), SystemTime::now()).unwrap();
+        fs::remove_file(config.path.as_ref().unwrap()).ok();
+        let (items, _) = reopened.page(None, 10);
+        assert_eq!(contents(&items), vec!["3", "4"]);
+        assert_eq!(items.iter().map(|item| item.seq).collect::<Vec<_>>(), vec![Some(4), Some(5)]);
+        assert_eq!(reopened.epoch(), epoch);
+    }
+
+    #[test]
+    fn line_cut_short_is_skipped_on_load() {
+        let config = logged(10, None);
+        let mut history = History::open(config.clone(), SystemTime::now()).unwrap();
+        history.push(text("whole"), SystemTime::now()).unwrap();
+        let path = config.path.as_ref().unwrap();
+        open_log(path, OpenOptions::new().append(true)).unwrap().write_all(b"{\"stored_at\":").unwrap();
+
+        let reopened = History::open(config.clone(), SystemTime::now()).unwrap();
+        fs::remove_file(path).ok();
+        assert_eq!(contents(&reopened.page(None, 10).0), vec!["whole"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn log_is_readable_only_by_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let config = logged(1, None);
+        let mut history = History::open(config.clone(), SystemTime::now()).unwrap();
+        history.push(text("one"), SystemTime::now()).unwrap();
+        history.push(text("two"), SystemTime::now()).unwrap();
+        history.push(text("three"), SystemTime::now()).unwrap();
+
+        // Still so after the log has been rewritten
+        let path = config.path.as_ref().unwrap();
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        fs::remove_file(path).ok();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn after_covers_only_what_the_cursor_has_not_seen() {
+        let mut history = in_memory(3);
+        for content in ["one", "two", "three", "four"] {
+            history.push(text(content), SystemTime::now()).unwrap();
+        }
+        let at = |seq| HistoryCursor { epoch: history.epoch(), seq };
+
+        assert_eq!(contents(&history.after(at(3)).unwrap()), vec!["four"]);
+        assert!(history.after(at(4)).unwrap().is_empty());
+
+        // Item one is gone, so after it is still complete but before it isn't
+        assert_eq!(contents(&history.after(at(1)).unwrap()), vec!["two", "three", "four"]);
+        assert!(history.after(at(0)).is_none());
+
+        // A cursor ahead of us is from a room we no longer are
+        assert!(history.after(at(5)).is_none());
+    }
+
+    #[test]
+    fn after_ignores_cursors_from_another_epoch() {
+        let mut history = in_memory(10);
+        history.push(text("one"), SystemTime::now()).unwrap();
+
+        // The same room started over, numbering from one again
+        let mut restarted = in_memory(10);
+        for content in ["a", "b"] {
+            restarted.push(text(content), SystemTime::now()).unwrap();
+        }
+
+        let old = HistoryCursor { epoch: history.epoch(), seq: 1 };
+        assert_ne!(history.epoch(), restarted.epoch());
+        assert!(restarted.after(old).is_none());
+    }
+}
@@ -0,0 +1,159 @@
+// src/identity.rs
+//
+// Long-term Ed25519 identity keys. Each client keeps one keypair on disk and
+// signs everything it sends to the room, so "alice" today can be told apart
+// from someone else using the name tomorrow. Signatures cover the exact bytes
+// the sender encoded, carried opaquely in the envelope, so they survive the
+// room re-encoding it for participants that speak a different wire format.
+// They also cover the room and channel the message was sent to, so the room
+// can't replay a signed message somewhere else.
+use crate::common::{ChatMessage, SignedPayload};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use nym_sdk::mixnet::Recipient;
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+// Domain separation for message signatures
+const SIGNATURE_CONTEXT: &[u8] = b"nymcat signed message v2";
+
+// Bytes of the key hash shown in a fingerprint
+const FINGERPRINT_LEN: usize = 10;
+
+/// Name of the identity file in the data directory
+pub const IDENTITY_FILE: &str = "identity.key";
+
+/// How far a received message could be authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Authenticity {
+    /// Signed by the key we know for its sender
+    Verified,
+    /// Not signed, or signed by a sender whose key we don't know
+    Unverified,
+    /// Signed, but the signature doesn't match
+    Invalid,
+    /// Signed by the sender's announced key, which isn't the one we pinned for them
+    KeyChanged,
+}
+
+/// Our long-term signing key
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut rand::rngs::OsRng),
+        }
+    }
+
+    /// Load the identity at `path`, creating and saving a new one if there is none
+    pub fn load_or_generate(path: &Path) -> anyhow::Result<Self> {
+        if path.exists() {
+            let seed: [u8; 32] = fs::read(path)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Corrupt identity key file {}", path.display()))?;
+            return Ok(Self { signing_key: SigningKey::from_bytes(&seed) });
+        }
+
+        let identity = Self::generate();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Never readable by anyone else, not even between creating and writing it
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)?.write_all(&identity.signing_key.to_bytes())?;
+
+        Ok(identity)
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.signing_key.verifying_key().to_bytes().to_vec()
+    }
+
+    /// Wrap `message`, sent to `channel` of `room`, in a signature envelope.
+    /// Messages for the whole room rather than one channel use an empty channel.
+    pub fn sign(&self, message: &ChatMessage, room: &Recipient, channel: &str) -> anyhow::Result<ChatMessage> {
+        let payload = SignedPayload::new(message)?;
+        let signature = self.signing_key.sign(&signed_bytes(&payload, room, channel));
+        Ok(ChatMessage::Signed {
+            message: payload,
+            signature: signature.to_bytes().to_vec(),
+            proof: None,
+            seq: None,
+        })
+    }
+}
+
+/// Short, human-comparable fingerprint of an identity key, e.g. `3f2a 9c01 ...`
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let hash = Sha256::digest(public_key);
+    hash[..FINGERPRINT_LEN]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Check that `signature` over `payload`, sent to `channel` of `room`, was made with `public_key`
+pub fn verify(
+    payload: &SignedPayload,
+    signature: &[u8],
+    public_key: &[u8],
+    room: &Recipient,
+    channel: &str,
+) -> anyhow::Result<()> {
+    let public_key: [u8; 32] = public_key.try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid identity key length"))?;
+    let verifying_key = VerifyingKey::from_bytes(&public_key)?;
+    let signature = Signature::from_slice(signature)?;
+
+    verifying_key.verify_strict(&signed_bytes(payload, room, channel), &signature)?;
+    Ok(())
+}
+
+fn signed_bytes(payload: &SignedPayload, room: &Recipient, channel: &str) -> Vec<u8> {
+    let room = room.to_string();
+    let mut bytes = SIGNATURE_CONTEXT.to_vec();
+
+    // Length-prefixed, so no room and channel pair reads as another
+    for scope in [room.as_bytes(), channel.as_bytes()] {
+        bytes.extend((scope.len() as u32).to_be_bytes());
+        bytes.extend(scope);
+    }
+
+    bytes.extend(payload.bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_is_reloaded_from_disk() {
+        let path = std::env::temp_dir().join(format!("nymcat-identity-{}", uuid::Uuid::new_v4()));
+
+        let created = Identity::load_or_generate(&path).unwrap();
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777
+        };
+        let reloaded = Identity::load_or_generate(&path).unwrap();
+        fs::remove_file(&path).ok();
+
+        assert_eq!(created.public_key(), reloaded.public_key());
+        #[cfg(unix)]
+        assert_eq!(mode, 0o600, "identity key is readable by others");
+    }
+}
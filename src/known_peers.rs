@@ -0,0 +1,210 @@
+// src/known_peers.rs
+//
+// Trust-on-first-use pinning of identity keys. The first key we see for a
+// username in a room is remembered, and a different key for that name later
+// is reported rather than silently accepted. Pins are kept in a plain text
+// file, one `<room> <username> <key>` line each, so they can be inspected and
+// edited by hand. Usernames come from whatever the room announces, so
+// anything in them that would break a line up is written as `%XX`, and a
+// line we can't read is skipped rather than costing every other pin.
+use crate::common::Colors;
+use nym_sdk::mixnet::Recipient;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Name of the known peers file in the data directory
+pub const KNOWN_PEERS_FILE: &str = "known_peers";
+
+/// How an announced identity key compares to what we pinned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinStatus {
+    /// First time we see this name in this room
+    New,
+    /// Matches the pinned key
+    Known,
+    /// Differs from the pinned key
+    Changed,
+}
+
+/// Pinned identity keys, per room and username
+#[derive(Default)]
+pub struct KnownPeers {
+    path: Option<PathBuf>, // Nothing is saved without a file
+    pins: HashMap<(String, String), Vec<u8>>,
+}
+
+impl KnownPeers {
+    /// Pins that only last for this session
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Load the pins saved at `path`; a missing file is an empty store
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut pins = HashMap::new();
+
+        if path.exists() {
+            for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                match parse_pin(line) {
+                    Ok((slot, key)) => {
+                        pins.insert(slot, key);
+                    },
+                    Err(e) => eprintln!("{}Warning:{} skipping {}:{}: {}",
+                        Colors::YELLOW, Colors::RESET, path.display(), number + 1, e),
+                }
+            }
+        }
+
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            pins,
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The key pinned for `username` in `room`, if any
+    pub fn pinned(&self, room: &Recipient, username: &str) -> Option<&[u8]> {
+        self.pins.get(&(room.to_string(), username.to_string())).map(Vec::as_slice)
+    }
+
+    pub fn check(&self, room: &Recipient, username: &str, key: &[u8]) -> PinStatus {
+        match self.pinned(room, username) {
+            None => PinStatus::New,
+            Some(pinned) if pinned == key => PinStatus::Known,
+            Some(_) => PinStatus::Changed,
+        }
+    }
+
+    /// Pin the first key seen for `username` in `room`. Pins are never replaced here.
+    pub fn pin(&mut self, room: &Recipient, username: &str, key: &[u8]) -> anyhow::Result<()> {
+        let slot = (room.to_string(), username.to_string());
+        if self.pins.contains_key(&slot) {
+            return Ok(());
+        }
+        self.pins.insert(slot, key.to_vec());
+
+        if let Some(path) = &self.path {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{} {} {}", room, escape(username), bs58::encode(key).into_string())?;
+        }
+
+        Ok(())
+    }
+}
+
+// One `<room> <username> <key>` line
+fn parse_pin(line: &str) -> anyhow::Result<((String, String), Vec<u8>)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [room, username, key] = fields[..] else {
+        return Err(anyhow::anyhow!("expected `<room> <username> <key>`"));
+    };
+    let key = bs58::decode(key).into_vec()
+        .map_err(|e| anyhow::anyhow!("invalid key: {}", e))?;
+
+    Ok(((room.to_string(), unescape(username)?), key))
+}
+
+// Write `%`, whitespace and control characters as `%XX`, byte by byte
+fn escape(field: &str) -> String {
+    let mut escaped = String::new();
+    for c in field.chars() {
+        if c == '%' || c.is_whitespace() || c.is_control() {
+            for byte in c.to_string().bytes() {
+                escaped.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+fn unescape(field: &str) -> anyhow::Result<String> {
+    let mut bytes = Vec::new();
+    let mut rest = field.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| anyhow::anyhow!("invalid escape in {}", field))?;
+            bytes.push(hex);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{self, Identity};
+    use crate::loopback::LoopbackNetwork;
+    use crate::transport::Transport;
+
+    #[test]
+    fn first_identity_key_is_pinned_per_room() {
+        let path = std::env::temp_dir().join(format!("nymcat-known-peers-{}", uuid::Uuid::new_v4()));
+        let network = LoopbackNetwork::new();
+        let room = network.connect().nym_address();
+        let other_room = network.connect().nym_address();
+        let (alice, impostor) = (Identity::generate().public_key(), Identity::generate().public_key());
+
+        let mut peers = KnownPeers::load(&path).unwrap();
+        assert_eq!(peers.check(&room, "alice", &alice), PinStatus::New);
+        peers.pin(&room, "alice", &alice).unwrap();
+
+        // Pins survive a restart and are never silently replaced
+        let mut peers = KnownPeers::load(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(peers.check(&room, "alice", &alice), PinStatus::Known);
+        assert_eq!(peers.check(&room, "alice", &impostor), PinStatus::Changed);
+        peers.pin(&room, "alice", &impostor).unwrap();
+        assert_eq!(peers.pinned(&room, "alice"), Some(alice.as_slice()));
+
+        // Another room's alice is someone else
+        assert_eq!(peers.check(&other_room, "alice", &impostor), PinStatus::New);
+        assert_ne!(identity::fingerprint(&alice), identity::fingerprint(&impostor));
+    }
+
+    #[test]
+    fn odd_usernames_and_bad_lines_do_not_spoil_the_file() {
+        let path = std::env::temp_dir().join(format!("nymcat-known-peers-{}", uuid::Uuid::new_v4()));
+        let room = LoopbackNetwork::new().connect().nym_address();
+        let key = Identity::generate().public_key();
+
+        // A hostile room can announce names that would split a line
+        let name = "mal lory\n100%";
+        let mut peers = KnownPeers::load(&path).unwrap();
+        peers.pin(&room, name, &key).unwrap();
+        peers.pin(&room, "alice", &key).unwrap();
+
+        // Nor does a line cut short or edited wrong by hand cost the rest
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "{} bob", room).unwrap();
+        writeln!(file, "{} carol not-base58!", room).unwrap();
+
+        let peers = KnownPeers::load(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(peers.pinned(&room, name), Some(key.as_slice()));
+        assert_eq!(peers.pinned(&room, "alice"), Some(key.as_slice()));
+        assert_eq!(peers.pinned(&room, "bob"), None);
+        assert_eq!(peers.pinned(&room, "carol"), None);
+    }
+}
@@ -0,0 +1,159 @@
+// src/last_seen.rs
+//
+// Where we left off in each room: the sequence number the room gave the last
+// history item we saw there, and the epoch of the room's history it belongs
+// to. A rejoining client sends it in its `Join`, so the room only syncs what
+// came after it. Cursors are kept in a plain text file, one
+// `<room> <epoch> <seq>` line each, written out now and then and when we
+// leave rather than every time one moves. A channel asked for by name gets
+// its own cursor, written as `<room>/<channel>`.
+use crate::common::{Colors, HistoryCursor};
+use nym_sdk::mixnet::Recipient;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the last seen file in the data directory
+pub const LAST_SEEN_FILE: &str = "last_seen";
+
+/// Last seen message per room and channel
+#[derive(Default)]
+pub struct LastSeen {
+    path: Option<PathBuf>, // Nothing is saved without a file
+    cursors: HashMap<String, HistoryCursor>,
+    dirty: bool, // A cursor moved since we last saved
+}
+
+impl LastSeen {
+    /// Cursors that only last for this session
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Load the cursors saved at `path`; a missing file is an empty store
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut cursors = HashMap::new();
+
+        if path.exists() {
+            for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                // A cursor we can't read only costs a full sync for that room
+                match parse_cursor(line) {
+                    Ok((room, cursor)) => {
+                        cursors.insert(room, cursor);
+                    },
+                    Err(e) => eprintln!("{}Warning:{} skipping {}:{}: {}",
+                        Colors::YELLOW, Colors::RESET, path.display(), number + 1, e),
+                }
+            }
+        }
+
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            cursors,
+            dirty: false,
+        })
+    }
+
+    /// The last history item we saw in `channel` of `room` (its default
+    /// channel if None), if we have been there
+    pub fn get(&self, room: &Recipient, channel: Option<&str>) -> Option<HistoryCursor> {
+        self.cursors.get(&cursor_key(room, channel)).copied()
+    }
+
+    /// Move the cursor for `channel` of `room`; it is written out on the next `save`
+    pub fn record(&mut self, room: &Recipient, channel: Option<&str>, cursor: HistoryCursor) {
+        if self.cursors.insert(cursor_key(room, channel), cursor) != Some(cursor) {
+            self.dirty = true;
+        }
+    }
+
+    /// Write the cursors out if any moved since we last did
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(path) if self.dirty => path,
+            _ => return Ok(()),
+        };
+
+        let mut contents = String::new();
+        for (room, cursor) in &self.cursors {
+            contents.push_str(&format!("{} {:016x} {:016x}\n", room, cursor.epoch, cursor.seq));
+        }
+
+        // Replace the file in one step so a crash leaves either version intact
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, path)?;
+
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+// One `<room> <epoch> <seq>` line
+fn parse_cursor(line: &str) -> anyhow::Result<(String, HistoryCursor)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [room, epoch, seq] = fields[..] else {
+        return Err(anyhow::anyhow!("expected `<room> <epoch> <seq>`"));
+    };
+    let epoch = u64::from_str_radix(epoch, 16)
+        .map_err(|e| anyhow::anyhow!("invalid epoch: {}", e))?;
+    let seq = u64::from_str_radix(seq, 16)
+        .map_err(|e| anyhow::anyhow!("invalid sequence number: {}", e))?;
+
+    Ok((room.to_string(), HistoryCursor { epoch, seq }))
+}
+
+fn cursor_key(room: &Recipient, channel: Option<&str>) -> String {
+    match channel {
+        Some(channel) => format!("{}/{}", room, channel),
+        None => room.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::LoopbackNetwork;
+    use crate::transport::Transport;
+
+    #[test]
+    fn cursors_survive_save_and_load() {
+        let path = std::env::temp_dir().join(format!("nymcat-last-seen-{}", uuid::Uuid::new_v4()));
+        let room = LoopbackNetwork::new().connect().nym_address();
+        let cursor = |seq| HistoryCursor { epoch: 0x1234_5678_9abc_def0, seq };
+
+        let mut last_seen = LastSeen::load(&path).unwrap();
+        assert_eq!(last_seen.get(&room, None), None);
+        last_seen.record(&room, None, cursor(7));
+        last_seen.record(&room, Some("random"), cursor(42));
+        last_seen.save().unwrap();
+
+        let loaded = LastSeen::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(loaded.get(&room, None), Some(cursor(7)));
+        assert_eq!(loaded.get(&room, Some("random")), Some(cursor(42)));
+        assert_eq!(loaded.get(&room, Some("general")), None);
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let path = std::env::temp_dir().join(format!("nymcat-last-seen-{}", uuid::Uuid::new_v4()));
+        let room = LoopbackNetwork::new().connect().nym_address();
+        let contents = format!("# comment\n{room}/cut 2a\n{room}/bad 1 zz\n{room} 1 2a\n", room = room);
+        std::fs::write(&path, contents).unwrap();
+
+        let loaded = LastSeen::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(loaded.get(&room, None), Some(HistoryCursor { epoch: 1, seq: 0x2a }));
+        assert_eq!(loaded.get(&room, Some("cut")), None);
+        assert_eq!(loaded.get(&room, Some("bad")), None);
+    }
+}
@@ -0,0 +1,160 @@
+// src/loopback.rs
+//
+// In-process stand-in for the Nym mixnet. Every client connected to the same
+// `LoopbackNetwork` gets a fake `Recipient` and `AnonymousSenderTag`, and
+// messages are delivered instantly through channels, so a room server and
+// several clients can talk inside one test binary.
+use crate::transport::{IncomingMessage, Transport, TransportSender};
+use nym_sdk::mixnet::{AnonymousSenderTag, IncludedSurbs, Recipient};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+// Size of a serialized recipient: identity key, encryption key, gateway key
+const RECIPIENT_LEN: usize = 96;
+
+// Size of an anonymous sender tag
+const SENDER_TAG_LEN: usize = 16;
+
+// Compressed Edwards identity point, always a valid ed25519 public key
+const ED25519_IDENTITY: [u8; 32] = {
+    let mut bytes = [0u8; 32];
+    bytes[0] = 1;
+    bytes
+};
+
+#[derive(Default)]
+struct NetworkState {
+    next_id: u64,
+    mailboxes: HashMap<String, mpsc::UnboundedSender<IncomingMessage>>,
+    reply_routes: HashMap<AnonymousSenderTag, String>,
+}
+
+/// A shared in-memory "mixnet" that loopback clients connect to
+#[derive(Clone, Default)]
+pub struct LoopbackNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect a new client with a fresh fake address and sender tag
+    pub fn connect(&self) -> LoopbackClient {
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        let id = state.next_id;
+
+        let address = fake_recipient(id);
+        let sender_tag = fake_sender_tag(id);
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        state.mailboxes.insert(address.to_string(), tx);
+        state.reply_routes.insert(sender_tag, address.to_string());
+
+        LoopbackClient {
+            address,
+            inbox: rx,
+            sender: LoopbackSender {
+                network: self.clone(),
+                sender_tag,
+            },
+        }
+    }
+
+    fn deliver(&self, address: &str, message: IncomingMessage) -> anyhow::Result<()> {
+        let state = self.state.lock().unwrap();
+        let mailbox = state.mailboxes.get(address)
+            .ok_or_else(|| anyhow::anyhow!("No loopback client at {}", address))?;
+
+        mailbox.send(message)
+            .map_err(|_| anyhow::anyhow!("Loopback client at {} has disconnected", address))
+    }
+}
+
+/// One endpoint on a `LoopbackNetwork`
+pub struct LoopbackClient {
+    address: Recipient,
+    inbox: mpsc::UnboundedReceiver<IncomingMessage>,
+    sender: LoopbackSender,
+}
+
+/// Sending half of a `LoopbackClient`
+#[derive(Clone)]
+pub struct LoopbackSender {
+    network: LoopbackNetwork,
+    sender_tag: AnonymousSenderTag,
+}
+
+impl TransportSender for LoopbackSender {
+    async fn send_message(
+        &self,
+        recipient: Recipient,
+        message: &[u8],
+        surbs: IncludedSurbs,
+    ) -> anyhow::Result<()> {
+        // Only messages carrying SURBs can be replied to anonymously
+        let sender_tag = match surbs {
+            IncludedSurbs::Amount(_) => Some(self.sender_tag),
+            _ => None,
+        };
+
+        self.network.deliver(&recipient.to_string(), IncomingMessage {
+            message: message.to_vec(),
+            sender_tag,
+        })
+    }
+
+    async fn send_reply(&self, recipient: AnonymousSenderTag, message: &[u8]) -> anyhow::Result<()> {
+        let address = {
+            let state = self.network.state.lock().unwrap();
+            state.reply_routes.get(&recipient).cloned()
+                .ok_or_else(|| anyhow::anyhow!("Unknown sender tag {}", recipient))?
+        };
+
+        self.network.deliver(&address, IncomingMessage {
+            message: message.to_vec(),
+            sender_tag: None,
+        })
+    }
+}
+
+impl Transport for LoopbackClient {
+    type Sender = LoopbackSender;
+
+    fn nym_address(&self) -> Recipient {
+        self.address
+    }
+
+    fn split_sender(&self) -> LoopbackSender {
+        self.sender.clone()
+    }
+
+    async fn on_messages<F>(&mut self, callback: F)
+    where
+        F: Fn(IncomingMessage),
+    {
+        while let Some(msg) = self.inbox.recv().await {
+            callback(msg);
+        }
+    }
+}
+
+// Build a well-formed recipient that is unique per id
+fn fake_recipient(id: u64) -> Recipient {
+    let mut bytes = [0u8; RECIPIENT_LEN];
+    bytes[0..32].copy_from_slice(&ED25519_IDENTITY);
+    bytes[32..40].copy_from_slice(&id.to_le_bytes());
+    bytes[64..96].copy_from_slice(&ED25519_IDENTITY);
+
+    Recipient::try_from_bytes(bytes).expect("loopback recipient should be well formed")
+}
+
+fn fake_sender_tag(id: u64) -> AnonymousSenderTag {
+    let mut bytes = [0u8; SENDER_TAG_LEN];
+    bytes[0..8].copy_from_slice(&id.to_le_bytes());
+
+    AnonymousSenderTag::from_bytes(bytes)
+}
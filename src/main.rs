@@ -1,10 +1,40 @@
 // src/main.rs
+mod chat_client;
+mod clock;
+mod codec;
 mod common;
+mod crypto;
+mod direct;
+mod group;
+mod history;
+mod identity;
+mod known_peers;
+mod last_seen;
+mod room_server;
+mod send_queue;
 mod simple;
+mod transport;
+#[cfg(test)]
+mod harness;
+#[cfg(test)]
+mod loopback;
 
+use chat_client::{ChatClient, ProxyConfig};
 use common::{Colors, LogLevel, separator};
+use direct::Peer;
+use history::HistoryConfig;
+use identity::Identity;
+use known_peers::KnownPeers;
+use last_seen::LastSeen;
+use room_server::RoomServer;
 use std::env;
-use std::io::Write;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+// Environment variable a room passphrase can be passed in
+const PASSPHRASE_ENV: &str = "NYMCAT_PASSPHRASE";
 
 fn get_verbosity(args: &[String]) -> LogLevel {
     for arg in args {
@@ -18,15 +48,131 @@ fn get_verbosity(args: &[String]) -> LogLevel {
     LogLevel::None
 }
 
-fn get_env_file(args: &[String]) -> Option<String> {
+// Options that take a value, e.g. `--env <file>`. `--listen` only takes one
+// for `create`; `dm` takes it as a bare flag.
+const VALUE_OPTIONS: &[&str] = &[
+    "--env", "--port", "--pool-size", "--identity", "--passphrase-file", "--data-dir",
+    "--history-file", "--history-limit", "--history-max-age", "--channels", "--channel",
+    "--name",
+];
+
+fn get_option(args: &[String], name: &str) -> Option<String> {
     for (i, arg) in args.iter().enumerate() {
-        if arg == "--env" && i + 1 < args.len() {
+        if arg == name && i + 1 < args.len() {
             return Some(args[i + 1].clone());
         }
     }
     None
 }
 
+fn get_env_file(args: &[String]) -> Option<String> {
+    get_option(args, "--env")
+}
+
+fn has_flag(args: &[String], name: &str) -> bool {
+    args.iter().any(|arg| arg == name)
+}
+
+// The room passphrase, from `--passphrase-file`, the environment, or a prompt
+// when `--passphrase` is given. Never from the command line itself, where
+// other local users can read it.
+fn get_passphrase(args: &[String]) -> Result<Option<String>, String> {
+    if let Some(path) = get_option(args, "--passphrase-file") {
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read passphrase file {}: {}", path, e))?;
+        return Ok(Some(contents.trim_end_matches(['\r', '\n']).to_string()));
+    }
+    
+    if let Some(passphrase) = env::var(PASSPHRASE_ENV).ok().filter(|passphrase| !passphrase.is_empty()) {
+        return Ok(Some(passphrase));
+    }
+    
+    if has_flag(args, "--passphrase") {
+        return prompt_passphrase().map(Some);
+    }
+    
+    Ok(None)
+}
+
+// Read a passphrase from the terminal without echoing it
+fn prompt_passphrase() -> Result<String, String> {
+    print!("Room passphrase: ");
+    io::stdout().flush().ok();
+    
+    let set_echo = |echo: &str| {
+        Command::new("stty").arg(echo).stdin(Stdio::inherit()).status().ok();
+    };
+    set_echo("-echo");
+    let mut line = String::new();
+    let read = io::stdin().lock().read_line(&mut line);
+    set_echo("echo");
+    println!();
+    
+    read.map_err(|e| format!("Failed to read passphrase: {}", e))?;
+    let passphrase = line.trim_end_matches(['\r', '\n']).to_string();
+    if passphrase.is_empty() {
+        return Err("No passphrase given".to_string());
+    }
+    Ok(passphrase)
+}
+
+// Arguments after the command that are not flags or option values
+fn get_positional(args: &[String]) -> Vec<String> {
+    let mut positional = Vec::new();
+    let mut skip_value = false;
+    
+    for arg in args.iter().skip(2) {
+        if skip_value {
+            skip_value = false;
+        } else if VALUE_OPTIONS.contains(&arg.as_str()) || (args[1] == "create" && arg == "--listen") {
+            skip_value = true;
+        } else if !arg.starts_with('-') {
+            positional.push(arg.clone());
+        }
+    }
+    
+    positional
+}
+
+// Most positional arguments each command takes
+fn max_positional(command: &str) -> usize {
+    match command {
+        "join" => 2,
+        "dm" | "invite" => 1,
+        _ => 0,
+    }
+}
+
+// Where client state lives unless `--data-dir` says otherwise
+fn default_data_dir() -> PathBuf {
+    let home = env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    home.join(".nymcat")
+}
+
+fn parse_option<T: std::str::FromStr>(args: &[String], name: &str, default: T) -> Result<T, String> {
+    match get_option(args, name) {
+        Some(value) => value.parse().map_err(|_| format!("Invalid value for {}: {}", name, value)),
+        None => Ok(default),
+    }
+}
+
+// Parse a duration like `90s`, `30m`, `12h` or `7d`; a bare number is seconds
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    
+    Some(Duration::from_secs(number.parse::<u64>().ok()?.checked_mul(multiplier)?))
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
@@ -38,22 +184,163 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let verbosity = get_verbosity(&args);
     let env_file = get_env_file(&args);
+    let proxy = has_flag(&args, "--proxy");
+    let positional = get_positional(&args);
+    let data_dir = get_option(&args, "--data-dir").map(PathBuf::from);
+    
+    // `--passphrase` used to take the passphrase itself, which would now be
+    // taken for the room address and shift everything after it
+    if let Some(extra) = positional.get(max_positional(&args[1])) {
+        println!("{}Error:{} unexpected argument {}", Colors::RED, Colors::RESET, extra);
+        if has_flag(&args, "--passphrase") {
+            println!("--passphrase takes no value: it prompts for the passphrase. Use --passphrase-file or {} to pass it in.", PASSPHRASE_ENV);
+        }
+        return Ok(());
+    }
 
     match args[1].as_str() {
         "create" => {
-            simple::run_room_server(verbosity, env_file).await?;
+            let history_file = get_option(&args, "--history-file").map(PathBuf::from);
+            let channels = get_option(&args, "--channels");
+            let passphrase = get_passphrase(&args)?;
+            if proxy && (passphrase.is_some() || data_dir.is_some() || history_file.is_some() || channels.is_some()) {
+                println!("{}Error:{} --passphrase, --data-dir, --history-file and --channels are not supported with --proxy",
+                    Colors::RED, Colors::RESET);
+                return Ok(());
+            }
+            
+            if proxy {
+                let listen = get_option(&args, "--listen")
+                    .unwrap_or_else(|| room_server::DEFAULT_LISTEN_ADDRESS.to_string());
+                
+                RoomServer::new(listen, verbosity).run(env_file).await?;
+            } else {
+                let max_age = match get_option(&args, "--history-max-age") {
+                    Some(value) => Some(parse_duration(&value)
+                        .ok_or_else(|| format!("Invalid value for --history-max-age: {}", value))?),
+                    None => None,
+                };
+                
+                let defaults = simple::RoomConfig::default();
+                let config = simple::RoomConfig {
+                    passphrase,
+                    history: HistoryConfig {
+                        path: history_file,
+                        max_items: parse_option(&args, "--history-limit", defaults.history.max_items)?,
+                        max_age,
+                    },
+                    channels: match channels {
+                        Some(channels) => channels.split(',')
+                            .map(|name| name.trim().trim_start_matches('#').to_string())
+                            .collect(),
+                        None => defaults.channels.clone(),
+                    },
+                    ..defaults
+                };
+                
+                simple::run_room_server(config, data_dir, verbosity, env_file).await?;
+            }
         },
         
         "join" => {
-            if args.len() < 4 {
+            if positional.len() < 2 {
                 print_usage(&args[0]);
                 return Ok(());
             }
             
-            let address = args[2].clone();
-            let username = args[3].clone();
+            let address = positional[0].clone();
+            let username = positional[1].clone();
+            let channel = get_option(&args, "--channel").map(|name| name.trim_start_matches('#').to_string());
             
-            simple::run_chat_client(username, address, verbosity, env_file).await?;
+            if proxy && channel.is_some() {
+                println!("{}Error:{} --channel is not supported with --proxy", Colors::RED, Colors::RESET);
+                return Ok(());
+            }
+            
+            if proxy {
+                let proxy_config = ProxyConfig {
+                    port: parse_option(&args, "--port", chat_client::DEFAULT_PROXY_CLIENT_PORT)?,
+                    pool_size: parse_option(&args, "--pool-size", chat_client::DEFAULT_PROXY_CLIENT_POOL_SIZE)?,
+                };
+                
+                ChatClient::new(username, address, proxy_config, verbosity).run(env_file).await?;
+            } else {
+                // Identity, pinned keys and where we left off live in the data directory;
+                // mixnet keys only if one was given
+                let passphrase = get_passphrase(&args)?;
+                let state_dir = data_dir.clone().unwrap_or_else(default_data_dir);
+                let identity_path = get_option(&args, "--identity")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| state_dir.join(identity::IDENTITY_FILE));
+                let config = simple::ClientConfig {
+                    passphrase,
+                    identity: Some(Identity::load_or_generate(&identity_path)?),
+                    known_peers: Some(KnownPeers::load(&state_dir.join(known_peers::KNOWN_PEERS_FILE))?),
+                    last_seen: Some(LastSeen::load(&state_dir.join(last_seen::LAST_SEEN_FILE))?),
+                    channel,
+                    ..Default::default()
+                };
+                
+                simple::run_chat_client(username, address, config, data_dir, verbosity, env_file).await?;
+            }
+        },
+        
+        "dm" => {
+            if proxy {
+                println!("{}Error:{} direct chats are not supported with --proxy", Colors::RED, Colors::RESET);
+                return Ok(());
+            }
+            
+            let peer = if has_flag(&args, "--listen") {
+                Peer::Listen
+            } else if let Some(address) = positional.first() {
+                // Direct chats aren't sealed with a room secret, so don't let an invite suggest they are
+                let (address, secret) = crypto::parse_invite(address)?;
+                if secret.is_some() {
+                    println!("{}Error:{} direct chats are not end-to-end encrypted with a room secret; give the address without the #secret",
+                        Colors::RED, Colors::RESET);
+                    return Ok(());
+                }
+                Peer::Address(address)
+            } else {
+                print_usage(&args[0]);
+                return Ok(());
+            };
+            let username = get_option(&args, "--name").unwrap_or_else(|| direct::DEFAULT_USERNAME.to_string());
+            
+            direct::run_direct_chat(peer, username, data_dir, verbosity, env_file).await?;
+        },
+        
+        "invite" => {
+            if positional.is_empty() {
+                print_usage(&args[0]);
+                return Ok(());
+            }
+            
+            // A fresh secret, generated here so the room server never learns it
+            let (room, _) = crypto::parse_invite(&positional[0])?;
+            let invite = crypto::format_invite(&room, &crypto::RoomSecret::generate());
+            
+            println!("{}Room invite:{} {}", Colors::BRIGHT_YELLOW, Colors::RESET, invite);
+            println!("Share it privately. Everyone who joins with it can read the room's messages.");
+        },
+        
+        "rotate" => {
+            let data_dir = match data_dir {
+                Some(data_dir) => data_dir,
+                None => {
+                    print_usage(&args[0]);
+                    return Ok(());
+                }
+            };
+            
+            if let Some(path) = &env_file {
+                env::set_var("NYM_ENV_FILE", path);
+            }
+            
+            let address = transport::rotate_mixnet_keys(&data_dir).await?;
+            println!("{}New address:{} nym://{}", Colors::BRIGHT_YELLOW, Colors::RESET, address);
+            println!("The old address no longer reaches you. Share the new one (and fresh invites) with the room.");
         },
         
         _ => {
@@ -69,10 +356,23 @@ fn print_usage(program_name: &str) {
     println!("{}Error:{} Invalid command or arguments\n", Colors::RED, Colors::RESET);
     
     println!("{}Create a chat room:{}", Colors::BRIGHT_YELLOW, Colors::RESET);
-    println!("    {} create [--env <env_file>] [-v|-vv|-vvv]", program_name);
+    println!("    {} create [--passphrase | --passphrase-file <file>] [--data-dir <dir>] [--env <env_file>] [-v|-vv|-vvv]", program_name);
+    println!("           [--history-file <file>] [--history-limit <n>] [--history-max-age <age>] [--channels <a,b,...>]");
+    println!("    {} create --proxy [--listen <addr:port>] [--env <env_file>] [-v|-vv|-vvv]", program_name);
     
     println!("\n{}Join a chat room:{}", Colors::BRIGHT_YELLOW, Colors::RESET);
-    println!("    {} join <address> <username> [--env <env_file>] [-v|-vv|-vvv]", program_name);
+    println!("    {} join <address> <username> [--channel <name>] [--passphrase | --passphrase-file <file>] [--identity <file>] [--data-dir <dir>] [--env <env_file>] [-v|-vv|-vvv]", program_name);
+    println!("    {} join --proxy <address> <username> [--port <port>] [--pool-size <n>] [--env <env_file>] [-v|-vv|-vvv]", program_name);
+    
+    println!("\n{}Chat one-to-one without a room:{}", Colors::BRIGHT_YELLOW, Colors::RESET);
+    println!("    {} dm --listen [--name <name>] [--data-dir <dir>] [--env <env_file>] [-v|-vv|-vvv]", program_name);
+    println!("    {} dm <address> [--name <name>] [--data-dir <dir>] [--env <env_file>] [-v|-vv|-vvv]", program_name);
+    
+    println!("\n{}Create an end-to-end encrypted room invite:{}", Colors::BRIGHT_YELLOW, Colors::RESET);
+    println!("    {} invite <address>", program_name);
+    
+    println!("\n{}Replace the mixnet keys kept in a data directory with new ones:{}", Colors::BRIGHT_YELLOW, Colors::RESET);
+    println!("    {} rotate --data-dir <dir> [--env <env_file>]", program_name);
     
     println!("\n{}Verbosity levels:{}", Colors::BRIGHT_YELLOW, Colors::RESET);
     println!("    -v    Info messages");
@@ -80,7 +380,51 @@ fn print_usage(program_name: &str) {
     println!("    -vvv  Trace messages (detailed)");
     
     println!("\n{}Additional options:{}", Colors::BRIGHT_YELLOW, Colors::RESET);
-    println!("    --env <file>  Specify Nym network environment file");
+    println!("    --env <file>        Specify Nym network environment file");
+    println!("    --passphrase        Prompt for a room passphrase: required to join when set on create");
+    println!("    --passphrase-file   Read the room passphrase from this file instead (or set {})", PASSPHRASE_ENV);
+    println!("    --data-dir <dir>    Keep mixnet keys here so the address survives restarts; also holds the");
+    println!("                        identity and pinned keys (default: ~/.nymcat, without mixnet keys)");
+    println!("    --history-file <f>  Keep room history in this file across restarts (default: memory only)");
+    println!("    --history-limit <n> Most history items the room keeps (default: {})", simple::RoomConfig::default().history.max_items);
+    println!("    --history-max-age   Drop history older than this, e.g. 90m, 12h or 7d (default: never)");
+    println!("    --channels <a,b>    Channels the room serves; clients join the first unless they name one");
+    println!("                        (default: general). Each keeps its history in <history file>-<channel>");
+    println!("    --channel <name>    Channel to join (default: the room's first)");
+    println!("    --name <name>       Name shown in a direct chat (default: {})", direct::DEFAULT_USERNAME);
+    println!("    --identity <file>   Signing key to use, created if missing (default: <data dir>/identity.key)");
+    println!("    --proxy             Use the TCP-proxy (stream) variant instead of raw mixnet messages");
+    println!("    --listen <addr>     Proxy room listener address (default: {})", room_server::DEFAULT_LISTEN_ADDRESS);
+    println!("    --port <port>       Local proxy client port (default: {})", chat_client::DEFAULT_PROXY_CLIENT_PORT);
+    println!("    --pool-size <n>     Proxy client connection pool size (default: {})", chat_client::DEFAULT_PROXY_CLIENT_POOL_SIZE);
     
     println!("{}\n", separator(None, 80));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn option_values_are_not_positional() {
+        let join = args("nymcat join --passphrase-file pass.txt addr alice --channel random -v");
+        assert_eq!(get_positional(&join), vec!["addr", "alice"]);
+
+        // `--listen` takes an address for `create`, but is a bare flag for `dm`
+        assert!(get_positional(&args("nymcat create --proxy --listen 127.0.0.1:9000")).is_empty());
+        assert_eq!(get_positional(&args("nymcat dm --listen addr")), vec!["addr"]);
+    }
+
+    #[test]
+    fn passphrase_given_the_old_way_is_one_argument_too_many() {
+        let old = args("nymcat join --passphrase secret addr alice");
+        assert_eq!(get_positional(&old).len(), max_positional("join") + 1);
+
+        let new = args("nymcat join --passphrase addr alice");
+        assert_eq!(get_positional(&new).len(), max_positional("join"));
+    }
+}
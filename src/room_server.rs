@@ -1,19 +1,23 @@
 // src/room_server.rs
-use crate::common::{ChatMessage, HistoryItem, LogLevel, log};
+use crate::common::{ChatMessage, HistoryItem, LogLevel, log, frame_codec};
 use nym_sdk::tcp_proxy::NymProxyServer;
 use nym_sdk::mixnet::NymNetworkDetails;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::signal;
-use tokio_util::codec::{BytesCodec, FramedRead, FramedWrite};
+use tokio_util::codec::{FramedRead, FramedWrite};
 use tokio_stream::StreamExt;
 use futures_util::sink::SinkExt;
-use serde_json;
 
 const MAX_HISTORY_ITEMS: usize = 100;
 
+// Default address the chat listener binds to (the proxy server forwards here)
+pub const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:9000";
+
 pub struct RoomServer {
     state: Arc<Mutex<RoomState>>,
+    listen_address: String,
     verbosity: LogLevel,
 }
 
@@ -41,9 +45,10 @@ impl RoomState {
 }
 
 impl RoomServer {
-    pub fn new(verbosity: LogLevel) -> Self {
+    pub fn new(listen_address: String, verbosity: LogLevel) -> Self {
         Self {
             state: Arc::new(Mutex::new(RoomState::new())),
+            listen_address,
             verbosity,
         }
     }
@@ -56,8 +61,8 @@ impl RoomServer {
             NymNetworkDetails::new_from_env()
         };
 
-        // Initialize the proxy server (listen on localhost:9000)
-        let proxy_server = NymProxyServer::new("0.0.0.0:9000", network_details).await?;
+        // Initialize the proxy server, forwarding mixnet streams to our listener
+        let proxy_server = NymProxyServer::new(&self.listen_address, network_details).await?;
         
         // Get the server's address for display
         let nym_address = proxy_server.nym_address().to_string();
@@ -69,188 +74,226 @@ impl RoomServer {
         let verbosity = self.verbosity;
         
         // Start the server
-        let server_handle = tokio::spawn(async move {
+        let _server_handle = tokio::spawn(async move {
             proxy_server.run().await
         });
         
-        // Handle TCP connections on localhost:9000
-        let listener = tokio::net::TcpListener::bind("0.0.0.0:9000").await?;
+        // Handle TCP connections forwarded by the proxy server
+        let listener = TcpListener::bind(&self.listen_address).await?;
+        log(LogLevel::Debug, self.verbosity, &format!("Listening on {}", self.listen_address));
         
         // Spawn a task to accept connections
+        tokio::spawn(Self::accept_connections(listener, state, verbosity));
+        
+        // Wait for Ctrl+C
+        signal::ctrl_c().await?;
+        println!("Shutting down room server...");
+        
+        Ok(())
+    }
+    
+    /// Hand each accepted connection to its own task
+    async fn accept_connections(
+        listener: TcpListener,
+        state: Arc<Mutex<RoomState>>,
+        verbosity: LogLevel,
+    ) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(Self::handle_connection(stream, Arc::clone(&state), verbosity));
+                },
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                }
+            }
+        }
+    }
+    
+    /// Relay one client's frames until it disconnects or sends a bad frame
+    async fn handle_connection(
+        stream: TcpStream,
+        conn_state: Arc<Mutex<RoomState>>,
+        conn_verbosity: LogLevel,
+    ) {
+        log(LogLevel::Debug, conn_verbosity, "New connection received");
+        
+        // Split TCP stream
+        let (read_half, write_half) = stream.into_split();
+        
+        // Setup framed reading/writing
+        let mut framed_read = FramedRead::new(read_half, frame_codec());
+        let framed_write = FramedWrite::new(write_half, frame_codec());
+        
+        // Create a channel for sending messages to this client
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+        
+        // Generate a unique connection ID
+        let connection_id = uuid::Uuid::new_v4().to_string();
+        
+        // Store the sender in our state
+        {
+            let mut state = conn_state.lock().unwrap();
+            state.connections.insert(connection_id.clone(), sender);
+        }
+        
+        // Spawn a task to handle sending messages to this client
+        let conn_id_clone = connection_id.clone();
+        let writer_state = Arc::clone(&conn_state);
+        let writer_verbosity = conn_verbosity;
+        
+        let mut framed_write = framed_write;
         tokio::spawn(async move {
-            loop {
-                match listener.accept().await {
-                    Ok((stream, _)) => {
-                        let conn_state = Arc::clone(&state);
-                        let conn_verbosity = verbosity;
-                        
-                        tokio::spawn(async move {
-                            log(LogLevel::Debug, conn_verbosity, "New connection received");
-                    
-                    // Split TCP stream
-                    let (read_half, write_half) = stream.into_split();
-                    
-                    // Setup framed reading/writing
-                    let mut framed_read = FramedRead::new(read_half, BytesCodec::new());
-                    let framed_write = FramedWrite::new(write_half, BytesCodec::new());
-                    
-                    // Create a channel for sending messages to this client
-                    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
-                    
-                    // Generate a unique connection ID
-                    let connection_id = uuid::Uuid::new_v4().to_string();
-                    
-                    // Store the sender in our state
-                    {
-                        let mut state = conn_state.lock().unwrap();
-                        state.connections.insert(connection_id.clone(), sender);
-                    }
-                    
-                    // Spawn a task to handle sending messages to this client
-                    let conn_id_clone = connection_id.clone();
-                    let writer_state = Arc::clone(&conn_state);
-                    let writer_verbosity = conn_verbosity;
+            while let Some(message) = receiver.recv().await {
+                log(LogLevel::Trace, writer_verbosity, &format!(
+                    "Sending {} bytes to connection {}", message.len(), conn_id_clone
+                ));
+                
+                if let Err(e) = framed_write.send(bytes::Bytes::from(message)).await {
+                    log(LogLevel::Debug, writer_verbosity, &format!(
+                        "Error sending to client: {}", e
+                    ));
+                    break;
+                }
+            }
+            
+            // Clean up the connection when the sender is dropped
+            let mut state = writer_state.lock().unwrap();
+            state.connections.remove(&conn_id_clone);
+            
+            // Remove any participants using this connection
+            let usernames: Vec<String> = state.participants
+                .iter()
+                .filter(|(_, conn_id)| **conn_id == conn_id_clone)
+                .map(|(username, _)| username.clone())
+                .collect();
+            
+            for username in usernames {
+                state.participants.remove(&username);
+                
+                // Notify others that user left
+                let leave_msg = ChatMessage::Leave {
+                    username: username.clone(),
+                };
+                
+                if let Ok(leave_bytes) = serde_json::to_vec(&leave_msg) {
+                    Self::broadcast(&state.connections, &leave_bytes, Some(&conn_id_clone));
+                }
+            }
+        });
+        
+        // Handle incoming messages
+        while let Some(frame) = framed_read.next().await {
+            let bytes = match frame {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    // Oversized or malformed frame, the stream can't be resynchronised
+                    log(LogLevel::Debug, conn_verbosity, &format!(
+                        "Dropping connection {}: {}", connection_id, e
+                    ));
+                    break;
+                }
+            };
+            
+            log(LogLevel::Trace, conn_verbosity, &format!(
+                "Received {} bytes from connection {}", bytes.len(), connection_id
+            ));
+            
+            // Try to parse the message
+            match serde_json::from_slice::<ChatMessage>(&bytes) {
+                Ok(message) => {
+                    let mut state = conn_state.lock().unwrap();
                     
-                    let mut framed_write = framed_write;
-                    tokio::spawn(async move {
-                        while let Some(message) = receiver.recv().await {
-                            log(LogLevel::Trace, writer_verbosity, &format!(
-                                "Sending {} bytes to connection {}", message.len(), conn_id_clone
+                    match &message {
+                        ChatMessage::Join { username, .. } => {
+                            log(LogLevel::Info, conn_verbosity, &format!(
+                                "User joined: {}", username
                             ));
                             
-                            if let Err(e) = framed_write.send(bytes::Bytes::from(message)).await {
-                                log(LogLevel::Debug, writer_verbosity, &format!(
-                                    "Error sending to client: {}", e
-                                ));
-                                break;
+                            // Store participant
+                            state.participants.insert(username.clone(), connection_id.clone());
+                            
+                            // Broadcast join message
+                            if let Ok(join_bytes) = serde_json::to_vec(&message) {
+                                Self::broadcast(&state.connections, &join_bytes, None);
                             }
-                        }
-                        
-                        // Clean up the connection when the sender is dropped
-                        let mut state = writer_state.lock().unwrap();
-                        state.connections.remove(&conn_id_clone);
-                        
-                        // Remove any participants using this connection
-                        let usernames: Vec<String> = state.participants
-                            .iter()
-                            .filter(|(_, conn_id)| **conn_id == conn_id_clone)
-                            .map(|(username, _)| username.clone())
-                            .collect();
-                        
-                        for username in usernames {
-                            state.participants.remove(&username);
                             
-                            // Notify others that user left
-                            let leave_msg = ChatMessage::Leave {
-                                username: username.clone(),
+                            // Send state sync to the new user
+                            let sync_msg = ChatMessage::StateSync {
+                                history: state.history.clone(),
+                                participants: state.participants.keys().cloned().collect(),
+                                key_packages: Vec::new(),
+                                identity_keys: Vec::new(),
+                                chunked: None,
+                                since: None,
+                                channel: None,
+                                more: false,
+                                history_epoch: None,
                             };
                             
-                            if let Ok(leave_bytes) = serde_json::to_vec(&leave_msg) {
-                                Self::broadcast(&state.connections, &leave_bytes, Some(&conn_id_clone));
-                            }
-                        }
-                    });
-                    
-                    // Handle incoming messages
-                    while let Some(Ok(bytes)) = framed_read.next().await {
-                        log(LogLevel::Trace, conn_verbosity, &format!(
-                            "Received {} bytes from connection {}", bytes.len(), connection_id
-                        ));
-                        
-                        // Try to parse the message
-                        match serde_json::from_slice::<ChatMessage>(&bytes) {
-                            Ok(message) => {
-                                let mut state = conn_state.lock().unwrap();
-                                
-                                match &message {
-                                    ChatMessage::Join { username } => {
-                                        log(LogLevel::Info, conn_verbosity, &format!(
-                                            "User joined: {}", username
-                                        ));
-                                        
-                                        // Store participant
-                                        state.participants.insert(username.clone(), connection_id.clone());
-                                        
-                                        // Broadcast join message
-                                        if let Ok(join_bytes) = serde_json::to_vec(&message) {
-                                            Self::broadcast(&state.connections, &join_bytes, None);
-                                        }
-                                        
-                                        // Send state sync to the new user
-                                        let sync_msg = ChatMessage::StateSync {
-                                            history: state.history.clone(),
-                                            participants: state.participants.keys().cloned().collect(),
-                                        };
-                                        
-                                        if let Ok(sync_bytes) = serde_json::to_vec(&sync_msg) {
-                                            if let Some(sender) = state.connections.get(&connection_id) {
-                                                let _ = sender.send(sync_bytes);
-                                            }
-                                        }
-                                    },
-                                    ChatMessage::Leave { username } => {
-                                        log(LogLevel::Info, conn_verbosity, &format!(
-                                            "User left: {}", username
-                                        ));
-                                        
-                                        // Remove participant
-                                        state.participants.remove(username);
-                                        
-                                        // Broadcast leave message
-                                        if let Ok(leave_bytes) = serde_json::to_vec(&message) {
-                                            Self::broadcast(&state.connections, &leave_bytes, None);
-                                        }
-                                    },
-                                    ChatMessage::Text { from, content, timestamp } => {
-                                        log(LogLevel::Info, conn_verbosity, &format!(
-                                            "Message from {}: {}", from, content
-                                        ));
-                                        
-                                        // Store in history
-                                        let history_item = HistoryItem {
-                                            from: from.clone(),
-                                            content: content.clone(),
-                                            timestamp: *timestamp,
-                                        };
-                                        
-                                        state.add_history_item(history_item);
-                                        
-                                        // Broadcast message
-                                        if let Ok(text_bytes) = serde_json::to_vec(&message) {
-                                            Self::broadcast(&state.connections, &text_bytes, None);
-                                        }
-                                    },
-                                    ChatMessage::StateSync { .. } => {
-                                        // Ignore state sync requests from clients
-                                        log(LogLevel::Debug, conn_verbosity, "Ignoring StateSync from client");
-                                    }
+                            if let Ok(sync_bytes) = serde_json::to_vec(&sync_msg) {
+                                if let Some(sender) = state.connections.get(&connection_id) {
+                                    let _ = sender.send(sync_bytes);
                                 }
-                            },
-                            Err(e) => {
-                                log(LogLevel::Debug, conn_verbosity, &format!(
-                                    "Failed to parse message: {}", e
-                                ));
                             }
-                        }
-                    }
-                    
-                            log(LogLevel::Debug, conn_verbosity, &format!(
-                                "Connection {} closed", connection_id
+                        },
+                        ChatMessage::Leave { username } => {
+                            log(LogLevel::Info, conn_verbosity, &format!(
+                                "User left: {}", username
+                            ));
+                            
+                            // Remove participant
+                            state.participants.remove(username);
+                            
+                            // Broadcast leave message
+                            if let Ok(leave_bytes) = serde_json::to_vec(&message) {
+                                Self::broadcast(&state.connections, &leave_bytes, None);
+                            }
+                        },
+                        ChatMessage::Text { from, content, timestamp, sealed, .. } => {
+                            log(LogLevel::Info, conn_verbosity, &format!(
+                                "Message from {}: {}", from, content
                             ));
-                        });
-                    },
-                    Err(e) => {
-                        eprintln!("Failed to accept connection: {}", e);
+                            
+                            // Store in history
+                            let history_item = HistoryItem {
+                                from: from.clone(),
+                                content: content.clone(),
+                                timestamp: *timestamp,
+                                sealed: sealed.clone(),
+                                seq: None,
+                                id: None,
+                                signature: None,
+                                identity_key: None,
+                            };
+                            
+                            state.add_history_item(history_item);
+                            
+                            // Broadcast message
+                            if let Ok(text_bytes) = serde_json::to_vec(&message) {
+                                Self::broadcast(&state.connections, &text_bytes, None);
+                            }
+                        },
+                        _ => {
+                            // The stream variant only speaks the original protocol
+                            log(LogLevel::Debug, conn_verbosity, "Ignoring unsupported message from client");
+                        }
                     }
+                },
+                Err(e) => {
+                    log(LogLevel::Debug, conn_verbosity, &format!(
+                        "Failed to parse message: {}", e
+                    ));
                 }
             }
-        });
+        }
         
-        // Wait for Ctrl+C
-        signal::ctrl_c().await?;
-        println!("Shutting down room server...");
+        // Dropping our sender stops the writer, which cleans up participants
+        conn_state.lock().unwrap().connections.remove(&connection_id);
         
-        Ok(())
+        log(LogLevel::Debug, conn_verbosity, &format!(
+            "Connection {} closed", connection_id
+        ));
     }
     
     fn broadcast(
@@ -269,3 +312,88 @@ impl RoomServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::MAX_FRAME_SIZE;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+    const EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn start_listener() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(RoomState::new()));
+        tokio::spawn(RoomServer::accept_connections(listener, state, LogLevel::None));
+        address
+    }
+
+    async fn connect(address: SocketAddr) -> Framed<TcpStream, LengthDelimitedCodec> {
+        Framed::new(TcpStream::connect(address).await.unwrap(), frame_codec())
+    }
+
+    async fn send(stream: &mut Framed<TcpStream, LengthDelimitedCodec>, message: &ChatMessage) {
+        stream.send(bytes::Bytes::from(serde_json::to_vec(message).unwrap())).await.unwrap();
+    }
+
+    /// Read frames until one parses as a message matching `predicate`
+    async fn expect<F>(stream: &mut Framed<TcpStream, LengthDelimitedCodec>, what: &str, predicate: F)
+    where
+        F: Fn(&ChatMessage) -> bool,
+    {
+        let wait = async {
+            while let Some(frame) = stream.next().await {
+                let message: ChatMessage = serde_json::from_slice(&frame.unwrap()).unwrap();
+                if predicate(&message) {
+                    return;
+                }
+            }
+            panic!("connection closed while waiting for {}", what);
+        };
+        tokio::time::timeout(EXPECT_TIMEOUT, wait).await
+            .unwrap_or_else(|_| panic!("timed out waiting for {}", what));
+    }
+
+    #[tokio::test]
+    async fn oversize_frame_drops_only_that_connection() {
+        let address = start_listener().await;
+
+        let mut alice = connect(address).await;
+        send(&mut alice, &ChatMessage::Join {
+            username: "alice".to_string(),
+            key_package: None,
+            identity_key: None,
+            proof: None,
+            last_seen: None,
+            channel: None,
+        }).await;
+        expect(&mut alice, "state sync", |m| matches!(m, ChatMessage::StateSync { .. })).await;
+
+        // Announce a frame past the limit; the room can't skip it, so it hangs up
+        let mut oversize = TcpStream::connect(address).await.unwrap();
+        oversize.write_all(&((MAX_FRAME_SIZE + 1) as u32).to_be_bytes()).await.unwrap();
+        oversize.write_all(&[0; 64]).await.unwrap();
+
+        let mut buf = [0; 64];
+        let read = tokio::time::timeout(EXPECT_TIMEOUT, oversize.read(&mut buf)).await
+            .expect("oversize connection was not dropped");
+        assert!(matches!(read, Ok(0) | Err(_)), "oversize connection still open");
+
+        // Everyone else keeps talking
+        send(&mut alice, &ChatMessage::Text {
+            from: "alice".to_string(),
+            content: "still here".to_string(),
+            timestamp: 1,
+            id: None,
+            sealed: None,
+            seq: None,
+        }).await;
+        expect(&mut alice, "own message", |m| {
+            matches!(m, ChatMessage::Text { content, .. } if content == "still here")
+        }).await;
+    }
+}
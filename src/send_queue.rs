@@ -0,0 +1,170 @@
+// src/send_queue.rs
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+// Times a waiting lower-priority queue may be passed over before it gets a turn
+const STARVATION_LIMIT: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessagePriority {
+    High,   // Join/Leave messages
+    Medium, // State sync and system messages
+    Low,    // Regular chat messages
+}
+
+impl MessagePriority {
+    const ALL: [MessagePriority; 3] = [MessagePriority::High, MessagePriority::Medium, MessagePriority::Low];
+
+    fn index(self) -> usize {
+        match self {
+            MessagePriority::High => 0,
+            MessagePriority::Medium => 1,
+            MessagePriority::Low => 2,
+        }
+    }
+}
+
+/// Number of queued items at each priority
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueDepths {
+    pub high: usize,
+    pub medium: usize,
+    pub low: usize,
+}
+
+struct Queues<T> {
+    queues: [VecDeque<T>; 3],
+    skipped: [u32; 3], // Turns each non-empty queue has been passed over
+}
+
+/// Bounded multi-priority send queue with a single consumer.
+///
+/// Higher priorities are served first, but a lower-priority queue that has
+/// been passed over `STARVATION_LIMIT` times in a row gets the next turn, so
+/// a steady stream of joins and syncs can't stall chat text indefinitely.
+pub struct SendQueue<T> {
+    inner: Mutex<Queues<T>>,
+    capacity: usize,
+    notify: Notify,
+}
+
+impl<T> SendQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Queues {
+                queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+                skipped: [0; 3],
+            }),
+            capacity,
+            notify: Notify::new(),
+        }
+    }
+
+    /// Queue an item, evicting the oldest lower-priority item if full.
+    /// Returns the item back if there was no room for it.
+    pub fn push(&self, priority: MessagePriority, item: T) -> Result<(), T> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let len: usize = inner.queues.iter().map(VecDeque::len).sum();
+
+            if len >= self.capacity {
+                let evicted = MessagePriority::ALL.iter().rev()
+                    .take_while(|p| p.index() > priority.index())
+                    .any(|p| inner.queues[p.index()].pop_front().is_some());
+
+                if !evicted {
+                    return Err(item);
+                }
+            }
+
+            inner.queues[priority.index()].push_back(item);
+        }
+
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Wait for the next item to send
+    pub async fn pop(&self) -> (MessagePriority, T) {
+        loop {
+            if let Some(next) = self.try_pop() {
+                return next;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    fn try_pop(&self) -> Option<(MessagePriority, T)> {
+        let mut inner = self.inner.lock().unwrap();
+
+        // A starved queue gets its turn first, lowest priority first
+        let starved = MessagePriority::ALL.iter().rev().copied()
+            .find(|p| inner.skipped[p.index()] >= STARVATION_LIMIT && !inner.queues[p.index()].is_empty());
+
+        let priority = starved.or_else(|| {
+            MessagePriority::ALL.iter().copied().find(|p| !inner.queues[p.index()].is_empty())
+        })?;
+
+        let item = inner.queues[priority.index()].pop_front()?;
+        inner.skipped[priority.index()] = 0;
+
+        // Every lower-priority queue still waiting has been passed over once more
+        for other in MessagePriority::ALL {
+            if other.index() > priority.index() && !inner.queues[other.index()].is_empty() {
+                inner.skipped[other.index()] += 1;
+            }
+        }
+
+        Some((priority, item))
+    }
+
+    pub fn depths(&self) -> QueueDepths {
+        let inner = self.inner.lock().unwrap();
+        QueueDepths {
+            high: inner.queues[0].len(),
+            medium: inner.queues[1].len(),
+            low: inner.queues[2].len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn membership_changes_overtake_queued_text_without_starving_it() {
+        let queue = SendQueue::new(100);
+        for n in 0..3 {
+            queue.push(MessagePriority::Low, format!("text {}", n)).unwrap();
+        }
+        for n in 0..20 {
+            queue.push(MessagePriority::High, format!("join {}", n)).unwrap();
+        }
+
+        let mut order = Vec::new();
+        for _ in 0..23 {
+            order.push(queue.pop().await);
+        }
+
+        // Joins go first, but text still gets a turn while joins are waiting
+        assert_eq!(order[0].0, MessagePriority::High);
+        let first_text = order.iter().position(|(p, _)| *p == MessagePriority::Low).unwrap();
+        assert!(first_text < 20, "text starved behind {} joins", first_text);
+        assert_eq!(order[first_text].1, "text 0");
+    }
+
+    #[tokio::test]
+    async fn full_queue_evicts_text_to_make_room_for_joins() {
+        let queue = SendQueue::new(2);
+        queue.push(MessagePriority::Low, "old text").unwrap();
+        queue.push(MessagePriority::Low, "new text").unwrap();
+
+        assert_eq!(queue.push(MessagePriority::Low, "overflow"), Err("overflow"));
+        queue.push(MessagePriority::High, "join").unwrap();
+
+        assert_eq!(queue.pop().await, (MessagePriority::High, "join"));
+        assert_eq!(queue.pop().await, (MessagePriority::Low, "new text"));
+    }
+}
@@ -1,10 +1,23 @@
 // src/simple.rs
 use crate::common::{
-    ChatMessage, HistoryItem, LogLevel, Colors, log, format_timestamp, separator
+    Capability, ChannelInfo, ChatMessage, DeliveryState, HistoryCursor, HistoryItem, IdentityKey, KeyPackage, LogLevel, MessageId, SyncChunks, Colors, log,
+    format_delivery_status, format_timestamp, new_message_id, separator,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
 };
-use nym_sdk::mixnet::{MixnetClient, MixnetMessageSender, Recipient, IncludedSurbs, AnonymousSenderTag};
-use std::collections::{HashMap, VecDeque};
-use std::str::FromStr;
+use crate::clock::{Clock, SystemClock};
+use crate::codec::{self, WireFormat};
+use crate::crypto::{self, AccessKey, RoomSecret, Sealed};
+use crate::group::GroupState;
+use crate::history::{History, HistoryConfig};
+use crate::identity::{self, Authenticity, Identity};
+use crate::known_peers::{KnownPeers, PinStatus};
+use crate::last_seen::LastSeen;
+use crate::send_queue::{MessagePriority, SendQueue};
+use crate::transport::{self, Transport, TransportSender};
+use nym_sdk::mixnet::{Recipient, IncludedSurbs, AnonymousSenderTag};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::signal;
@@ -14,9 +27,29 @@ use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
 // Reduced from 50 to 15 to decrease network overhead while maintaining reliability
 const SURBS_PER_MESSAGE: u32 = 15; 
 
+// Estimated reply SURBs left for a participant below which the room asks for more
+const SURB_LOW_WATERMARK: u32 = 10;
+
+// Clients top up enough SURBs for this much of the room's recent traffic
+const SURB_TOPUP_HORIZON_SECS: u64 = 120;
+
+// Bounds on a single SURB top-up
+const MIN_SURB_TOPUP: u32 = 20;
+const MAX_SURB_TOPUP: u32 = 200;
+
+// Longest username the room accepts, in characters
+const MAX_USERNAME_LEN: usize = 32;
+
+// Channel of a room that doesn't name any
+const DEFAULT_CHANNEL: &str = "general";
+
 // Maximum history items to retain (prevents unbounded memory growth)
 const MAX_HISTORY_SIZE: usize = 100;
 
+// History items a client asks for per /history, and the most the room sends at once
+const HISTORY_PAGE_SIZE: u32 = 20;
+const MAX_HISTORY_PAGE: u32 = 50;
+
 // Participant timeout in seconds (prune after this duration of inactivity)
 const PARTICIPANT_TIMEOUT_SECS: u64 = 300; // 5 minutes
 
@@ -26,18 +59,148 @@ const BATCH_SIZE: usize = 10;
 // Maximum message queue size (prevent memory exhaustion)
 const MAX_QUEUE_SIZE: usize = 1000;
 
-#[derive(Debug, Clone, Copy)]
-enum MessagePriority {
-    High,   // Join/Leave messages
-    Medium, // State sync and system messages
-    Low,    // Regular chat messages
+// How often the room server looks for inactive participants
+const PRUNE_INTERVAL_SECS: u64 = 60;
+
+// Handshakes the room keeps waiting for a join; the oldest is forgotten to make room
+const MAX_PENDING_HELLOS: usize = 256;
+
+// How long the room keeps a handshake that hasn't turned into a join
+const PENDING_HELLO_TIMEOUT_SECS: u64 = 60;
+
+// How long a client waits for the room's welcome before assuming a legacy server
+const HANDSHAKE_TIMEOUT_SECS: u64 = 20;
+
+// Protocol features each side advertises during the handshake
+const CLIENT_CAPABILITIES: &[Capability] = &[
+    Capability::BinaryCodec, Capability::DeliveryAcks, Capability::SurbTopUp, Capability::Signatures, Capability::ChunkedSync,
+    Capability::DirectMessages,
+];
+const SERVER_CAPABILITIES: &[Capability] = &[
+    Capability::BinaryCodec, Capability::DeliveryAcks, Capability::SurbTopUp, Capability::Signatures, Capability::ChunkedSync,
+    Capability::DirectMessages,
+];
+
+// Most history, in encoded bytes, sent in one state sync message; more is split into chunks
+const SYNC_CHUNK_BYTES: usize = 8 * 1024;
+
+// How long the room keeps a chunked state sync around for resend requests
+const SYNC_RESEND_WINDOW_SECS: u64 = 120;
+
+// How long a client waits for the rest of a chunked state sync before asking again
+const SYNC_CHUNK_TIMEOUT_SECS: u64 = 10;
+
+// Requests for missing state sync chunks before the client gives up on them
+const MAX_SYNC_RESENDS: u32 = 3;
+
+// Wait before the first retransmission of an unacknowledged line (doubles each retry)
+const RETRANSMIT_INITIAL_SECS: u64 = 10;
+
+// Sends of one line (first send plus retries) before it is shown as failed
+const MAX_SEND_ATTEMPTS: u32 = 4;
+
+// How often the client checks for overdue acknowledgements
+const ACK_CHECK_INTERVAL_SECS: u64 = 5;
+
+// Recent (sender, message id) pairs the room remembers to drop retransmissions
+const DEDUP_WINDOW_SIZE: usize = 1000;
+
+/// Tunables for a room server instance
+pub struct RoomConfig {
+    /// Time source used for participant activity and pruning
+    pub clock: Arc<dyn Clock>,
+    /// How often inactive participants are pruned
+    pub prune_interval: Duration,
+    /// Passphrase joining clients must prove they know; anyone may join without one
+    pub passphrase: Option<String>,
+    /// How much history to keep, and whether to keep it across restarts
+    pub history: HistoryConfig,
+    /// Channels served at the room address; clients that don't name one join the first
+    pub channels: Vec<String>,
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            prune_interval: Duration::from_secs(PRUNE_INTERVAL_SECS),
+            passphrase: None,
+            history: HistoryConfig {
+                path: None,
+                max_items: MAX_HISTORY_SIZE,
+                max_age: None,
+            },
+            channels: vec![DEFAULT_CHANNEL.to_string()],
+        }
+    }
+}
+
+/// Options for a chat client session
+#[derive(Default)]
+pub struct ClientConfig {
+    /// Secret from the room invite; without it messages are sent in the clear
+    pub room_secret: Option<RoomSecret>,
+    /// Passphrase for rooms that require one
+    pub passphrase: Option<String>,
+    /// Key we sign our messages with; a throwaway one is generated if unset
+    pub identity: Option<Identity>,
+    /// Identity keys pinned on first contact; kept in memory only if unset
+    pub known_peers: Option<KnownPeers>,
+    /// Where we left off in each room; kept in memory only if unset
+    pub last_seen: Option<LastSeen>,
+    /// Channel to join; the room's first channel if unset
+    pub channel: Option<String>,
+}
+
+/// Where a chat session reads its input lines from and reports what it receives
+pub struct SessionIo {
+    /// Lines typed by the user; the session leaves the room when this closes
+    pub lines: mpsc::UnboundedReceiver<String>,
+    /// Optional copy of every message received from the room
+    pub received: Option<mpsc::UnboundedSender<ChatMessage>>,
+}
+
+impl SessionIo {
+    /// Feed lines from stdin into a session until Ctrl+C, then run `on_exit`.
+    /// Once stdin closes the session keeps receiving until Ctrl+C.
+    pub fn stdin(on_exit: impl FnOnce() + Send + 'static) -> Self {
+        let (line_tx, line_rx) = mpsc::unbounded_channel::<String>();
+        
+        tokio::spawn(async move {
+            let stdin = BufReader::new(tokio::io::stdin());
+            let mut lines = stdin.lines();
+            
+            loop {
+                tokio::select! {
+                    line = lines.next_line() => match line {
+                        Ok(Some(line)) => {
+                            if line_tx.send(line).is_err() {
+                                return;
+                            }
+                        },
+                        _ => {
+                            signal::ctrl_c().await.ok();
+                            break;
+                        }
+                    },
+                    _ = signal::ctrl_c() => break,
+                }
+            }
+            
+            on_exit();
+        });
+        
+        Self {
+            lines: line_rx,
+            received: None,
+        }
+    }
 }
 
 #[derive(Debug)]
 struct QueuedMessage {
     message: Vec<u8>,
     recipient: AnonymousSenderTag,
-    priority: MessagePriority,
     timestamp: Instant,
 }
 
@@ -46,125 +209,468 @@ struct Participant {
     username: String,
     sender_tag: AnonymousSenderTag,
     last_active: SystemTime,
+    format: WireFormat, // Encoding this participant speaks
+    version: u32,
+    capabilities: Vec<Capability>,
+    surb_balance: u32, // Estimated reply SURBs we still hold for this participant
+    surbs_requested: bool, // A SurbRequest is outstanding
+    key_package: Option<Vec<u8>>, // Public key for group key updates, relayed as-is
+    identity_key: Option<Vec<u8>>, // Long-term signing key, shared with later joiners
+    sync: Option<SentSync>, // Our last chunked state sync to them, kept for resends
 }
 
-struct RoomState {
+/// An encoded chunked state sync, ready to send again
+#[derive(Debug)]
+struct SentSync {
+    sync_id: u64,
+    chunks: Vec<Vec<u8>>,
+    complete: Vec<u8>, // The closing `StateSync`
+    sent: SystemTime,
+}
+
+/// Handshake details from a client that has not joined yet
+#[derive(Debug)]
+struct PendingHello {
+    version: u32,
+    capabilities: Vec<Capability>,
+    received: SystemTime,
+    surb_balance: u32,
+    challenge: Option<Vec<u8>>, // What the join must answer, in protected rooms
+}
+
+/// Bounded memory of recently accepted messages, oldest forgotten first
+struct DedupWindow {
+    seen: HashSet<(AnonymousSenderTag, MessageId)>,
+    order: VecDeque<(AnonymousSenderTag, MessageId)>,
+    capacity: usize,
+}
+
+impl DedupWindow {
+    fn new(capacity: usize) -> Self {
+        Self {
+            seen: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Record a message, returning false if it was already seen
+    fn insert(&mut self, sender: AnonymousSenderTag, id: MessageId) -> bool {
+        if !self.seen.insert((sender, id)) {
+            return false;
+        }
+        
+        self.order.push_back((sender, id));
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        
+        true
+    }
+}
+
+/// One named channel of the room, with its own members and history
+struct Channel {
     participants: HashMap<String, Participant>,
-    history: VecDeque<HistoryItem>,
+    history: History,
+}
+
+struct RoomState {
+    channels: BTreeMap<String, Channel>,
+    default_channel: String, // Where clients that don't name a channel go
+    pending_hellos: HashMap<AnonymousSenderTag, PendingHello>,
+    recent_messages: DedupWindow,
     start_time: SystemTime,
     message_count: usize,
     broadcast_count: usize,
+    clock: Arc<dyn Clock>,
+    access_key: Option<AccessKey>, // Set when the room requires a passphrase
 }
 
 impl RoomState {
-    fn new() -> Self {
+    /// A room serving `channels`, the first of which is the default
+    fn new(clock: Arc<dyn Clock>, access_key: Option<AccessKey>, channels: Vec<(String, History)>) -> Self {
+        let default_channel = channels.first().map(|(name, _)| name.clone()).unwrap_or_default();
         Self {
-            participants: HashMap::new(),
-            history: VecDeque::with_capacity(MAX_HISTORY_SIZE),
-            start_time: SystemTime::now(),
+            channels: channels.into_iter()
+                .map(|(name, history)| (name, Channel { participants: HashMap::new(), history }))
+                .collect(),
+            default_channel,
+            pending_hellos: HashMap::new(),
+            recent_messages: DedupWindow::new(DEDUP_WINDOW_SIZE),
+            start_time: clock.now(),
             message_count: 0,
             broadcast_count: 0,
+            clock,
+            access_key,
         }
     }
 
-    fn add_history_item(&mut self, item: HistoryItem) {
-        self.history.push_back(item);
-        if self.history.len() > MAX_HISTORY_SIZE {
-            self.history.pop_front();
+    /// Store `item` in `channel`'s history, returning the sequence number it was given
+    fn add_history_item(&mut self, channel: &str, item: HistoryItem) -> anyhow::Result<Option<u64>> {
+        let now = self.clock.now();
+        match self.channels.get_mut(channel) {
+            Some(channel) => channel.history.push(item, now).map(Some),
+            None => Ok(None),
         }
     }
 
-    fn prune_inactive_participants(&mut self) -> Vec<String> {
-        let now = SystemTime::now();
+    /// Check whether `sender_tag` may join `channel` as `username`, explaining why not.
+    /// Names are unique across channels, so a name means one person throughout the room.
+    fn check_join(&self, username: &str, channel: &str, sender_tag: AnonymousSenderTag) -> Result<(), String> {
+        validate_username(username)?;
+        
+        if !self.channels.contains_key(channel) {
+            let names: Vec<String> = self.channels.keys().map(|name| format!("#{}", name)).collect();
+            return Err(format!("There is no channel #{} in this room. Channels: {}", channel, names.join(", ")));
+        }
+        
+        if let Some((_, existing)) = self.participant(username) {
+            if existing.sender_tag != sender_tag {
+                return Err(format!("The name {} is already taken in this room.", username));
+            }
+        }
+        
+        match self.member(sender_tag) {
+            Some((joined, existing)) if existing.username != username || joined != channel => {
+                Err(format!("You have already joined #{} as {}.", joined, existing.username))
+            },
+            _ => Ok(()),
+        }
+    }
+    
+    /// The participant called `username`, and the channel they are in
+    fn participant(&self, username: &str) -> Option<(&str, &Participant)> {
+        self.channels.iter()
+            .find_map(|(name, channel)| Some((name.as_str(), channel.participants.get(username)?)))
+    }
+    
+    /// The participant who joined with `sender_tag`, and the channel they are in
+    fn member(&self, sender_tag: AnonymousSenderTag) -> Option<(&str, &Participant)> {
+        self.channels.iter().find_map(|(name, channel)| {
+            let participant = channel.participants.values().find(|p| p.sender_tag == sender_tag)?;
+            Some((name.as_str(), participant))
+        })
+    }
+    
+    /// Like `member`, noting that they are still active
+    fn touch_member(&mut self, sender_tag: AnonymousSenderTag) -> Option<(String, &mut Participant)> {
+        let now = self.clock.now();
+        self.channels.iter_mut().find_map(|(name, channel)| {
+            let participant = channel.participants.values_mut().find(|p| p.sender_tag == sender_tag)?;
+            participant.last_active = now;
+            Some((name.clone(), participant))
+        })
+    }
+    
+    fn participant_count(&self) -> usize {
+        self.channels.values().map(|channel| channel.participants.len()).sum()
+    }
+    
+    /// Check a joining sender's answer to our passphrase challenge. A failed
+    /// attempt uses up the challenge, so every guess costs a new handshake.
+    fn check_access(&mut self, username: &str, sender_tag: AnonymousSenderTag, proof: Option<&[u8]>) -> Result<(), String> {
+        let access_key = match &self.access_key {
+            Some(access_key) => access_key,
+            None => return Ok(()),
+        };
+        
+        // Members repeating their own join were let in already
+        if self.participant(username).is_some_and(|(_, p)| p.sender_tag == sender_tag) {
+            return Ok(());
+        }
+        
+        let challenge = self.pending_hellos.get(&sender_tag).and_then(|hello| hello.challenge.as_deref());
+        let granted = match (challenge, proof) {
+            (Some(challenge), Some(proof)) => access_key.verify(challenge, username, proof),
+            _ => false,
+        };
+        if granted {
+            return Ok(());
+        }
+        
+        self.pending_hellos.remove(&sender_tag);
+        match proof {
+            Some(_) => Err("Wrong room passphrase.".to_string()),
+            None => Err("This room requires a passphrase.".to_string()),
+        }
+    }
+    
+    /// Keep a handshake until its join, forgetting the oldest one if there are
+    /// too many, so a stream of hellos can't grow the room without limit
+    fn add_pending_hello(&mut self, sender_tag: AnonymousSenderTag, hello: PendingHello) {
+        if !self.pending_hellos.contains_key(&sender_tag) && self.pending_hellos.len() >= MAX_PENDING_HELLOS {
+            let oldest = self.pending_hellos.iter()
+                .min_by_key(|(_, pending)| pending.received)
+                .map(|(tag, _)| *tag);
+            if let Some(oldest) = oldest {
+                self.pending_hellos.remove(&oldest);
+            }
+        }
+        
+        self.pending_hellos.insert(sender_tag, hello);
+    }
+    
+    fn participant_by_tag_mut(&mut self, sender_tag: AnonymousSenderTag) -> Option<&mut Participant> {
+        self.channels.values_mut()
+            .flat_map(|channel| channel.participants.values_mut())
+            .find(|p| p.sender_tag == sender_tag)
+    }
+    
+    /// Count reply SURBs that arrived with a message from `sender_tag`
+    fn credit_surbs(&mut self, sender_tag: AnonymousSenderTag, surbs: u32) {
+        if let Some(participant) = self.participant_by_tag_mut(sender_tag) {
+            participant.surb_balance = participant.surb_balance.saturating_add(surbs);
+            if participant.surb_balance >= SURB_LOW_WATERMARK {
+                participant.surbs_requested = false;
+            }
+        } else if let Some(hello) = self.pending_hellos.get_mut(&sender_tag) {
+            hello.surb_balance = hello.surb_balance.saturating_add(surbs);
+        }
+    }
+    
+    /// Count one reply SURB spent on `sender_tag`. Returns the remaining balance
+    /// and wire format when the participant should be asked to top up.
+    fn spend_surb(&mut self, sender_tag: AnonymousSenderTag) -> Option<(u32, WireFormat)> {
+        if let Some(hello) = self.pending_hellos.get_mut(&sender_tag) {
+            hello.surb_balance = hello.surb_balance.saturating_sub(1);
+            return None;
+        }
+        
+        let participant = self.participant_by_tag_mut(sender_tag)?;
+        participant.surb_balance = participant.surb_balance.saturating_sub(1);
+        
+        let can_top_up = participant.capabilities.contains(&Capability::SurbTopUp);
+        if can_top_up && !participant.surbs_requested && participant.surb_balance < SURB_LOW_WATERMARK {
+            participant.surbs_requested = true;
+            Some((participant.surb_balance, participant.format))
+        } else {
+            None
+        }
+    }
+    
+    /// Remove participants who have gone quiet, returning their channels and names
+    fn prune_inactive_participants(&mut self) -> Vec<(String, String)> {
+        let now = self.clock.now();
         let timeout_duration = Duration::from_secs(PARTICIPANT_TIMEOUT_SECS);
         
         let mut pruned = Vec::new();
         
-        self.participants.retain(|username, participant| {
-            let is_active = match now.duration_since(participant.last_active) {
-                Ok(duration) => duration < timeout_duration,
-                Err(_) => true, // Keep if time calculation fails
-            };
-            
-            if !is_active {
-                pruned.push(username.clone());
+        for (name, channel) in self.channels.iter_mut() {
+            channel.participants.retain(|username, participant| {
+                let is_active = match now.duration_since(participant.last_active) {
+                    Ok(duration) => duration < timeout_duration,
+                    Err(_) => true, // Keep if time calculation fails
+                };
+                
+                if !is_active {
+                    pruned.push((name.clone(), username.clone()));
+                }
+                
+                is_active
+            });
+        }
+        
+        // Forget chunked state syncs too old to be asked for again
+        let resend_window = Duration::from_secs(SYNC_RESEND_WINDOW_SECS);
+        for participant in self.channels.values_mut().flat_map(|channel| channel.participants.values_mut()) {
+            let expired = participant.sync.as_ref()
+                .is_some_and(|sync| now.duration_since(sync.sent).is_ok_and(|age| age >= resend_window));
+            if expired {
+                participant.sync = None;
             }
-            
-            is_active
+        }
+        
+        // Forget handshakes that never turned into a join, long before members
+        let hello_timeout = Duration::from_secs(PENDING_HELLO_TIMEOUT_SECS);
+        self.pending_hellos.retain(|_, hello| match now.duration_since(hello.received) {
+            Ok(duration) => duration < hello_timeout,
+            Err(_) => true,
         });
         
         pruned
     }
+    
+    /// Drop history past the retention limits in every channel
+    fn expire_history(&mut self) -> anyhow::Result<()> {
+        let now = self.clock.now();
+        for channel in self.channels.values_mut() {
+            channel.history.expire(now)?;
+        }
+        Ok(())
+    }
+}
+
+/// Split history into runs of at most `max_bytes`, oldest first. An item
+/// larger than that gets a chunk of its own.
+fn chunk_history(history: Vec<HistoryItem>, max_bytes: usize) -> Vec<Vec<HistoryItem>> {
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    let mut chunk_bytes = 0;
+    
+    for item in history {
+        // JSON is the most verbose wire format, so this errs on the small side
+        let item_bytes = serde_json::to_vec(&item).map_or(0, |bytes| bytes.len());
+        if !chunk.is_empty() && chunk_bytes + item_bytes > max_bytes {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_bytes = 0;
+        }
+        chunk_bytes += item_bytes;
+        chunk.push(item);
+    }
+    
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Reject channel names that are empty, overlong or unfit for a file name
+fn validate_channel_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_USERNAME_LEN {
+        Err(format!("Channel names must be 1 to {} characters.", MAX_USERNAME_LEN))
+    } else if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        Err(format!("Channel name {} may only contain letters, digits, '-' and '_'.", name))
+    } else {
+        Ok(())
+    }
+}
+
+/// Reject usernames that are empty, overlong or hard to tell apart
+fn validate_username(username: &str) -> Result<(), String> {
+    if username.is_empty() {
+        Err("Usernames cannot be empty.".to_string())
+    } else if username.chars().count() > MAX_USERNAME_LEN {
+        Err(format!("Usernames can be at most {} characters.", MAX_USERNAME_LEN))
+    } else if username.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Err("Usernames cannot contain spaces or control characters.".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Pick the protocol version to speak with a client, or explain why we can't
+fn negotiate_version(client_version: u32, client_min_version: u32) -> Result<u32, String> {
+    let version = client_version.min(PROTOCOL_VERSION);
+    
+    if version < MIN_PROTOCOL_VERSION {
+        Err(format!(
+            "This room requires protocol v{} or newer but your client speaks v{}. Please upgrade nymcat.",
+            MIN_PROTOCOL_VERSION, client_version
+        ))
+    } else if version < client_min_version {
+        Err(format!(
+            "Your client requires protocol v{} or newer but this room only speaks up to v{}.",
+            client_min_version, PROTOCOL_VERSION
+        ))
+    } else {
+        Ok(version)
+    }
 }
 
-pub async fn run_room_server(verbosity: LogLevel, env_file: Option<String>) -> anyhow::Result<()> {
+pub async fn run_room_server(
+    config: RoomConfig,
+    data_dir: Option<PathBuf>,
+    verbosity: LogLevel,
+    env_file: Option<String>,
+) -> anyhow::Result<()> {
     // Set environment if provided
     if let Some(path) = &env_file {
         std::env::set_var("NYM_ENV_FILE", path);
     }
     
-    // Create a mixnet client
-    let mut client = MixnetClient::connect_new().await?;
-    let _room_address = *client.nym_address();
+    // Create a mixnet client, keeping its address if we have somewhere to store its keys
+    let client = transport::connect_mixnet(data_dir.as_deref()).await?;
+    if let Some(data_dir) = &data_dir {
+        log(LogLevel::Info, verbosity, &format!("Mixnet keys kept in {}", data_dir.display()));
+    }
+    
+    serve_room(client, config, verbosity).await
+}
+
+/// Run the room server over any transport
+pub async fn serve_room<T: Transport>(mut client: T, config: RoomConfig, verbosity: LogLevel) -> anyhow::Result<()> {
     let room_address_str = client.nym_address().to_string();
     
     // Create message queue
-    let (tx, mut rx) = mpsc::channel::<QueuedMessage>(MAX_QUEUE_SIZE);
+    let queue = Arc::new(SendQueue::<QueuedMessage>::new(MAX_QUEUE_SIZE));
     
     // Print fancy banner
-    print_welcome_banner(&room_address_str);
+    print_welcome_banner(&room_address_str, config.history.path.is_some());
     
     log(LogLevel::Info, verbosity, &format!("Room address: {}", room_address_str));
     
     // Create shared state
-    let state = Arc::new(Mutex::new(RoomState::new()));
+    let access_key = config.passphrase
+        .map(|passphrase| AccessKey::from_passphrase(&passphrase, &client.nym_address()))
+        .transpose()?;
+    if access_key.is_some() {
+        println!("{}This room requires a passphrase to join.{}\n", Colors::BRIGHT_YELLOW, Colors::RESET);
+    }
+    if let Some(path) = &config.history.path {
+        println!("{}History is kept in {}{}\n", Colors::BRIGHT_YELLOW, path.display(), Colors::RESET);
+    }
+    
+    // Each channel keeps its own history
+    if config.channels.is_empty() {
+        return Err(anyhow::anyhow!("A room needs at least one channel"));
+    }
+    let mut channels = Vec::new();
+    for name in &config.channels {
+        validate_channel_name(name).map_err(|e| anyhow::anyhow!(e))?;
+        if channels.iter().any(|(existing, _)| existing == name) {
+            return Err(anyhow::anyhow!("Channel {} is listed twice", name));
+        }
+        
+        channels.push((name.clone(), History::open(config.history.for_channel(name), config.clock.now())?));
+    }
+    if config.channels.len() > 1 {
+        let names: Vec<String> = config.channels.iter().map(|name| format!("#{}", name)).collect();
+        println!("{}Channels: {}{}\n", Colors::BRIGHT_YELLOW, names.join(", "), Colors::RESET);
+    }
+    let state = Arc::new(Mutex::new(RoomState::new(config.clock, access_key, channels)));
     
     // Clone for pruning task
     let prune_state = Arc::clone(&state);
     let prune_verbosity = verbosity;
-    let prune_tx = tx.clone();
+    let prune_queue = Arc::clone(&queue);
+    let prune_interval = config.prune_interval;
     
     // Start periodic pruning task
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        let mut interval = tokio::time::interval(prune_interval);
         
         loop {
             interval.tick().await;
             
-            let pruned = {
+            let (pruned, expired) = {
                 let mut state_lock = prune_state.lock().unwrap();
-                state_lock.prune_inactive_participants()
+                (state_lock.prune_inactive_participants(), state_lock.expire_history())
             };
             
+            if let Err(e) = expired {
+                log(LogLevel::Info, prune_verbosity, &format!("Failed to expire history: {}", e));
+            }
+            
             if !pruned.is_empty() {
                 log(LogLevel::Info, prune_verbosity, 
                     &format!("Pruned {} inactive participants", pruned.len()));
                 
                 // Send leave messages for pruned participants
-                for username in pruned {
+                for (channel, username) in pruned {
                     let leave_msg = ChatMessage::Leave { username: username.clone() };
                     
-                    if let Ok(leave_bytes) = serde_json::to_vec(&leave_msg) {
-                        // Broadcast to all remaining participants
-                        let recipients = {
-                            let state_lock = prune_state.lock().unwrap();
-                            state_lock.participants.values()
-                                .map(|p| p.sender_tag)
-                                .collect::<Vec<_>>()
-                        };
-                        
-                        for recipient in recipients {
-                            if let Err(e) = prune_tx.send(QueuedMessage {
-                                message: leave_bytes.clone(),
-                                recipient,
-                                priority: MessagePriority::High,
-                                timestamp: Instant::now(),
-                            }).await {
-                                log(LogLevel::Debug, prune_verbosity, 
-                                    &format!("Failed to queue leave message: {}", e));
-                            }
-                        }
-                    }
+                    broadcast_to_participants(
+                        &leave_msg,
+                        &prune_state,
+                        &prune_queue,
+                        &channel,
+                        &username,
+                        MessagePriority::High,
+                        prune_verbosity
+                    );
                 }
             }
         }
@@ -174,13 +680,16 @@ pub async fn run_room_server(verbosity: LogLevel, env_file: Option<String>) -> a
     let sender = client.split_sender();
     let state_clone = Arc::clone(&state);
     let sender_clone = sender.clone();
+    let processor_queue = Arc::clone(&queue);
     let processor_verbosity = verbosity;
     
     // Start message processing task
     tokio::spawn(async move {
         log(LogLevel::Debug, processor_verbosity, "Starting message processor");
         
-        while let Some(msg) = rx.recv().await {
+        loop {
+            let (priority, msg) = processor_queue.pop().await;
+            
             // Skip if message is too old (more than 30 seconds)
             if msg.timestamp.elapsed() > Duration::from_secs(30) {
                 log(LogLevel::Debug, processor_verbosity, 
@@ -189,8 +698,8 @@ pub async fn run_room_server(verbosity: LogLevel, env_file: Option<String>) -> a
             }
             
             log(LogLevel::Trace, processor_verbosity, &format!(
-                "Processing queued message of {} bytes to recipient", 
-                msg.message.len()));
+                "Processing {:?} priority message of {} bytes to recipient", 
+                priority, msg.message.len()));
             
             // Send the message
             if let Err(e) = sender_clone.send_reply(msg.recipient, &msg.message).await {
@@ -198,10 +707,20 @@ pub async fn run_room_server(verbosity: LogLevel, env_file: Option<String>) -> a
                     "Failed to send message: {}", e));
             }
             
-            // Update broadcast counter
-            {
+            // Update broadcast counter and the recipient's SURB estimate
+            let low_balance = {
                 let mut state_lock = state_clone.lock().unwrap();
                 state_lock.broadcast_count += 1;
+                state_lock.spend_surb(msg.recipient)
+            };
+            
+            // Ask for more SURBs before the recipient stops hearing from us
+            if let Some((remaining, format)) = low_balance {
+                log(LogLevel::Debug, processor_verbosity, &format!(
+                    "Requesting SURB top-up, {} left for recipient", remaining));
+                if let Ok(request_bytes) = format.encode(&ChatMessage::SurbRequest { remaining }) {
+                    queue_message(&processor_queue, msg.recipient, request_bytes, MessagePriority::High);
+                }
             }
             
             // Small delay to prevent flooding
@@ -211,6 +730,7 @@ pub async fn run_room_server(verbosity: LogLevel, env_file: Option<String>) -> a
     
     // Clone for stats task
     let stats_state = Arc::clone(&state);
+    let stats_queue = Arc::clone(&queue);
     let stats_verbosity = verbosity;
     
     // Start periodic statistics reporting
@@ -223,16 +743,18 @@ pub async fn run_room_server(verbosity: LogLevel, env_file: Option<String>) -> a
             let stats = {
                 let state_lock = stats_state.lock().unwrap();
                 (
-                    state_lock.participants.len(),
+                    state_lock.participant_count(),
                     state_lock.message_count,
                     state_lock.broadcast_count,
-                    state_lock.start_time
+                    state_lock.start_time,
+                    state_lock.clock.now()
                 )
             };
             
-            let (participants, messages, broadcasts, start_time) = stats;
+            let (participants, messages, broadcasts, start_time, now) = stats;
+            let depths = stats_queue.depths();
             
-            let uptime = SystemTime::now().duration_since(start_time)
+            let uptime = now.duration_since(start_time)
                 .map(|d| d.as_secs())
                 .unwrap_or(0);
             
@@ -240,15 +762,15 @@ pub async fn run_room_server(verbosity: LogLevel, env_file: Option<String>) -> a
             let minutes = (uptime % 3600) / 60;
             
             log(LogLevel::Info, stats_verbosity, &format!(
-                "Stats: {} participants, {} messages, {} broadcasts, uptime: {}h {}m",
-                participants, messages, broadcasts, hours, minutes
+                "Stats: {} participants, {} messages, {} broadcasts, queued high/medium/low: {}/{}/{}, uptime: {}h {}m",
+                participants, messages, broadcasts, depths.high, depths.medium, depths.low, hours, minutes
             ));
         }
     });
     
     // Clone for message handler
     let state_clone = Arc::clone(&state);
-    let msg_tx = tx.clone();
+    let msg_queue = Arc::clone(&queue);
     let msg_verbosity = verbosity;
     
     // Handle messages
@@ -256,9 +778,9 @@ pub async fn run_room_server(verbosity: LogLevel, env_file: Option<String>) -> a
         log(LogLevel::Trace, msg_verbosity, &format!(
             "Received raw message: {} bytes", msg.message.len()));
         
-        // Try to parse the message
-        let message: ChatMessage = match serde_json::from_slice(&msg.message) {
-            Ok(m) => m,
+        // Try to parse the message in whichever format the sender used
+        let (envelope, format) = match codec::decode(&msg.message) {
+            Ok(decoded) => decoded,
             Err(e) => {
                 log(LogLevel::Debug, msg_verbosity, &format!("Failed to parse message: {}", e));
                 return;
@@ -273,126 +795,593 @@ pub async fn run_room_server(verbosity: LogLevel, env_file: Option<String>) -> a
             }
         };
         
-        match &message {
-            ChatMessage::Join { username } => {
-                println!("{}User joined:{} {}", Colors::GREEN, Colors::RESET, username);
-                log(LogLevel::Info, msg_verbosity, &format!(
-                    "User joined: {} with sender tag", username));
+        // Signed messages are handled by what they carry, but relayed as they came
+        // so their signatures still verify
+        let message = envelope.payload();
+        
+        // Every client message carries reply SURBs we can spend on the sender
+        let attached_surbs = match message {
+            ChatMessage::SurbTopUp { surbs } => *surbs,
+            _ => SURBS_PER_MESSAGE,
+        };
+        state_clone.lock().unwrap().credit_surbs(sender_tag, attached_surbs);
+        
+        match message {
+            ChatMessage::Hello { version, min_version, capabilities } => {
+                log(LogLevel::Debug, msg_verbosity, &format!(
+                    "Hello: protocol v{} (min v{}), capabilities {:?}", version, min_version, capabilities));
                 
-                // Store participant with last active time
-                {
+                let reply = match negotiate_version(*version, *min_version) {
+                    Ok(negotiated) => {
+                        let mut state_lock = state_clone.lock().unwrap();
+                        let now = state_lock.clock.now();
+                        let challenge = state_lock.access_key.as_ref().map(|_| crypto::generate_challenge());
+                        state_lock.add_pending_hello(sender_tag, PendingHello {
+                            version: negotiated,
+                            capabilities: capabilities.clone(),
+                            received: now,
+                            surb_balance: SURBS_PER_MESSAGE,
+                            challenge: challenge.clone(),
+                        });
+                        
+                        ChatMessage::Welcome {
+                            version: negotiated,
+                            capabilities: SERVER_CAPABILITIES.to_vec(),
+                            challenge,
+                            default_channel: Some(state_lock.default_channel.clone()),
+                        }
+                    },
+                    Err(reason) => {
+                        log(LogLevel::Info, msg_verbosity, &format!("Rejected hello: {}", reason));
+                        ChatMessage::HelloRejected {
+                            server_version: PROTOCOL_VERSION,
+                            reason,
+                        }
+                    }
+                };
+                
+                if let Ok(reply_bytes) = format.encode(&reply) {
+                    queue_message(&msg_queue, sender_tag, reply_bytes, MessagePriority::Medium);
+                }
+            },
+            ChatMessage::Join { username, key_package, identity_key, last_seen, channel, .. } => {
+                // Store participant with last active time, unless they lack the
+                // passphrase or the name or channel is unavailable
+                let accepted = {
                     let mut state_lock = state_clone.lock().unwrap();
-                    state_lock.participants.insert(username.clone(), Participant {
-                        username: username.clone(),
-                        sender_tag,
-                        last_active: SystemTime::now(),
-                    });
+                    let now = state_lock.clock.now();
+                    let channel = channel.clone().unwrap_or_else(|| state_lock.default_channel.clone());
                     
-                    state_lock.message_count += 1;
-                }
+                    let accepted = match state_lock.check_access(username, sender_tag, envelope.join_proof()) {
+                        Ok(()) => state_lock.check_join(username, &channel, sender_tag)
+                            .map(|()| channel)
+                            .map_err(|reason| ChatMessage::JoinRejected { reason }),
+                        Err(reason) => Err(ChatMessage::AccessDenied { reason }),
+                    };
+                    if let Ok(channel) = &accepted {
+                        // Clients that skipped the handshake speak the original protocol
+                        let (version, capabilities, surb_balance) = match state_lock.pending_hellos.remove(&sender_tag) {
+                            Some(hello) => (hello.version, hello.capabilities, hello.surb_balance),
+                            None => (1, Vec::new(), SURBS_PER_MESSAGE),
+                        };
+                        
+                        let participant = Participant {
+                            username: username.clone(),
+                            sender_tag,
+                            last_active: now,
+                            format,
+                            version,
+                            capabilities,
+                            surb_balance,
+                            surbs_requested: false,
+                            key_package: key_package.clone(),
+                            identity_key: identity_key.clone(),
+                            sync: None,
+                        };
+                        if let Some(channel) = state_lock.channels.get_mut(channel) {
+                            channel.participants.insert(username.clone(), participant);
+                        }
+                        
+                        state_lock.message_count += 1;
+                    }
+                    accepted
+                };
+                
+                let channel = match accepted {
+                    Ok(channel) => channel,
+                    Err(rejection) => {
+                        log(LogLevel::Info, msg_verbosity, &format!("Rejected join as {}: {:?}", username, rejection));
+                        if let Ok(reject_bytes) = format.encode(&rejection) {
+                            queue_message(&msg_queue, sender_tag, reject_bytes, MessagePriority::Medium);
+                        }
+                        return;
+                    }
+                };
+                
+                println!("{}User joined:{} {} in #{}", Colors::GREEN, Colors::RESET, username, channel);
+                log(LogLevel::Info, msg_verbosity, &format!(
+                    "User joined: {} in #{} with sender tag", username, channel));
                 
-                // Send state sync to new user
+                // Send state sync to new user, covering only their channel
                 let state_data = {
                     let state_lock = state_clone.lock().unwrap();
+                    let joined = &state_lock.channels[&channel];
                     (
-                        Vec::from(state_lock.history.clone()),
-                        state_lock.participants.values().map(|p| p.username.clone()).collect::<Vec<_>>()
+                        joined.participants.get(username)
+                            .is_some_and(|p| p.capabilities.contains(&Capability::ChunkedSync)),
+                        // Only what the client hasn't seen, if we still have what it saw
+                        // last, and no more than a page of it
+                        match last_seen.and_then(|cursor| joined.history.after(cursor)) {
+                            Some(mut newer) => {
+                                let more = newer.len() > HISTORY_PAGE_SIZE as usize;
+                                newer.drain(..newer.len().saturating_sub(HISTORY_PAGE_SIZE as usize));
+                                (newer, last_seen.map(|cursor| cursor.seq), more)
+                            },
+                            None => {
+                                let (latest, more) = joined.history.page(None, HISTORY_PAGE_SIZE as usize);
+                                (latest, None, more)
+                            },
+                        },
+                        joined.participants.values().map(|p| p.username.clone()).collect::<Vec<_>>(),
+                        joined.participants.values()
+                            .filter_map(|p| Some(KeyPackage {
+                                username: p.username.clone(),
+                                public_key: p.key_package.clone()?,
+                            }))
+                            .collect::<Vec<_>>(),
+                        joined.participants.values()
+                            .filter_map(|p| Some(IdentityKey {
+                                username: p.username.clone(),
+                                public_key: p.identity_key.clone()?,
+                            }))
+                            .collect::<Vec<_>>(),
+                        joined.history.epoch(),
                     )
                 };
                 
-                let (history, participants) = state_data;
-                let sync_msg = ChatMessage::StateSync {
-                    history,
-                    participants,
-                };
+                let (chunks_supported, (history, since, more), participants, key_packages, identity_keys, history_epoch) = state_data;
+                let history_chunks = chunk_history(history, SYNC_CHUNK_BYTES);
                 
-                if let Ok(sync_bytes) = serde_json::to_vec(&sync_msg) {
-                    let tx_clone = msg_tx.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = tx_clone.send(QueuedMessage {
-                            message: sync_bytes,
-                            recipient: sender_tag,
-                            priority: MessagePriority::Medium,
-                            timestamp: Instant::now(),
-                        }).await {
-                            eprintln!("Failed to queue sync: {}", e);
-                        }
+                if chunks_supported && history_chunks.len() > 1 {
+                    // Too much for one message: send the history in parts, then
+                    // a state sync that says how many to expect
+                    let chunked = SyncChunks {
+                        sync_id: rand::random(),
+                        chunks: history_chunks.len() as u32,
+                    };
+                    let chunks: Vec<Vec<u8>> = history_chunks.into_iter()
+                        .enumerate()
+                        .filter_map(|(index, history)| format.encode(&ChatMessage::StateSyncChunk {
+                            sync_id: chunked.sync_id,
+                            index: index as u32,
+                            chunks: chunked.chunks,
+                            history,
+                        }).ok())
+                        .collect();
+                    let complete = format.encode(&ChatMessage::StateSync {
+                        history: Vec::new(),
+                        participants,
+                        key_packages,
+                        identity_keys,
+                        chunked: Some(chunked),
+                        since,
+                        channel: Some(channel.clone()),
+                        more,
+                        history_epoch: Some(history_epoch),
                     });
+                    
+                    if let Ok(complete) = complete {
+                        log(LogLevel::Debug, msg_verbosity, &format!("Sending state sync in {} chunks", chunks.len()));
+                        for chunk in &chunks {
+                            queue_message(&msg_queue, sender_tag, chunk.clone(), MessagePriority::Medium);
+                        }
+                        queue_message(&msg_queue, sender_tag, complete.clone(), MessagePriority::Medium);
+                        
+                        let mut state_lock = state_clone.lock().unwrap();
+                        let now = state_lock.clock.now();
+                        let joined = state_lock.channels.get_mut(&channel)
+                            .and_then(|joined| joined.participants.get_mut(username));
+                        if let Some(participant) = joined {
+                            participant.sync = Some(SentSync {
+                                sync_id: chunked.sync_id,
+                                chunks,
+                                complete,
+                                sent: now,
+                            });
+                        }
+                    }
+                } else {
+                    let sync_msg = ChatMessage::StateSync {
+                        history: history_chunks.concat(),
+                        participants,
+                        key_packages,
+                        identity_keys,
+                        chunked: None,
+                        since,
+                        channel: Some(channel.clone()),
+                        more,
+                        history_epoch: Some(history_epoch),
+                    };
+                    
+                    if let Ok(sync_bytes) = format.encode(&sync_msg) {
+                        queue_message(&msg_queue, sender_tag, sync_bytes, MessagePriority::Medium);
+                    }
                 }
                 
-                // Broadcast join to others
-                if let Ok(join_bytes) = serde_json::to_vec(&message) {
-                    broadcast_to_participants(
-                        &join_bytes, 
-                        &state_clone, 
-                        &msg_tx, 
-                        username, 
-                        MessagePriority::High,
-                        msg_verbosity
-                    );
-                }
+                // Broadcast join to others, without the proof that was only for us
+                broadcast_to_participants(
+                    &envelope.without_proof(), 
+                    &state_clone, 
+                    &msg_queue, 
+                    &channel,
+                    username, 
+                    MessagePriority::High,
+                    msg_verbosity
+                );
             },
             ChatMessage::Leave { username } => {
-                println!("{}User left:{} {}", Colors::YELLOW, Colors::RESET, username);
-                log(LogLevel::Info, msg_verbosity, &format!("User left: {}", username));
+                // Only the owner of a name may take it out of the room
+                let owned = {
+                    let state_lock = state_clone.lock().unwrap();
+                    state_lock.participant(username)
+                        .filter(|(_, participant)| participant.sender_tag == sender_tag)
+                        .map(|(channel, _)| channel.to_string())
+                };
+                
+                let channel = match owned {
+                    Some(channel) => channel,
+                    None => {
+                        log(LogLevel::Debug, msg_verbosity, &format!(
+                            "Rejected leave for {}: sender does not own that name", username));
+                        return;
+                    }
+                };
+                
+                println!("{}User left:{} {} from #{}", Colors::YELLOW, Colors::RESET, username, channel);
+                log(LogLevel::Info, msg_verbosity, &format!("User left: {} from #{}", username, channel));
                 
                 // Remove participant
                 {
                     let mut state_lock = state_clone.lock().unwrap();
-                    state_lock.participants.remove(username);
+                    if let Some(joined) = state_lock.channels.get_mut(&channel) {
+                        joined.participants.remove(username);
+                    }
                     state_lock.message_count += 1;
                 }
                 
                 // Broadcast leave to others
-                if let Ok(leave_bytes) = serde_json::to_vec(&message) {
-                    broadcast_to_participants(
-                        &leave_bytes, 
-                        &state_clone, 
-                        &msg_tx, 
-                        username, 
-                        MessagePriority::High,
-                        msg_verbosity
-                    );
-                }
+                broadcast_to_participants(
+                    &envelope, 
+                    &state_clone, 
+                    &msg_queue, 
+                    &channel,
+                    username, 
+                    MessagePriority::High,
+                    msg_verbosity
+                );
             },
-            ChatMessage::Text { from, content, timestamp } => {
-                println!("{}: {}", from, content);
-                log(LogLevel::Info, msg_verbosity, &format!("Message from {}: {}", from, content));
-                
-                // Update last active time
-                {
+            ChatMessage::Text { from: claimed_from, content, timestamp, id, sealed, .. } => {
+                // Attribute the message to whoever joined with this sender tag,
+                // update their last active time, and drop retransmissions we already accepted
+                let (owner, is_new) = {
                     let mut state_lock = state_clone.lock().unwrap();
-                    if let Some(participant) = state_lock.participants.get_mut(from) {
-                        participant.last_active = SystemTime::now();
+                    let owner = state_lock.touch_member(sender_tag).map(|(channel, participant)| {
+                        (participant.username.clone(), participant.capabilities.contains(&Capability::DeliveryAcks), channel)
+                    });
+                    let is_new = match (&owner, id) {
+                        (Some(_), Some(id)) => state_lock.recent_messages.insert(sender_tag, *id),
+                        _ => true,
+                    };
+                    (owner, is_new)
+                };
+                
+                let (from, wants_ack, channel) = match owner {
+                    Some(owner) => owner,
+                    None => {
+                        log(LogLevel::Debug, msg_verbosity, &format!(
+                            "Rejected message claiming to be from {}: sender has not joined", claimed_from));
+                        return;
+                    }
+                };
+                
+                if &from != claimed_from {
+                    log(LogLevel::Debug, msg_verbosity, &format!(
+                        "Rewrote message claiming to be from {} to its owner {}", claimed_from, from));
+                }
+                
+                // Confirm delivery to the sender, with where the message went in history
+                let ack = |seq: Option<u64>| {
+                    if let (Some(id), true) = (id, wants_ack) {
+                        if let Ok(ack_bytes) = format.encode(&ChatMessage::Ack { id: *id, seq }) {
+                            queue_message(&msg_queue, sender_tag, ack_bytes, MessagePriority::Medium);
+                        }
                     }
+                };
+                
+                if !is_new {
+                    log(LogLevel::Debug, msg_verbosity, &format!("Dropping duplicate message from {}", from));
+                    
+                    // Confirm again, in case our first ack was lost
+                    let seq = {
+                        let state_lock = state_clone.lock().unwrap();
+                        id.and_then(|id| state_lock.channels.get(&channel)?.history.seq_of(&from, id))
+                    };
+                    ack(seq);
+                    return;
                 }
                 
+                // End-to-end encrypted content is opaque to the room
+                let shown = if sealed.is_some() { "<encrypted>" } else { content.as_str() };
+                println!("{}: {}", from, shown);
+                log(LogLevel::Info, msg_verbosity, &format!("Message from {}: {}", from, shown));
+                
+                // A message we had to re-attribute loses its signature, which no longer fits
+                let relayed = if &from == claimed_from {
+                    envelope.clone()
+                } else {
+                    ChatMessage::Text {
+                        from: from.clone(),
+                        content: content.clone(),
+                        timestamp: *timestamp,
+                        id: *id,
+                        sealed: sealed.clone(),
+                        seq: None,
+                    }
+                };
+                
                 // Store in history
-                {
+                let seq = {
                     let mut state_lock = state_clone.lock().unwrap();
-                    let history_item = HistoryItem {
+                    let mut history_item = HistoryItem {
                         from: from.clone(),
                         content: content.clone(),
                         timestamp: *timestamp,
+                        sealed: sealed.clone(),
+                        seq: None, // Numbered by the history store
+                        id: *id,
+                        signature: None,
+                        identity_key: None,
                     };
-                    state_lock.add_history_item(history_item);
-                    state_lock.message_count += 1;
+                    
+                    // Keep just the signature, if it covers exactly what the item holds,
+                    // so history doesn't carry every message twice
+                    if let ChatMessage::Signed { message, signature, .. } = &relayed {
+                        let identity_key = state_lock.participant(&from).and_then(|(_, p)| p.identity_key.clone());
+                        let covers_item = history_item.signed_payload()
+                            .is_ok_and(|payload| payload.bytes() == message.bytes());
+                        if let (Some(identity_key), true) = (identity_key, covers_item) {
+                            history_item.signature = Some(signature.clone());
+                            history_item.identity_key = Some(identity_key);
+                        }
+                    }
+                    let seq = state_lock.add_history_item(&channel, history_item).unwrap_or_else(|e| {
+                        log(LogLevel::Info, msg_verbosity, &format!("Failed to save history: {}", e));
+                        None
+                    });
+                    state_lock.message_count += 1;
+                    seq
+                };
+                ack(seq);
+                
+                // Broadcast message to others, numbered so they can tell the room where they left off
+                let relayed = match seq {
+                    Some(seq) => relayed.with_seq(seq),
+                    None => relayed,
+                };
+                broadcast_to_participants(
+                    &relayed, 
+                    &state_clone, 
+                    &msg_queue, 
+                    &channel,
+                    &from, 
+                    MessagePriority::Low,
+                    msg_verbosity
+                );
+            },
+            ChatMessage::DirectMessage { from: claimed_from, to, content, sealed } => {
+                // Attribute the message like a `Text`, then hand it to the one
+                // participant it is for, wherever they are in the room
+                let route = {
+                    let mut state_lock = state_clone.lock().unwrap();
+                    let from = state_lock.touch_member(sender_tag).map(|(_, participant)| participant.username.clone());
+                    let recipient = state_lock.participant(to).map(|(_, participant)| (
+                        participant.sender_tag,
+                        participant.format,
+                        participant.capabilities.contains(&Capability::Signatures),
+                        participant.format != WireFormat::LegacyJson
+                            && participant.capabilities.contains(&Capability::DirectMessages),
+                    ));
+                    if from.is_some() {
+                        state_lock.message_count += 1;
+                    }
+                    from.map(|from| (from, recipient))
+                };
+                
+                let (from, recipient) = match route {
+                    Some(route) => route,
+                    None => {
+                        log(LogLevel::Debug, msg_verbosity, &format!(
+                            "Rejected direct message claiming to be from {}: sender has not joined", claimed_from));
+                        return;
+                    }
+                };
+                
+                // Tell the sender if it can't be delivered, rather than handing it
+                // to a client that would show it as if it were said to everyone
+                let delivery = match recipient {
+                    Some((recipient, recipient_format, signatures, true)) => Ok((recipient, recipient_format, signatures)),
+                    Some(_) => Err(format!("{}'s client can't receive private messages.", to)),
+                    None => Err(format!("{} is not in this room.", to)),
+                };
+                let (recipient, recipient_format, signatures) = match delivery {
+                    Ok(route) => route,
+                    Err(reason) => {
+                        log(LogLevel::Debug, msg_verbosity, &format!("Direct message from {} for {} not delivered: {}", from, to, reason));
+                        let failed = ChatMessage::DirectMessageFailed {
+                            to: to.clone(),
+                            reason,
+                        };
+                        if let Ok(failed_bytes) = format.encode(&failed) {
+                            queue_message(&msg_queue, sender_tag, failed_bytes, MessagePriority::Medium);
+                        }
+                        return;
+                    }
+                };
+                
+                // Private, so the content is neither shown here nor kept in history
+                log(LogLevel::Debug, msg_verbosity, &format!("Relaying direct message from {} to {}", from, to));
+                let relayed = if &from == claimed_from {
+                    envelope.clone()
+                } else {
+                    ChatMessage::DirectMessage {
+                        from,
+                        to: to.clone(),
+                        content: content.clone(),
+                        sealed: sealed.clone(),
+                    }
+                };
+                
+                if let Ok(relayed_bytes) = recipient_format.encode(&relayable(&relayed, signatures)) {
+                    queue_message(&msg_queue, recipient, relayed_bytes, MessagePriority::Low);
                 }
+            },
+            ChatMessage::GroupCommit { epoch, committer: member, .. }
+            | ChatMessage::GroupKeyRequest { epoch, member } => {
+                // Relay key updates untouched, but only under the sender's own name
+                let owned = {
+                    let mut state_lock = state_clone.lock().unwrap();
+                    state_lock.touch_member(sender_tag)
+                        .filter(|(_, participant)| &participant.username == member)
+                        .map(|(channel, _)| channel)
+                };
                 
-                // Broadcast message to others
-                if let Ok(text_bytes) = serde_json::to_vec(&message) {
-                    broadcast_to_participants(
-                        &text_bytes, 
-                        &state_clone, 
-                        &msg_tx, 
-                        from, 
-                        MessagePriority::Low,
-                        msg_verbosity
-                    );
+                let channel = match owned {
+                    Some(channel) => channel,
+                    None => {
+                        log(LogLevel::Debug, msg_verbosity, &format!(
+                            "Rejected key update claiming to be from {}: sender does not own that name", member));
+                        return;
+                    }
+                };
+                
+                // Same priority as state syncs, so a commit answering a join
+                // can't overtake the sync queued for the joiner
+                log(LogLevel::Debug, msg_verbosity, &format!("Relaying key epoch {} update from {} in #{}", epoch, member, channel));
+                broadcast_to_participants(
+                    &envelope,
+                    &state_clone,
+                    &msg_queue,
+                    &channel,
+                    member,
+                    MessagePriority::Medium,
+                    msg_verbosity
+                );
+            },
+            ChatMessage::HistoryRequest { before, limit } => {
+                // Only members get to read back through the room
+                let page = {
+                    let mut state_lock = state_clone.lock().unwrap();
+                    state_lock.touch_member(sender_tag)
+                        .map(|(channel, _)| channel)
+                        .map(|channel| state_lock.channels[&channel].history.page(*before, (*limit).min(MAX_HISTORY_PAGE) as usize))
+                };
+                
+                let (items, more) = match page {
+                    Some(page) => page,
+                    None => {
+                        log(LogLevel::Debug, msg_verbosity, "Rejected history request: sender has not joined");
+                        return;
+                    }
+                };
+                
+                log(LogLevel::Debug, msg_verbosity, &format!("Sending {} history items", items.len()));
+                if let Ok(page_bytes) = format.encode(&ChatMessage::HistoryPage { items, more }) {
+                    queue_message(&msg_queue, sender_tag, page_bytes, MessagePriority::Medium);
+                }
+            },
+            ChatMessage::StateSyncResend { sync_id, missing } => {
+                // Send the missing parts again, and the closing state sync in case that was lost too
+                let resend = {
+                    let mut state_lock = state_clone.lock().unwrap();
+                    let now = state_lock.clock.now();
+                    match state_lock.participant_by_tag_mut(sender_tag) {
+                        Some(participant) => {
+                            participant.last_active = now;
+                            participant.sync.as_ref()
+                                .filter(|sync| sync.sync_id == *sync_id)
+                                .map(|sync| {
+                                    let mut resend: Vec<Vec<u8>> = sync.chunks.iter()
+                                        .enumerate()
+                                        .filter(|(index, _)| missing.contains(&(*index as u32)))
+                                        .map(|(_, chunk)| chunk.clone())
+                                        .collect();
+                                    resend.push(sync.complete.clone());
+                                    resend
+                                })
+                        },
+                        None => None,
+                    }
+                };
+                
+                let resend = match resend {
+                    Some(resend) => resend,
+                    None => {
+                        log(LogLevel::Debug, msg_verbosity, "Ignoring resend request for an unknown state sync");
+                        return;
+                    }
+                };
+                
+                log(LogLevel::Debug, msg_verbosity, &format!("Resending {} state sync chunks", resend.len() - 1));
+                for message_bytes in resend {
+                    queue_message(&msg_queue, sender_tag, message_bytes, MessagePriority::Medium);
                 }
             },
-            ChatMessage::StateSync { .. } => {
-                log(LogLevel::Debug, msg_verbosity, "Ignoring StateSync message at server");
+            ChatMessage::ListChannels => {
+                // Only members get to see what else the room has
+                let channels = {
+                    let mut state_lock = state_clone.lock().unwrap();
+                    state_lock.touch_member(sender_tag).is_some().then(|| {
+                        state_lock.channels.iter()
+                            .map(|(name, channel)| ChannelInfo {
+                                name: name.clone(),
+                                participants: channel.participants.len() as u32,
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                };
+                
+                let channels = match channels {
+                    Some(channels) => channels,
+                    None => {
+                        log(LogLevel::Debug, msg_verbosity, "Rejected channel list request: sender has not joined");
+                        return;
+                    }
+                };
+                
+                if let Ok(list_bytes) = format.encode(&ChatMessage::ChannelList { channels }) {
+                    queue_message(&msg_queue, sender_tag, list_bytes, MessagePriority::Medium);
+                }
+            },
+            ChatMessage::SurbTopUp { surbs } => {
+                // A member that only listens still tops up, so it counts as activity
+                state_clone.lock().unwrap().touch_member(sender_tag);
+                log(LogLevel::Debug, msg_verbosity, &format!("Received {} reply SURBs from client", surbs));
+            },
+            ChatMessage::StateSync { .. }
+            | ChatMessage::StateSyncChunk { .. }
+            | ChatMessage::Welcome { .. }
+            | ChatMessage::HelloRejected { .. }
+            | ChatMessage::Ack { .. }
+            | ChatMessage::JoinRejected { .. }
+            | ChatMessage::AccessDenied { .. }
+            | ChatMessage::HistoryPage { .. }
+            | ChatMessage::ChannelList { .. }
+            | ChatMessage::DirectMessageFailed { .. }
+            | ChatMessage::SurbRequest { .. } => {
+                log(LogLevel::Debug, msg_verbosity, "Ignoring server-only message at server");
+            },
+            ChatMessage::Signed { .. } => {
+                log(LogLevel::Debug, msg_verbosity, "Ignoring message signed more than once");
             }
         }
     }).await;
@@ -410,7 +1399,7 @@ pub async fn run_room_server(verbosity: LogLevel, env_file: Option<String>) -> a
     // Print final stats
     {
         let state_lock = state.lock().unwrap();
-        let uptime = SystemTime::now().duration_since(state_lock.start_time)
+        let uptime = state_lock.clock.now().duration_since(state_lock.start_time)
             .map(|d| d.as_secs())
             .unwrap_or(0);
         
@@ -419,7 +1408,7 @@ pub async fn run_room_server(verbosity: LogLevel, env_file: Option<String>) -> a
         
         println!("\n{}", separator(Some("Final Statistics"), 80));
         println!("Uptime: {}h {}m", hours, minutes);
-        println!("Total participants: {}", state_lock.participants.len());
+        println!("Total participants: {}", state_lock.participant_count());
         println!("Total messages processed: {}", state_lock.message_count);
         println!("Total broadcasts sent: {}", state_lock.broadcast_count);
         println!("{}\n", separator(None, 80));
@@ -428,20 +1417,41 @@ pub async fn run_room_server(verbosity: LogLevel, env_file: Option<String>) -> a
     Ok(())
 }
 
+/// Queue a single encoded message for one recipient
+fn queue_message(
+    queue: &SendQueue<QueuedMessage>,
+    recipient: AnonymousSenderTag,
+    message: Vec<u8>,
+    priority: MessagePriority,
+) {
+    let queued = QueuedMessage {
+        message,
+        recipient,
+        timestamp: Instant::now(),
+    };
+    
+    if queue.push(priority, queued).is_err() {
+        eprintln!("Send queue full, dropping {:?} priority message", priority);
+    }
+}
+
 fn broadcast_to_participants(
-    message: &[u8],
+    message: &ChatMessage,
     state: &Arc<Mutex<RoomState>>,
-    tx: &mpsc::Sender<QueuedMessage>,
+    queue: &SendQueue<QueuedMessage>,
+    channel: &str,
     skip_username: &str,
     priority: MessagePriority,
     verbosity: LogLevel,
 ) {
-    // Get participants to broadcast to
+    // Get participants of the channel to broadcast to
     let recipients = {
         let state_lock = state.lock().unwrap();
-        state_lock.participants.values()
+        state_lock.channels.get(channel)
+            .into_iter()
+            .flat_map(|channel| channel.participants.values())
             .filter(|p| p.username != skip_username)
-            .map(|p| p.sender_tag)
+            .map(|p| (p.sender_tag, p.format, p.capabilities.contains(&Capability::Signatures)))
             .collect::<Vec<_>>()
     };
     
@@ -453,204 +1463,1193 @@ fn broadcast_to_participants(
         "Broadcasting message to {} recipients", recipients.len()
     ));
     
+    // Encode once per wire format and envelope in use, not once per recipient
+    let mut encoded: HashMap<(WireFormat, bool), Vec<u8>> = HashMap::new();
+    
     // Queue the broadcasts
-    for recipient in recipients {
-        let tx_clone = tx.clone();
-        let message_clone = message.to_vec();
+    for (recipient, format, signatures) in recipients {
+        let message_bytes = match encoded.get(&(format, signatures)) {
+            Some(bytes) => bytes.clone(),
+            None => match format.encode(&relayable(message, signatures)) {
+                Ok(bytes) => {
+                    encoded.insert((format, signatures), bytes.clone());
+                    bytes
+                },
+                Err(e) => {
+                    log(LogLevel::Debug, verbosity, &format!("Failed to encode broadcast: {}", e));
+                    continue;
+                }
+            },
+        };
         
-        tokio::spawn(async move {
-            if let Err(e) = tx_clone.send(QueuedMessage {
-                message: message_clone,
-                recipient,
-                priority,
-                timestamp: Instant::now(),
-            }).await {
-                eprintln!("Failed to queue broadcast: {}", e);
-            }
-        });
+        queue_message(queue, recipient, message_bytes, priority);
+    }
+}
+
+/// `message` as a participant can read it: clients that don't understand
+/// signature envelopes get just what the envelope carries, still numbered
+fn relayable(message: &ChatMessage, signatures: bool) -> ChatMessage {
+    match (signatures, message.seq()) {
+        (true, _) => message.clone(),
+        (false, Some(seq)) => message.payload().with_seq(seq),
+        (false, None) => message.payload().clone(),
     }
 }
 
-pub async fn run_chat_client(username: String, room_address: String, verbosity: LogLevel, env_file: Option<String>) -> anyhow::Result<()> {
+pub async fn run_chat_client(
+    username: String,
+    room_address: String,
+    config: ClientConfig,
+    data_dir: Option<PathBuf>,
+    verbosity: LogLevel,
+    env_file: Option<String>,
+) -> anyhow::Result<()> {
     // Set environment if provided
     if let Some(path) = &env_file {
         std::env::set_var("NYM_ENV_FILE", path);
     }
     
-    // Clean up address
-    let address_str = room_address.strip_prefix("nym://").unwrap_or(&room_address);
-    let room_address = Recipient::from_str(address_str)?;
+    // The invite may carry the room secret after the address
+    let (room_address, room_secret) = crypto::parse_invite(&room_address)?;
+    if room_secret.is_none() {
+        println!("{}Warning:{} this address has no room secret, so your messages will not be end-to-end encrypted.",
+            Colors::YELLOW, Colors::RESET);
+        println!("Create an invite with `nymcat invite <address>` and share it with the room.");
+    }
     
     // Create mixnet client
-    let mut client = MixnetClient::connect_new().await?;
-    log(LogLevel::Info, verbosity, &format!("Connected to mixnet as {}", client.nym_address()));
+    let client = transport::connect_mixnet(data_dir.as_deref()).await?;
     
-    let sender = client.split_sender();
+    // Feed stdin into the session until Ctrl+C
+    let io = SessionIo::stdin(move || {
+        println!("{}Leaving chat room...{}", Colors::YELLOW, Colors::RESET);
+        log(LogLevel::Info, verbosity, "Leaving chat room (Ctrl+C received)");
+    });
     
-    // Send join message
-    let join_msg = ChatMessage::Join { username: username.clone() };
-    log(LogLevel::Debug, verbosity, "Sending join message");
-    sender.send_message(
-        room_address, 
-        &serde_json::to_vec(&join_msg)?, 
-        IncludedSurbs::Amount(SURBS_PER_MESSAGE)
-    ).await?;
+    let config = ClientConfig { room_secret, ..config };
+    chat_session(client, username, room_address, config, io, verbosity).await?;
+    
+    // Wait briefly for leave message to be sent
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    std::process::exit(0);
+}
+
+/// Run a chat client over any transport, returning once it has left the room
+pub async fn chat_session<T: Transport>(
+    mut client: T,
+    username: String,
+    room_address: Recipient,
+    config: ClientConfig,
+    io: SessionIo,
+    verbosity: LogLevel,
+) -> anyhow::Result<()> {
+    log(LogLevel::Info, verbosity, &format!("Connected to mixnet as {}", client.nym_address()));
     
-    println!("{}Joined chat room as {}{}", Colors::GREEN, username, Colors::RESET);
+    let access_key = config.passphrase
+        .map(|passphrase| AccessKey::from_passphrase(&passphrase, &room_address))
+        .transpose()?;
+
+    let SessionIo { lines: mut input_lines, received } = io;
+    let mut session = ClientSession {
+        sender: client.split_sender(),
+        group: config.room_secret.map(|secret| GroupState::new(secret, room_address)),
+        identity: config.identity.unwrap_or_else(Identity::generate),
+        identity_keys: HashMap::new(),
+        known_peers: config.known_peers.unwrap_or_else(KnownPeers::in_memory),
+        last_seen: config.last_seen.unwrap_or_else(LastSeen::in_memory),
+        history_epoch: None,
+        synced: false,
+        early_commits: Vec::new(),
+        channel: config.channel,
+        joined_channel: None,
+        default_channel: None,
+        key_changed: HashSet::new(),
+        access_key,
+        challenge: None,
+        access_denied: None,
+        oldest_seq: None,
+        history_complete: false,
+        sync: None,
+        signing: false,
+        room_address,
+        username,
+        // Until the room tells us otherwise, speak the format every server understands
+        format: WireFormat::LegacyJson,
+        acks_enabled: false,
+        pending: HashMap::new(),
+        outgoing: VecDeque::new(),
+        choosing_name: false,
+        surb_topups: false,
+        surb_estimate: 0,
+        top_up_requested: false,
+        traffic: TrafficRate::new(Duration::from_secs(SURB_TOPUP_HORIZON_SECS)),
+        verbosity,
+    };
     
-    // Handle user input
-    let username_input = username.clone();
-    let sender_input = sender.clone();
-    let input_verbosity = verbosity;
-    let input_room_address = room_address;
+    // Decode incoming messages and hand them to the session loop
+    let (incoming_tx, mut incoming_rx) = mpsc::unbounded_channel::<ChatMessage>();
+    let msgs_verbosity = verbosity;
     
-    tokio::spawn(async move {
-        let stdin = BufReader::new(tokio::io::stdin());
-        let mut lines = stdin.lines();
+    let handle_messages = client.on_messages(move |msg| {
+        log(LogLevel::Trace, msgs_verbosity, &format!("Received raw message: {} bytes", msg.message.len()));
         
-        while let Ok(Some(line)) = lines.next_line().await {
-            if line.trim().is_empty() { continue; }
-            
-            let timestamp = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .map(|d| d.as_secs())
-                .unwrap_or(0);
-            
-            let text_msg = ChatMessage::Text {
-                from: username_input.clone(),
-                content: line.trim().to_string(),
-                timestamp,
-            };
-            
-            log(LogLevel::Debug, input_verbosity, &format!("Sending text message: {}", line.trim()));
-            match serde_json::to_vec(&text_msg) {
-                Ok(msg_bytes) => {
-                    if let Err(e) = sender_input.send_message(
-                        input_room_address,
-                        &msg_bytes,
-                        IncludedSurbs::Amount(SURBS_PER_MESSAGE)
-                    ).await {
-                        eprintln!("{}Failed to send message: {}{}", Colors::RED, e, Colors::RESET);
-                    }
-                },
-                Err(e) => {
-                    log(LogLevel::Debug, input_verbosity, &format!("Failed to serialize message: {}", e));
+        match codec::decode(&msg.message) {
+            Ok((message, _)) => {
+                if let Some(received) = &received {
+                    received.send(message.clone()).ok();
                 }
+                incoming_tx.send(message).ok();
+            },
+            Err(e) => {
+                log(LogLevel::Debug, msgs_verbosity, &format!("Failed to parse incoming message: {}", e));
             }
         }
     });
     
-    // Handle Ctrl+C for clean exit
-    let username_exit = username.clone();
-    let sender_exit = sender.clone();
-    let exit_verbosity = verbosity;
-    let exit_room_address = room_address;
+    let run_session = async {
+        session.handshake(&mut incoming_rx).await?;
+        
+        // Send join message
+        log(LogLevel::Debug, verbosity, "Sending join message");
+        session.send(&session.join_message()).await?;
+        
+        // Handle user input and incoming messages until the input is closed
+        let mut ack_check = tokio::time::interval(Duration::from_secs(ACK_CHECK_INTERVAL_SECS));
+        
+        loop {
+            tokio::select! {
+                line = input_lines.recv() => match line {
+                    Some(line) => session.handle_input(&line).await,
+                    None => break,
+                },
+                message = incoming_rx.recv() => match message {
+                    Some(message) => {
+                        session.handle_message(&message);
+                        if let Some(reason) = session.access_denied.take() {
+                            return Err(anyhow::anyhow!("Room denied access: {}", reason));
+                        }
+                        session.send_outgoing().await;
+                        session.top_up_surbs().await;
+                    },
+                    None => return Ok(()),
+                },
+                _ = ack_check.tick() => {
+                    session.save_last_seen();
+                    session.check_sync();
+                    session.check_rekey();
+                    session.send_outgoing().await;
+                    session.retransmit_pending().await;
+                    session.top_up_surbs().await;
+                },
+            }
+        }
+        
+        // A rejected join never made us a participant
+        if !session.choosing_name {
+            log(LogLevel::Debug, verbosity, "Sending leave message");
+            if let Err(e) = session.send(&ChatMessage::Leave { username: session.username.clone() }).await {
+                log(LogLevel::Debug, verbosity, &format!("Failed to send leave message: {}", e));
+            }
+        }
+        
+        Ok(())
+    };
     
-    tokio::spawn(async move {
-        signal::ctrl_c().await.ok();
-        println!("{}Leaving chat room...{}", Colors::YELLOW, Colors::RESET);
-        log(LogLevel::Info, exit_verbosity, "Leaving chat room (Ctrl+C received)");
+    // Receive until we have left the room, then keep where we left off
+    let result = tokio::select! {
+        _ = handle_messages => Ok(()),
+        result = run_session => result,
+    };
+    session.save_last_seen();
+    result
+}
+
+/// One of our lines still waiting for the room's acknowledgement
+struct PendingLine {
+    message: ChatMessage,
+    content: String,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+// Backoff before the next send, given how many sends were already made
+fn retransmit_delay(attempts: u32) -> Duration {
+    Duration::from_secs(RETRANSMIT_INITIAL_SECS << attempts.saturating_sub(1).min(6))
+}
+
+/// A chunked state sync still being put back together
+struct PendingSync {
+    sync_id: u64,
+    chunks: u32,
+    received: BTreeMap<u32, Vec<HistoryItem>>,
+    complete: Option<ChatMessage>, // The closing `StateSync`, once it arrives
+    last_progress: Instant,
+    resends: u32,
+    done: bool, // Applied or given up on; late duplicates are ignored
+}
+
+/// Sliding count of messages received from the room
+struct TrafficRate {
+    arrivals: VecDeque<Instant>,
+    window: Duration,
+}
+
+impl TrafficRate {
+    fn new(window: Duration) -> Self {
+        Self {
+            arrivals: VecDeque::new(),
+            window,
+        }
+    }
+    
+    fn record(&mut self, now: Instant) {
+        self.arrivals.push_back(now);
+        self.expire(now);
+    }
+    
+    /// Messages received within the last window
+    fn count(&mut self, now: Instant) -> usize {
+        self.expire(now);
+        self.arrivals.len()
+    }
+    
+    fn expire(&mut self, now: Instant) {
+        while let Some(oldest) = self.arrivals.front() {
+            if now.duration_since(*oldest) < self.window {
+                break;
+            }
+            self.arrivals.pop_front();
+        }
+    }
+}
+
+/// Client-side state for one chat session
+struct ClientSession<S: TransportSender> {
+    sender: S,
+    room_address: Recipient,
+    group: Option<GroupState>, // Encrypts our content end-to-end
+    identity: Identity,
+    identity_keys: HashMap<String, Vec<u8>>, // Username -> identity key, from joins and state syncs
+    known_peers: KnownPeers,
+    last_seen: LastSeen, // Last message we saw in each room, so a rejoin only syncs what's new
+    history_epoch: Option<u64>, // Numbering of our channel's history, from its state sync
+    synced: bool, // The room has sent our state sync
+    early_commits: Vec<ChatMessage>, // Commits that came ahead of it, applied once it has
+    channel: Option<String>, // Channel we asked to join, if we named one
+    joined_channel: Option<String>, // Channel the room put us in, once it says
+    default_channel: Option<String>, // Where the room puts joins that name no channel
+    key_changed: HashSet<String>, // Members whose announced key differs from the pinned one
+    access_key: Option<AccessKey>, // From the room passphrase
+    challenge: Option<Vec<u8>>, // The room's join challenge, if it is protected
+    access_denied: Option<String>, // Why the room turned us away; ends the session
+    oldest_seq: Option<u64>, // Oldest history item we have shown, where /history continues from
+    history_complete: bool, // The room has nothing older than what we have shown
+    sync: Option<PendingSync>, // Chunked state sync being put back together
+    signing: bool, // Room relays signed envelopes
+    username: String,
+    format: WireFormat,
+    acks_enabled: bool, // Room confirms each line it accepts
+    pending: HashMap<MessageId, PendingLine>,
+    outgoing: VecDeque<ChatMessage>, // Replies decided while handling a message, sent afterwards
+    choosing_name: bool, // Room rejected our join; the next line is a new username
+    surb_topups: bool, // Room understands SurbTopUp
+    surb_estimate: u32, // Reply SURBs we think the room still holds for us
+    top_up_requested: bool,
+    traffic: TrafficRate,
+    verbosity: LogLevel,
+}
+
+impl<S: TransportSender> ClientSession<S> {
+    /// Send a message to the room in the negotiated wire format
+    async fn send(&mut self, message: &ChatMessage) -> anyhow::Result<()> {
+        self.send_with_surbs(message, SURBS_PER_MESSAGE).await
+    }
+    
+    async fn send_with_surbs(&mut self, message: &ChatMessage, surbs: u32) -> anyhow::Result<()> {
+        let msg_bytes = if self.signing {
+            // Other members never see our join proof, so it goes beside the signature
+            let mut signed = self.identity.sign(&message.without_proof(), &self.room_address, self.signing_channel(message))?;
+            if let ChatMessage::Signed { proof, .. } = &mut signed {
+                *proof = message.join_proof().map(<[u8]>::to_vec);
+            }
+            self.format.encode(&signed)?
+        } else {
+            self.format.encode(message)?
+        };
+        self.sender.send_message(
+            self.room_address,
+            &msg_bytes,
+            IncludedSurbs::Amount(surbs)
+        ).await?;
         
-        let leave_msg = ChatMessage::Leave { username: username_exit };
-        match serde_json::to_vec(&leave_msg) {
-            Ok(msg_bytes) => {
-                sender_exit.send_message(
-                    exit_room_address,
-                    &msg_bytes,
-                    IncludedSurbs::Amount(SURBS_PER_MESSAGE)
-                ).await.ok();
+        self.surb_estimate = self.surb_estimate.saturating_add(surbs);
+        Ok(())
+    }
+    
+    /// The channel a signature on `message` is bound to: ours, or none for
+    /// messages between two members, which the room relays across channels
+    fn signing_channel(&self, message: &ChatMessage) -> &str {
+        match message.payload() {
+            ChatMessage::DirectMessage { .. } => "",
+            _ => self.joined_channel.as_deref()
+                .or(self.channel.as_deref())
+                .or(self.default_channel.as_deref())
+                .unwrap_or(""),
+        }
+    }
+    
+    /// Our join announcement, with a key package when we take part in group keying
+    fn join_message(&self) -> ChatMessage {
+        ChatMessage::Join {
+            username: self.username.clone(),
+            key_package: self.group.as_ref().map(GroupState::key_package),
+            identity_key: Some(self.identity.public_key()),
+            proof: match (&self.access_key, &self.challenge) {
+                (Some(access_key), Some(challenge)) => Some(access_key.prove(challenge, &self.username)),
+                _ => None,
             },
-            Err(e) => {
-                log(LogLevel::Debug, exit_verbosity, &format!("Failed to serialize leave message: {}", e));
+            last_seen: self.last_seen.get(&self.room_address, self.channel.as_deref()),
+            channel: self.channel.clone(),
+        }
+    }
+    
+    /// Send the messages `handle_message` decided on
+    async fn send_outgoing(&mut self) {
+        while let Some(message) = self.outgoing.pop_front() {
+            if let Err(e) = self.send(&message).await {
+                log(LogLevel::Debug, self.verbosity, &format!("Failed to send control message: {}", e));
             }
         }
+    }
+    
+    /// Send the room enough reply SURBs to keep up with its recent traffic
+    async fn top_up_surbs(&mut self) {
+        if !self.surb_topups {
+            return;
+        }
         
-        // Wait briefly for message to be sent
-        tokio::time::sleep(Duration::from_millis(500)).await;
-        std::process::exit(0);
-    });
+        // Expect as many messages over the next window as over the last one
+        let expected = self.traffic.count(Instant::now()) as u32;
+        let target = expected.clamp(MIN_SURB_TOPUP, MAX_SURB_TOPUP);
+        
+        if !self.top_up_requested && self.surb_estimate >= target / 2 {
+            return;
+        }
+        
+        let surbs = target.saturating_sub(self.surb_estimate).max(MIN_SURB_TOPUP);
+        log(LogLevel::Debug, self.verbosity, &format!(
+            "Topping up {} reply SURBs ({} messages from the room recently)", surbs, expected));
+        
+        match self.send_with_surbs(&ChatMessage::SurbTopUp { surbs }, surbs).await {
+            Ok(()) => self.top_up_requested = false,
+            Err(e) => log(LogLevel::Debug, self.verbosity, &format!("Failed to send SURB top-up: {}", e)),
+        }
+    }
     
-    // Handle incoming messages
-    let username_msgs = username.clone();
-    let msgs_verbosity = verbosity;
+    /// Exchange Hello/Welcome with the room and settle on a wire format
+    async fn handshake(&mut self, incoming: &mut mpsc::UnboundedReceiver<ChatMessage>) -> anyhow::Result<()> {
+        // Only claim encryption when we hold a room secret to seal with
+        let mut capabilities = CLIENT_CAPABILITIES.to_vec();
+        if self.group.is_some() {
+            capabilities.push(Capability::Encryption);
+        }
+        
+        log(LogLevel::Debug, self.verbosity, "Sending hello");
+        self.send(&ChatMessage::Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities,
+        }).await?;
+        
+        let verbosity = self.verbosity;
+        let wait_for_welcome = async {
+            while let Some(message) = incoming.recv().await {
+                match message {
+                    ChatMessage::Welcome { .. } | ChatMessage::HelloRejected { .. } => return Some(message),
+                    _ => log(LogLevel::Debug, verbosity, "Ignoring message received before welcome"),
+                }
+            }
+            None
+        };
+        
+        let reply = tokio::time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), wait_for_welcome).await;
+        match reply {
+            Ok(Some(ChatMessage::Welcome { version, capabilities, challenge, default_channel })) => {
+                log(LogLevel::Info, self.verbosity, &format!(
+                    "Room speaks protocol v{} with capabilities {:?}", version, capabilities));
+                
+                if capabilities.contains(&Capability::BinaryCodec) {
+                    self.format = WireFormat::PREFERRED;
+                }
+                self.acks_enabled = capabilities.contains(&Capability::DeliveryAcks);
+                self.surb_topups = capabilities.contains(&Capability::SurbTopUp);
+                self.signing = capabilities.contains(&Capability::Signatures);
+                self.challenge = challenge;
+                self.default_channel = default_channel;
+                Ok(())
+            },
+            Ok(Some(ChatMessage::HelloRejected { reason, .. })) => {
+                println!("{}Room rejected connection:{} {}", Colors::RED, Colors::RESET, reason);
+                Err(anyhow::anyhow!("Room rejected connection: {}", reason))
+            },
+            Ok(_) => Err(anyhow::anyhow!("Connection closed during handshake")),
+            Err(_) => {
+                // Rooms that predate the handshake silently drop our hello
+                log(LogLevel::Info, self.verbosity, "No welcome from room, assuming a legacy room server");
+                Ok(())
+            },
+        }
+    }
     
-    client.on_messages(move |msg| {
-        log(LogLevel::Trace, msgs_verbosity, &format!("Received raw message: {} bytes", msg.message.len()));
+    /// Send a line typed by the user
+    async fn handle_input(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() { return; }
         
-        if let Ok(message) = serde_json::from_slice::<ChatMessage>(&msg.message) {
-            match &message {
-                ChatMessage::Join { username: join_username } if join_username != &username_msgs => {
-                    println!("{}User joined:{} {}", Colors::GREEN, Colors::RESET, join_username);
-                    log(LogLevel::Info, msgs_verbosity, &format!("User joined: {}", join_username));
-                },
-                ChatMessage::Leave { username: leave_username } if leave_username != &username_msgs => {
-                    println!("{}User left:{} {}", Colors::YELLOW, Colors::RESET, leave_username);
-                    log(LogLevel::Info, msgs_verbosity, &format!("User left: {}", leave_username));
-                },
-                ChatMessage::Text { from, content, .. } if from != &username_msgs => {
-                    let name_color = get_username_color(from);
-                    println!("{}{}{}: {}", name_color, from, Colors::RESET, content);
-                    log(LogLevel::Info, msgs_verbosity, &format!("Message from {}: {}", from, content));
+        if self.choosing_name {
+            self.username = line.to_string();
+            self.choosing_name = false;
+            
+            log(LogLevel::Debug, self.verbosity, &format!("Retrying join as {}", self.username));
+            if let Err(e) = self.send(&self.join_message()).await {
+                log(LogLevel::Debug, self.verbosity, &format!("Failed to send join message: {}", e));
+            }
+            return;
+        }
+        
+        if let Some(command) = line.strip_prefix('/') {
+            self.handle_command(command);
+            self.send_outgoing().await;
+            return;
+        }
+        
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        
+        // Only ciphertext leaves this client when we hold the room key
+        let (content, sealed) = match &self.group {
+            Some(group) => match group.seal(line, &self.username) {
+                Ok(sealed) => (String::new(), Some(sealed)),
+                Err(e) => {
+                    log(LogLevel::Debug, self.verbosity, &format!("Failed to encrypt message: {}", e));
+                    println!("{}", format_delivery_status(DeliveryState::Failed, line));
+                    return;
+                }
+            },
+            None => (line.to_string(), None),
+        };
+        
+        let id = new_message_id();
+        let text_msg = ChatMessage::Text {
+            from: self.username.clone(),
+            content,
+            timestamp,
+            id: Some(id),
+            sealed,
+            seq: None,
+        };
+        
+        log(LogLevel::Debug, self.verbosity, &format!("Sending text message: {}", line));
+        let sent = self.send(&text_msg).await;
+        if let Err(e) = &sent {
+            log(LogLevel::Debug, self.verbosity, &format!("Failed to send message: {}", e));
+        }
+        
+        if self.acks_enabled {
+            // Even a failed send gets retried until the room acknowledges it
+            println!("{}", format_delivery_status(DeliveryState::Pending, line));
+            self.pending.insert(id, PendingLine {
+                message: text_msg,
+                content: line.to_string(),
+                attempts: 1,
+                next_attempt: Instant::now() + retransmit_delay(1),
+            });
+        } else if sent.is_err() {
+            println!("{}", format_delivery_status(DeliveryState::Failed, line));
+        }
+    }
+    
+    /// Run a `/command` typed by the user
+    fn handle_command(&mut self, command: &str) {
+        let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+        let argument = argument.trim();
+        
+        match name {
+            "verify" => self.show_fingerprint(argument),
+            "history" => self.request_history(argument),
+            "list" => self.outgoing.push_back(ChatMessage::ListChannels),
+            "msg" => self.send_direct(argument),
+            _ => {
+                println!("{}Unknown command /{}{}", Colors::RED, name, Colors::RESET);
+                println!("Commands: /verify [user], /history [count], /list, /msg <user> <text>");
+            }
+        }
+    }
+    
+    /// Send a line to one participant only
+    fn send_direct(&mut self, argument: &str) {
+        let (to, text) = match argument.split_once(' ') {
+            Some((to, text)) if !text.trim().is_empty() => (to, text.trim()),
+            _ => {
+                println!("{}Usage: /msg <user> <text>{}", Colors::RED, Colors::RESET);
+                return;
+            }
+        };
+        
+        // Only members of our channel hold our room key, so only they can read a sealed message
+        let (content, sealed) = match &self.group {
+            Some(_) if !self.identity_keys.contains_key(to) => {
+                println!("{}Not sent:{} {} is not in your channel, and only members of it can read encrypted messages from you",
+                    Colors::RED, Colors::RESET, to);
+                return;
+            },
+            Some(group) => match group.seal(text, &self.username) {
+                Ok(sealed) => (String::new(), Some(sealed)),
+                Err(e) => {
+                    log(LogLevel::Debug, self.verbosity, &format!("Failed to encrypt message: {}", e));
+                    println!("{}", format_delivery_status(DeliveryState::Failed, text));
+                    return;
+                }
+            },
+            None => (text.to_string(), None),
+        };
+        
+        println!("{}→ {}{}: {}", Colors::MAGENTA, to, Colors::RESET, text);
+        self.outgoing.push_back(ChatMessage::DirectMessage {
+            from: self.username.clone(),
+            to: to.to_string(),
+            content,
+            sealed,
+        });
+    }
+    
+    /// Ask the room for the page of history before the oldest item we have shown
+    fn request_history(&mut self, argument: &str) {
+        let limit = match argument {
+            "" => HISTORY_PAGE_SIZE,
+            count => match count.parse::<u32>() {
+                Ok(count) if count > 0 => count.min(MAX_HISTORY_PAGE),
+                _ => {
+                    println!("{}Usage: /history [count]{}", Colors::RED, Colors::RESET);
+                    return;
+                }
+            },
+        };
+        
+        if self.history_complete {
+            println!("{}No earlier messages in this room{}", Colors::DIM, Colors::RESET);
+            return;
+        }
+        
+        self.outgoing.push_back(ChatMessage::HistoryRequest {
+            before: self.oldest_seq,
+            limit,
+        });
+    }
+    
+    /// Print a member's identity fingerprint (or ours) for comparison out of band
+    fn show_fingerprint(&self, username: &str) {
+        if username.is_empty() || username == self.username {
+            println!("Your fingerprint: {}{}{}",
+                Colors::BOLD, identity::fingerprint(&self.identity.public_key()), Colors::RESET);
+            return;
+        }
+        
+        let key = match self.identity_keys.get(username) {
+            Some(key) => key,
+            None => {
+                println!("No identity key known for {}", username);
+                return;
+            }
+        };
+        
+        println!("Fingerprint for {}: {}{}{}", username, Colors::BOLD, identity::fingerprint(key), Colors::RESET);
+        if let Some(pinned) = self.known_peers.pinned(&self.room_address, username).filter(|pinned| *pinned != key.as_slice()) {
+            println!("{}This is NOT the key you pinned for {}: {}{}",
+                Colors::BRIGHT_RED, username, identity::fingerprint(pinned), Colors::RESET);
+        }
+        println!("Compare it with {} over another channel before trusting them.", username);
+    }
+    
+    /// Pin a member's identity key on first contact, warning if it changed since
+    fn observe_identity_key(&mut self, username: &str, key: &[u8]) {
+        match self.known_peers.check(&self.room_address, username, key) {
+            PinStatus::New => {
+                log(LogLevel::Debug, self.verbosity, &format!(
+                    "Pinning identity key {} for {}", identity::fingerprint(key), username));
+                if let Err(e) = self.known_peers.pin(&self.room_address, username, key) {
+                    log(LogLevel::Info, self.verbosity, &format!("Failed to save pinned key for {}: {}", username, e));
+                }
+                self.key_changed.remove(username);
+            },
+            PinStatus::Known => {
+                self.key_changed.remove(username);
+            },
+            PinStatus::Changed => {
+                let pinned = self.known_peers.pinned(&self.room_address, username).unwrap_or_default();
+                println!("\n{}{}WARNING: the identity key for {} has changed!{}", Colors::BOLD, Colors::BRIGHT_RED, username, Colors::RESET);
+                println!("{}  pinned:    {}{}", Colors::BRIGHT_RED, identity::fingerprint(pinned), Colors::RESET);
+                println!("{}  announced: {}{}", Colors::BRIGHT_RED, identity::fingerprint(key), Colors::RESET);
+                println!("Someone may be impersonating {}. Their messages will be marked [key changed].", username);
+                if let Some(path) = self.known_peers.path() {
+                    println!("If they really have a new key, check it with /verify {} and remove their old entry from {}\n",
+                        username, path.display());
+                }
+                self.key_changed.insert(username.to_string());
+            },
+        }
+    }
+    
+    /// Resend lines the room has not acknowledged yet, giving up after a few tries
+    async fn retransmit_pending(&mut self) {
+        let now = Instant::now();
+        let due: Vec<MessageId> = self.pending.iter()
+            .filter(|(_, line)| line.next_attempt <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        
+        for id in due {
+            let message = match self.pending.get_mut(&id) {
+                Some(line) if line.attempts >= MAX_SEND_ATTEMPTS => None,
+                Some(line) => {
+                    line.attempts += 1;
+                    line.next_attempt = now + retransmit_delay(line.attempts);
+                    Some((line.message.clone(), line.attempts))
                 },
-                ChatMessage::StateSync { history, participants } => {
-                    log(LogLevel::Debug, msgs_verbosity, &format!(
-                        "Received state sync with {} messages and {} participants",
-                        history.len(), participants.len()
-                    ));
-                    
-                    // Print participant list
-                    println!("\n{}", separator(Some(&format!("Current Participants ({})", participants.len())), 80));
-                    
-                    for participant in participants {
-                        let color = if *participant == username_msgs {
-                            Colors::BRIGHT_BLUE
-                        } else {
-                            get_username_color(&participant)
-                        };
-                        println!("- {}{}{}", color, participant, Colors::RESET);
+                None => continue,
+            };
+            
+            match message {
+                Some((message, attempt)) => {
+                    log(LogLevel::Debug, self.verbosity, &format!(
+                        "Retransmitting message {:016x} (attempt {})", id, attempt));
+                    if let Err(e) = self.send(&message).await {
+                        log(LogLevel::Debug, self.verbosity, &format!("Retransmission failed: {}", e));
                     }
-                    
-                    println!("{}", separator(None, 80));
-                    
-                    // Print history
-                    if !history.is_empty() {
-                        println!("{}", separator(Some("Message History"), 80));
-                        
-                        for item in history {
-                            if &item.from != &username_msgs {
-                                // Format timestamp
-                                let time = UNIX_EPOCH + Duration::from_secs(item.timestamp);
-                                let time_str = format_timestamp(time);
-                                
-                                // Get username color
-                                let name_color = get_username_color(&item.from);
-                                
-                                println!("{}{}{} {}{}{}: {}",
-                                    Colors::DIM, time_str, Colors::RESET,
-                                    name_color, item.from, Colors::RESET,
-                                    item.content
-                                );
-                            }
-                        }
-                        
-                        println!("{}", separator(None, 80));
+                },
+                None => {
+                    if let Some(line) = self.pending.remove(&id) {
+                        println!("{}", format_delivery_status(DeliveryState::Failed, &line.content));
                     }
+                }
+            }
+        }
+    }
+    
+    /// Ask for a fresh commit if live traffic is sealed under an epoch we never got
+    fn request_commit(&mut self, epoch: u64) {
+        if let Some(group) = &mut self.group {
+            if let Some(request) = group.request_commit(epoch, &self.username) {
+                log(LogLevel::Debug, self.verbosity, &format!("Missed the commit for key epoch {}, asking for a new one", epoch));
+                self.outgoing.push_back(request);
+            }
+        }
+    }
+    
+    /// Rekey in place of members ahead of us in line who should have by now
+    fn check_rekey(&mut self) {
+        if let Some(group) = &mut self.group {
+            if let Some(commit) = group.overdue_commit(Instant::now(), &self.username) {
+                log(LogLevel::Debug, self.verbosity, &format!("No commit arrived in time, rekeying to epoch {} ourselves", group.epoch()));
+                self.outgoing.push_back(commit);
+            }
+        }
+    }
+    
+    /// Content as we can show it. `stored` content comes from the room's
+    /// history rather than live, so it is from an epoch that is already past.
+    fn reveal(&self, from: &str, content: &str, sealed: Option<&Sealed>, stored: bool) -> String {
+        let sealed = match sealed {
+            Some(sealed) => sealed,
+            None => return content.to_string(),
+        };
+        
+        match &self.group {
+            Some(group) => match group.open(sealed, from) {
+                Ok(plaintext) => plaintext,
+                Err(_) if self.unrecoverable(sealed, stored) => {
+                    format!("{}[sealed under a room key this session never held; it can't be recovered]{}", Colors::DIM, Colors::RESET)
                 },
-                _ => {}
+                Err(e) => {
+                    log(LogLevel::Debug, self.verbosity, &format!("Failed to decrypt message from {}: {}", from, e));
+                    format!("{}[could not decrypt this message]{}", Colors::RED, Colors::RESET)
+                }
+            },
+            None => format!("{}[encrypted, join with the room invite to read it]{}", Colors::DIM, Colors::RESET),
+        }
+    }
+    
+    /// Whether `sealed` is under a past epoch we don't hold a key for, which
+    /// no later commit will give us. Live content from a newer epoch is just
+    /// waiting on its commit.
+    fn unrecoverable(&self, sealed: &Sealed, stored: bool) -> bool {
+        self.group.as_ref().is_some_and(|group| {
+            !group.holds(sealed.epoch) && (stored || sealed.epoch < group.epoch())
+        })
+    }
+    
+    /// Unwrap a signed message and check it against its sender's identity key
+    fn authenticate<'m>(&self, message: &'m ChatMessage) -> (&'m ChatMessage, Authenticity) {
+        let (signed, signature) = match message {
+            ChatMessage::Signed { message, signature, .. } => (message, signature),
+            message => return (message, Authenticity::Unverified),
+        };
+        let payload = signed.message();
+        
+        // A join is signed with the key it announces. Members who have left
+        // since are checked against the key we pinned for them.
+        let key = match payload {
+            ChatMessage::Join { identity_key: Some(key), .. } => Some(key.as_slice()),
+            _ => payload.sender().and_then(|sender| {
+                self.identity_keys.get(sender).map(Vec::as_slice)
+                    .or_else(|| self.known_peers.pinned(&self.room_address, sender))
+            }),
+        };
+        
+        let channel = self.signing_channel(payload);
+        let authenticity = match key.map(|key| identity::verify(signed, signature, key, &self.room_address, channel)) {
+            Some(Ok(())) if payload.sender().is_some_and(|sender| self.key_changed.contains(sender)) => {
+                Authenticity::KeyChanged
+            },
+            Some(Ok(())) => Authenticity::Verified,
+            Some(Err(e)) => {
+                log(LogLevel::Debug, self.verbosity, &format!(
+                    "Bad signature on message from {}: {}", payload.sender().unwrap_or("the room"), e));
+                Authenticity::Invalid
+            },
+            None => Authenticity::Unverified,
+        };
+        
+        (payload, authenticity)
+    }
+    
+    /// Check a history item against the signature the room kept with it
+    fn history_authenticity(&self, item: &HistoryItem) -> Authenticity {
+        let (signature, key) = match (&item.signature, &item.identity_key) {
+            (Some(signature), Some(key)) => (signature, key),
+            _ => return Authenticity::Unverified,
+        };
+        
+        // The signature has to cover the item exactly as we were given it
+        let verified = item.signed_payload().and_then(|payload| {
+            identity::verify(&payload, signature, key, &self.room_address, self.signing_channel(payload.message()))
+        });
+        if let Err(e) = verified {
+            log(LogLevel::Debug, self.verbosity, &format!("Bad signature on history item from {}: {}", item.from, e));
+            return Authenticity::Invalid;
+        }
+        
+        // The room says which key made it, so it only counts if we know them by that key
+        let known = self.identity_keys.get(&item.from).map(Vec::as_slice)
+            .or_else(|| self.known_peers.pinned(&self.room_address, &item.from));
+        match known {
+            Some(known) if known != key.as_slice() || self.key_changed.contains(&item.from) => Authenticity::KeyChanged,
+            Some(_) => Authenticity::Verified,
+            None => Authenticity::Unverified,
+        }
+    }
+    
+    /// Print history items under a heading
+    fn print_history(&self, title: &str, items: &[&HistoryItem]) {
+        if items.is_empty() {
+            return;
+        }
+        
+        println!("{}", separator(Some(title), 80));
+        
+        for item in items {
+            // Format timestamp
+            let time = UNIX_EPOCH + Duration::from_secs(item.timestamp);
+            let time_str = format_timestamp(time);
+            
+            // Get username color
+            let name_color = if item.from == self.username {
+                Colors::BRIGHT_BLUE
+            } else {
+                get_username_color(&item.from)
+            };
+            
+            println!("{}{}{} {}{}{}{}: {}",
+                Colors::DIM, time_str, Colors::RESET,
+                name_color, item.from, Colors::RESET,
+                format_authenticity(self.history_authenticity(item)),
+                self.reveal(&item.from, &item.content, item.sealed.as_ref(), true)
+            );
+        }
+        
+        println!("{}", separator(None, 80));
+        
+        let unrecoverable = items.iter()
+            .filter(|item| item.sealed.as_ref().is_some_and(|sealed| self.unrecoverable(sealed, true)))
+            .count();
+        if unrecoverable > 0 {
+            println!("{}{} of these were sealed under room keys this session never held and can't be read here{}",
+                Colors::DIM, unrecoverable, Colors::RESET);
+        }
+    }
+    
+    /// Take in the room's participants and keys and show its recent history
+    fn apply_state_sync(&mut self, history: &[HistoryItem], sync: &ChatMessage) {
+        let (participants, key_packages, identity_keys, since, channel, more, history_epoch) = match sync {
+            ChatMessage::StateSync { participants, key_packages, identity_keys, since, channel, more, history_epoch, .. } => {
+                (participants, key_packages, identity_keys, *since, channel.as_deref(), *more, *history_epoch)
+            },
+            _ => return,
+        };
+        
+        log(LogLevel::Debug, self.verbosity, &format!(
+            "Received state sync with {} messages and {} participants",
+            history.len(), participants.len()
+        ));
+        
+        // The room only syncs us once it has accepted our join
+        println!("{}Joined chat room as {}{}", Colors::GREEN, self.username, Colors::RESET);
+        
+        self.identity_keys = identity_keys.iter()
+            .map(|key| (key.username.clone(), key.public_key.clone()))
+            .collect();
+        for key in identity_keys {
+            if key.username != self.username {
+                self.observe_identity_key(&key.username, &key.public_key);
             }
+        }
+        
+        if let Some(group) = &mut self.group {
+            group.sync_members(key_packages, &self.username);
+        }
+        
+        // Print participant list; rooms that predate channels don't say which we are in
+        self.joined_channel = channel.map(str::to_string);
+        let title = match channel {
+            Some(channel) => format!("Current Participants in #{} ({})", channel, participants.len()),
+            None => format!("Current Participants ({})", participants.len()),
+        };
+        println!("\n{}", separator(Some(&title), 80));
+        
+        for participant in participants {
+            let color = if *participant == self.username {
+                Colors::BRIGHT_BLUE
+            } else {
+                get_username_color(participant)
+            };
+            println!("- {}{}{}", color, participant, Colors::RESET);
+        }
+        
+        println!("{}", separator(None, 80));
+        
+        // Print history; after a rejoin, just what we missed
+        self.history_epoch = history_epoch;
+        self.oldest_seq = history.first().and_then(|item| item.seq);
+        let others: Vec<&HistoryItem> = history.iter().filter(|item| item.from != self.username).collect();
+        if since.is_some() {
+            self.print_history("Since You Were Last Here", &others);
         } else {
-            log(LogLevel::Debug, msgs_verbosity, "Failed to parse incoming message");
+            self.print_history("Message History", &others);
         }
-    }).await;
+        if more {
+            println!("{}Type /history for older messages{}", Colors::DIM, Colors::RESET);
+        }
+        self.mark_seen(history.iter().rev().find_map(|item| item.seq));
+        
+        self.synced = true;
+        for commit in std::mem::take(&mut self.early_commits) {
+            self.process_message(&commit);
+        }
+    }
     
-    // Wait for Ctrl+C
-    signal::ctrl_c().await?;
+    /// Remember item `seq` as the last we saw in our channel of this room.
+    /// Rooms that don't say which numbering they use get no cursor.
+    fn mark_seen(&mut self, seq: Option<u64>) {
+        if let (Some(seq), Some(epoch)) = (seq, self.history_epoch) {
+            self.last_seen.record(&self.room_address, self.channel.as_deref(), HistoryCursor { epoch, seq });
+        }
+    }
     
-    Ok(())
+    /// Write out where we left off, if it moved since we last did
+    fn save_last_seen(&mut self) {
+        if let Err(e) = self.last_seen.save() {
+            log(LogLevel::Info, self.verbosity, &format!("Failed to save last seen message: {}", e));
+        }
+    }
+    
+    /// The chunked state sync a part belongs to, unless it was already dealt with.
+    /// A new sync (after joining under another name, say) replaces an older one.
+    fn sync_part(&mut self, sync_id: u64, chunks: u32) -> Option<&mut PendingSync> {
+        if self.sync.as_ref().is_none_or(|sync| sync.sync_id != sync_id) {
+            self.sync = Some(PendingSync {
+                sync_id,
+                chunks,
+                received: BTreeMap::new(),
+                complete: None,
+                last_progress: Instant::now(),
+                resends: 0,
+                done: false,
+            });
+        }
+        
+        let sync = self.sync.as_mut().filter(|sync| !sync.done)?;
+        sync.last_progress = Instant::now();
+        Some(sync)
+    }
+    
+    /// Apply a chunked state sync once every part has arrived, or with whatever
+    /// did arrive when giving up on the rest
+    fn finish_sync(&mut self, give_up: bool) {
+        let sync = match &mut self.sync {
+            Some(sync) if !sync.done => sync,
+            _ => return,
+        };
+        
+        let chunks = sync.chunks;
+        let missing = chunks.saturating_sub(sync.received.len() as u32);
+        if missing > 0 && !give_up {
+            return;
+        }
+        
+        let complete = match sync.complete.take() {
+            Some(complete) => complete,
+            None if give_up => {
+                sync.done = true;
+                println!("{}The room's state sync never arrived in full{}", Colors::YELLOW, Colors::RESET);
+                return;
+            },
+            None => return,
+        };
+        
+        sync.done = true;
+        let history: Vec<HistoryItem> = std::mem::take(&mut sync.received).into_values().flatten().collect();
+        if missing > 0 {
+            println!("{}{} of {} parts of the room history never arrived{}", Colors::YELLOW, missing, chunks, Colors::RESET);
+        }
+        
+        self.apply_state_sync(&history, &complete);
+    }
+    
+    /// Ask the room again for state sync parts that are overdue
+    fn check_sync(&mut self) {
+        let sync = match &mut self.sync {
+            Some(sync) if !sync.done && sync.last_progress.elapsed() >= Duration::from_secs(SYNC_CHUNK_TIMEOUT_SECS) => sync,
+            _ => return,
+        };
+        
+        if sync.resends >= MAX_SYNC_RESENDS {
+            self.finish_sync(true);
+            return;
+        }
+        
+        sync.resends += 1;
+        sync.last_progress = Instant::now();
+        let sync_id = sync.sync_id;
+        let missing: Vec<u32> = (0..sync.chunks)
+            .filter(|index| !sync.received.contains_key(index))
+            .collect();
+        
+        log(LogLevel::Debug, self.verbosity, &format!("Asking the room again for {} state sync parts", missing.len()));
+        self.outgoing.push_back(ChatMessage::StateSyncResend { sync_id, missing });
+    }
+    
+    /// Render a message received from the room
+    fn handle_message(&mut self, message: &ChatMessage) {
+        // Each message from the room used up one of our reply SURBs
+        self.traffic.record(Instant::now());
+        self.surb_estimate = self.surb_estimate.saturating_sub(1);
+        
+        // The room may relay a commit for our join ahead of our state sync,
+        // before we know the committer or its identity key
+        if !self.synced && matches!(message.payload(), ChatMessage::GroupCommit { .. }) {
+            self.early_commits.push(message.clone());
+            return;
+        }
+        
+        self.process_message(message);
+    }
+    
+    fn process_message(&mut self, message: &ChatMessage) {
+        let seq = message.seq();
+        let (message, authenticity) = self.authenticate(message);
+        let flag = format_authenticity(authenticity);
+        
+        match message {
+            ChatMessage::SurbRequest { remaining } => {
+                log(LogLevel::Debug, self.verbosity, &format!(
+                    "Room is running low on reply SURBs ({} left)", remaining));
+                self.surb_estimate = *remaining;
+                self.top_up_requested = true;
+            },
+            ChatMessage::JoinRejected { reason } => {
+                println!("{}Room rejected username {}:{} {}", Colors::RED, self.username, Colors::RESET, reason);
+                print!("Choose another username: ");
+                std::io::stdout().flush().ok();
+                
+                // Lines sent before the rejection will never be accepted
+                for (_, line) in self.pending.drain() {
+                    println!("{}", format_delivery_status(DeliveryState::Failed, &line.content));
+                }
+                self.choosing_name = true;
+            },
+            ChatMessage::AccessDenied { reason } => {
+                println!("{}Room denied access:{} {}", Colors::RED, Colors::RESET, reason);
+                if self.access_key.is_none() {
+                    println!("Join again with --passphrase to enter it.");
+                }
+                
+                for (_, line) in self.pending.drain() {
+                    println!("{}", format_delivery_status(DeliveryState::Failed, &line.content));
+                }
+                self.access_denied = Some(reason.clone());
+            },
+            ChatMessage::Ack { id, seq } => {
+                if let Some(line) = self.pending.remove(id) {
+                    println!("{}", format_delivery_status(DeliveryState::Delivered, &line.content));
+                    self.mark_seen(*seq);
+                }
+            },
+            ChatMessage::Join { username: join_username, key_package, identity_key, .. } if join_username != &self.username => {
+                println!("{}User joined:{} {}{}", Colors::GREEN, Colors::RESET, join_username, flag);
+                log(LogLevel::Info, self.verbosity, &format!("User joined: {}", join_username));
+                
+                // A join with a bad signature can't vouch for the key it carries
+                if let Some(key) = identity_key.as_ref().filter(|_| authenticity != Authenticity::Invalid) {
+                    self.identity_keys.insert(join_username.clone(), key.clone());
+                    self.observe_identity_key(join_username, key);
+                }
+                
+                // Rekey so the newcomer gets a share, if it's our turn
+                if let Some(group) = &mut self.group {
+                    if let Some(commit) = group.member_joined(join_username, key_package.as_deref(), &self.username) {
+                        log(LogLevel::Debug, self.verbosity, &format!("Rekeying to epoch {} for {}", group.epoch(), join_username));
+                        self.outgoing.push_back(commit);
+                    }
+                }
+            },
+            ChatMessage::Leave { username: leave_username } if leave_username != &self.username => {
+                println!("{}User left:{} {}{}", Colors::YELLOW, Colors::RESET, leave_username, flag);
+                log(LogLevel::Info, self.verbosity, &format!("User left: {}", leave_username));
+                self.identity_keys.remove(leave_username);
+                
+                // Rekey so the departed member can't read what follows, if it's our turn.
+                // The room announces members it prunes itself, unsigned; its word
+                // alone doesn't take anyone out of the key agreement.
+                if authenticity != Authenticity::Verified {
+                    log(LogLevel::Debug, self.verbosity, &format!(
+                        "Keeping {} in the room key until they leave themselves", leave_username));
+                } else if let Some(group) = &mut self.group {
+                    if let Some(commit) = group.member_left(leave_username, &self.username) {
+                        log(LogLevel::Debug, self.verbosity, &format!("Rekeying to epoch {} without {}", group.epoch(), leave_username));
+                        self.outgoing.push_back(commit);
+                    }
+                }
+            },
+            // Only a member's own signature can move the group to a key; the
+            // room could otherwise key everyone to a secret it chose
+            ChatMessage::GroupCommit { epoch, committer, .. } if authenticity != Authenticity::Verified => {
+                log(LogLevel::Info, self.verbosity, &format!(
+                    "Ignoring key epoch {} from {} without a valid signature", epoch, committer));
+            },
+            ChatMessage::GroupCommit { epoch, committer, commit_key, shares } => {
+                if let Some(group) = &mut self.group {
+                    match group.apply_commit(*epoch, committer, commit_key, shares, &self.username) {
+                        Ok(true) => log(LogLevel::Info, self.verbosity, &format!(
+                            "{} rotated the room key (epoch {})", committer, epoch)),
+                        Ok(false) => log(LogLevel::Debug, self.verbosity, &format!(
+                            "Ignoring stale key epoch {} from {}", epoch, committer)),
+                        Err(e) => {
+                            log(LogLevel::Debug, self.verbosity, &format!(
+                                "Could not apply key epoch {} from {}: {}", epoch, committer, e));
+                            self.request_commit(*epoch);
+                        },
+                    }
+                }
+            },
+            ChatMessage::GroupKeyRequest { epoch, member } if authenticity != Authenticity::Verified => {
+                log(LogLevel::Info, self.verbosity, &format!(
+                    "Ignoring request for key epoch {} from {} without a valid signature", epoch, member));
+            },
+            ChatMessage::GroupKeyRequest { epoch, member } => {
+                if let Some(group) = &mut self.group {
+                    if let Some(commit) = group.commit_requested(member, *epoch, &self.username) {
+                        log(LogLevel::Debug, self.verbosity, &format!(
+                            "{} missed key epoch {}, rekeying to epoch {}", member, epoch, group.epoch()));
+                        self.outgoing.push_back(commit);
+                    }
+                }
+            },
+            ChatMessage::Text { from, content, sealed, .. } if from != &self.username => {
+                self.mark_seen(seq);
+                if let Some(sealed) = sealed {
+                    self.request_commit(sealed.epoch);
+                }
+                let content = self.reveal(from, content, sealed.as_ref(), false);
+                let name_color = get_username_color(from);
+                println!("{}{}{}{}: {}", name_color, from, Colors::RESET, flag, content);
+                log(LogLevel::Info, self.verbosity, &format!("Message from {}: {}", from, content));
+            },
+            ChatMessage::DirectMessage { from, content, sealed, .. } => {
+                let content = self.reveal(from, content, sealed.as_ref(), false);
+                let name_color = get_username_color(from);
+                println!("{}{}{}{} {}(private){}: {}", name_color, from, Colors::RESET, flag, Colors::MAGENTA, Colors::RESET, content);
+                log(LogLevel::Info, self.verbosity, &format!("Direct message from {}", from));
+            },
+            ChatMessage::DirectMessageFailed { to, reason } => {
+                println!("{}Message to {} not delivered:{} {}", Colors::RED, to, Colors::RESET, reason);
+            },
+            ChatMessage::StateSync { history, chunked: None, .. } => {
+                self.apply_state_sync(history, message);
+            },
+            ChatMessage::StateSync { chunked: Some(chunked), .. } => {
+                if let Some(sync) = self.sync_part(chunked.sync_id, chunked.chunks) {
+                    sync.complete = Some(message.clone());
+                }
+                self.finish_sync(false);
+            },
+            ChatMessage::StateSyncChunk { sync_id, index, chunks, history } => {
+                if let Some(sync) = self.sync_part(*sync_id, *chunks) {
+                    if *index < sync.chunks {
+                        sync.received.insert(*index, history.clone());
+                    }
+                }
+                self.finish_sync(false);
+            },
+            ChatMessage::HistoryPage { items, more } => {
+                self.history_complete = !more;
+                if items.is_empty() {
+                    println!("{}No earlier messages in this room{}", Colors::DIM, Colors::RESET);
+                    return;
+                }
+                
+                self.oldest_seq = items.first().and_then(|item| item.seq);
+                self.print_history("Earlier Messages", &items.iter().collect::<Vec<_>>());
+                if *more {
+                    println!("{}Type /history again for older messages{}", Colors::DIM, Colors::RESET);
+                }
+            },
+            ChatMessage::ChannelList { channels } => {
+                println!("\n{}", separator(Some(&format!("Channels ({})", channels.len())), 80));
+                for channel in channels {
+                    let current = if self.joined_channel.as_ref() == Some(&channel.name) { " (you are here)" } else { "" };
+                    println!("- #{} {}{} online{}{}", channel.name, Colors::DIM, channel.participants, current, Colors::RESET);
+                }
+                println!("{}", separator(None, 80));
+            },
+            _ => {}
+        }
+    }
+}
+
+// Marker shown after the sender's name on messages we could not verify
+fn format_authenticity(authenticity: Authenticity) -> String {
+    match authenticity {
+        Authenticity::Verified => String::new(),
+        Authenticity::Unverified => format!(" {}[unsigned]{}", Colors::YELLOW, Colors::RESET),
+        Authenticity::Invalid => format!(" {}[invalid signature]{}", Colors::RED, Colors::RESET),
+        Authenticity::KeyChanged => format!(" {}{}[key changed]{}", Colors::BOLD, Colors::BRIGHT_RED, Colors::RESET),
+    }
 }
 
 // Print welcome banner for the server
-fn print_welcome_banner(address: &str) {
+fn print_welcome_banner(address: &str, history_persisted: bool) {
     println!("{}", Colors::BRIGHT_CYAN);
     println!(r"
 
@@ -660,8 +2659,12 @@ fn print_welcome_banner(address: &str) {
 '  `(_/  '  `   `'  `' 
     
     chat with your catz and get mixxed up in some serious sh1t among
-    the other sphinx packets with SURBs.
-    No messages stored, no rooms persist, it all turns to dust.");
+    the other sphinx packets with SURBs.");
+    if history_persisted {
+        println!("    History is kept on disk, everything else turns to dust.");
+    } else {
+        println!("    No messages stored, no rooms persist, it all turns to dust.");
+    }
     println!("{}", Colors::RESET);
     
     println!("{}{}{} Room Server {}{}{}\n",
@@ -696,3 +2699,36 @@ fn get_username_color(username: &str) -> &'static str {
         _ => unreachable!(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedup_window_accepts_each_message_once() {
+        let (alice, bob) = (AnonymousSenderTag::from_bytes([1; 16]), AnonymousSenderTag::from_bytes([2; 16]));
+        let mut window = DedupWindow::new(2);
+
+        assert!(window.insert(alice, 42));
+        assert!(!window.insert(alice, 42), "retransmission accepted twice");
+
+        // Ids are only unique per sender
+        assert!(window.insert(bob, 42));
+
+        // Only the latest few are remembered
+        assert!(window.insert(alice, 43));
+        assert!(window.insert(alice, 42));
+    }
+
+    #[test]
+    fn usernames_must_be_one_printable_word() {
+        assert!(validate_username("alice").is_ok());
+        assert!(validate_username(&"é".repeat(MAX_USERNAME_LEN)).is_ok());
+
+        assert!(validate_username("").is_err());
+        assert!(validate_username(&"a".repeat(MAX_USERNAME_LEN + 1)).is_err());
+        assert!(validate_username("two words").is_err());
+        assert!(validate_username("tab\there").is_err());
+        assert!(validate_username("bell\u{7}").is_err());
+    }
+}
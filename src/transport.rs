@@ -0,0 +1,156 @@
+// src/transport.rs
+use nym_sdk::mixnet::{
+    AnonymousSenderTag, IncludedSurbs, MixnetClient, MixnetClientBuilder, MixnetClientSender, MixnetMessageSender,
+    Recipient, StoragePaths,
+};
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+
+// Where the mixnet client's keys live inside a data directory
+const MIXNET_KEYS_DIR: &str = "mixnet";
+
+/// A message handed to the receiving side of a transport
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+    pub message: Vec<u8>,
+    pub sender_tag: Option<AnonymousSenderTag>,
+}
+
+/// Sending half of a transport, cheap to clone into background tasks
+pub trait TransportSender: Clone + Send + Sync + 'static {
+    /// Send a message to a nym address, attaching reply SURBs as requested
+    fn send_message(
+        &self,
+        recipient: Recipient,
+        message: &[u8],
+        surbs: IncludedSurbs,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Reply to an anonymous sender using one of the SURBs it attached
+    fn send_reply(
+        &self,
+        recipient: AnonymousSenderTag,
+        message: &[u8],
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// A connection to the mixnet (or something that behaves like one)
+pub trait Transport {
+    type Sender: TransportSender;
+
+    /// Our own address, as handed out to peers
+    fn nym_address(&self) -> Recipient;
+
+    /// Get a sender that can be moved into other tasks
+    fn split_sender(&self) -> Self::Sender;
+
+    /// Feed every incoming message to `callback` until the transport closes
+    fn on_messages<F>(&mut self, callback: F) -> impl Future<Output = ()>
+    where
+        F: Fn(IncomingMessage);
+}
+
+impl TransportSender for MixnetClientSender {
+    async fn send_message(
+        &self,
+        recipient: Recipient,
+        message: &[u8],
+        surbs: IncludedSurbs,
+    ) -> anyhow::Result<()> {
+        MixnetMessageSender::send_message(self, recipient, message, surbs).await?;
+        Ok(())
+    }
+
+    async fn send_reply(&self, recipient: AnonymousSenderTag, message: &[u8]) -> anyhow::Result<()> {
+        MixnetMessageSender::send_reply(self, recipient, message).await?;
+        Ok(())
+    }
+}
+
+impl Transport for MixnetClient {
+    type Sender = MixnetClientSender;
+
+    fn nym_address(&self) -> Recipient {
+        *MixnetClient::nym_address(self)
+    }
+
+    fn split_sender(&self) -> MixnetClientSender {
+        MixnetClient::split_sender(self)
+    }
+
+    async fn on_messages<F>(&mut self, callback: F)
+    where
+        F: Fn(IncomingMessage),
+    {
+        MixnetClient::on_messages(self, move |msg| {
+            callback(IncomingMessage {
+                message: msg.message,
+                sender_tag: msg.sender_tag,
+            })
+        })
+        .await
+    }
+}
+
+/// Connect to the mixnet. With a data directory the client's keys are kept
+/// there, so it comes back with the same nym address after a restart.
+pub async fn connect_mixnet(data_dir: Option<&Path>) -> anyhow::Result<MixnetClient> {
+    let data_dir = match data_dir {
+        Some(data_dir) => data_dir,
+        None => return Ok(MixnetClient::connect_new().await?),
+    };
+
+    connect_with_keys(&data_dir.join(MIXNET_KEYS_DIR)).await
+}
+
+// Connect with the keys kept in `keys_dir`, creating them if there are none
+async fn connect_with_keys(keys_dir: &Path) -> anyhow::Result<MixnetClient> {
+    let storage_paths = StoragePaths::new_from_dir(keys_dir)?;
+    let client = MixnetClientBuilder::new_with_default_storage(storage_paths)
+        .await?
+        .build()?
+        .connect_to_mixnet()
+        .await?;
+    Ok(client)
+}
+
+/// Replace the mixnet keys kept in `data_dir` with freshly registered ones,
+/// returning the new nym address. The old keys stay in place until the new
+/// ones have connected, so a failed rotation leaves the old address working.
+pub async fn rotate_mixnet_keys(data_dir: &Path) -> anyhow::Result<Recipient> {
+    let keys_dir = data_dir.join(MIXNET_KEYS_DIR);
+    let new_keys_dir = data_dir.join(format!("{}.new", MIXNET_KEYS_DIR));
+    let old_keys_dir = data_dir.join(format!("{}.old", MIXNET_KEYS_DIR));
+
+    // An interrupted rotation may have moved the old keys aside; put them
+    // back, and drop whatever else it left behind
+    if !keys_dir.exists() && old_keys_dir.exists() {
+        fs::rename(&old_keys_dir, &keys_dir)?;
+    }
+    for leftover in [&new_keys_dir, &old_keys_dir] {
+        if leftover.exists() {
+            fs::remove_dir_all(leftover)?;
+        }
+    }
+
+    let client = match connect_with_keys(&new_keys_dir).await {
+        Ok(client) => client,
+        Err(e) => {
+            fs::remove_dir_all(&new_keys_dir).ok();
+            return Err(e);
+        }
+    };
+    let address = *client.nym_address();
+    client.disconnect().await;
+
+    // A directory can't be renamed over another, so move the old keys aside first
+    if keys_dir.exists() {
+        fs::rename(&keys_dir, &old_keys_dir)?;
+    }
+    fs::rename(&new_keys_dir, &keys_dir)?;
+    if old_keys_dir.exists() {
+        fs::remove_dir_all(&old_keys_dir)?;
+    }
+    Ok(address)
+}